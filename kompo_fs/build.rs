@@ -2,6 +2,9 @@ use std::env;
 use std::fs;
 use std::path::Path;
 
+// Keep in sync with kompo_storage::KOMPO_STORAGE_ABI_VERSION
+const STORAGE_ABI_VERSION: u32 = 1;
+
 fn main() {
     // Get the target directory from OUT_DIR
     // OUT_DIR is typically target/release/build/kompo_fs-xxx/out
@@ -17,8 +20,17 @@ fn main() {
     fs::write(target_dir.join("KOMPO_VFS_VERSION"), version)
         .expect("Failed to write KOMPO_VFS_VERSION file");
 
-    // Rerun if Cargo.toml changes
+    // The kompo_storage ABI version this crate is written against, checked at runtime
+    // against the linked kompo_storage in initialize_trie()
+    fs::write(
+        Path::new(&out_dir).join("abi_version.rs"),
+        format!("const EXPECTED_STORAGE_ABI_VERSION: u32 = {STORAGE_ABI_VERSION};\n"),
+    )
+    .expect("Failed to write abi_version.rs");
+
+    // Rerun if Cargo.toml or the expected ABI version changes
     println!("cargo:rerun-if-changed=Cargo.toml");
+    println!("cargo:rerun-if-changed=build.rs");
 
     // Link zlib for compression support
     // On macOS, zlib is available as a system library
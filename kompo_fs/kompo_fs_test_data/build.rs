@@ -1,4 +1,107 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// Spans several pages (even 16KiB ones) so mmap can be tested at nonzero offsets.
+const LARGE_LEN: usize = 4 * 16384 + 100;
+static LARGE: [u8; LARGE_LEN] = byte_pattern();
+
+// Byte `i` is `i % 251`, so any window of the file is distinguishable from others.
+const fn byte_pattern<const N: usize>() -> [u8; N] {
+    let mut buf = [0; N];
+    let mut i = 0;
+    while i < N {
+        buf[i] = (i % 251) as u8;
+        i += 1;
+    }
+    buf
+}
+
+// Files embedded into the test binary, as `(path, content)` pairs.
+const FIXTURES: &[(&str, &[u8])] = &[
+    ("/test/hello.txt", b"Hello, World!"),
+    ("/test/world.txt", b"Test Content"),
+    ("/test/data/small.bin", &[0xAB; 100]),
+    ("/test/data/large.bin", &LARGE),
+    ("/test/lib/greeting.rb", b"puts 'hi'"),
+    // An empty directory
+    ("/test/tmp/", b""),
+];
+
+// Working directory
+const WD: &str = "/test";
+
+fn byte_array(name: &str, bytes: &[u8], mutable: bool) -> String {
+    let values = bytes
+        .iter()
+        .map(|b| b.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let kind = if mutable { "static mut" } else { "static" };
+
+    format!(
+        "#[unsafe(no_mangle)]\npub {kind} {name}: [u8; {}] = [{values}];\n",
+        bytes.len()
+    )
+}
+
+fn u64_value(name: &str, value: u64) -> String {
+    format!("#[unsafe(no_mangle)]\npub static {name}: u64 = {value};\n")
+}
+
+fn u64_array(name: &str, values: &[u64]) -> String {
+    let joined = values
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        "#[unsafe(no_mangle)]\npub static {name}: [u64; {}] = [{joined}];\n",
+        values.len()
+    )
+}
+
+fn int(name: &str, value: usize) -> String {
+    format!("#[unsafe(no_mangle)]\npub static {name}: i32 = {value};\n")
+}
+
 fn main() {
-    println!("cargo::rerun-if-changed=dummy_fs.c");
-    cc::Build::new().file("dummy_fs.c").compile("dummy_fs");
+    println!("cargo::rerun-if-changed=build.rs");
+
+    let mut paths = Vec::new();
+    let mut files = Vec::new();
+    let mut files_sizes = vec![0u64];
+
+    for (path, content) in FIXTURES {
+        paths.extend_from_slice(path.as_bytes());
+        paths.push(0);
+
+        files.extend_from_slice(content);
+        files_sizes.push(files.len() as u64);
+    }
+
+    let mut wd = WD.as_bytes().to_vec();
+    wd.push(0);
+
+    let mut out = String::new();
+    out.push_str(&byte_array("PATHS", &paths, false));
+    out.push_str(&int("PATHS_SIZE", paths.len()));
+    out.push_str(&byte_array("FILES", &files, false));
+    out.push_str(&int("FILES_SIZE", files.len()));
+    out.push_str(&u64_array("FILES_SIZES", &files_sizes));
+    out.push_str(&u64_value("FILES_HASH", xxhash_rust::xxh3::xxh3_64(&files)));
+    out.push_str(&byte_array("WD", &wd, false));
+
+    // Compression support symbols (compression disabled for tests)
+    out.push_str(&int("COMPRESSION_ENABLED", 0));
+    out.push_str(&byte_array("COMPRESSED_FILES", &[0], false));
+    out.push_str(&int("COMPRESSED_FILES_SIZE", 0));
+    out.push_str(&u64_array("COMPRESSED_SIZES", &[0]));
+    out.push_str(&byte_array("FILES_BUFFER", &[0], true));
+    out.push_str(&int("FILES_BUFFER_SIZE", 0));
+    out.push_str(&u64_array("ORIGINAL_SIZES", &[0]));
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("fixtures.rs"), out).expect("Failed to write fixtures.rs");
 }
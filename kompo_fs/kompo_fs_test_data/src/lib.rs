@@ -1,12 +1,5 @@
 // This crate provides test data symbols for kompo_fs tests.
-// The symbols are defined in dummy_fs.c and compiled by build.rs.
+// The symbols are generated from the fixture table in build.rs, so no C
+// compiler is needed to build the tests.
 
-#[allow(dead_code)]
-unsafe extern "C" {
-    pub static PATHS: libc::c_char;
-    pub static PATHS_SIZE: libc::c_int;
-    pub static FILES: libc::c_char;
-    pub static FILES_SIZE: libc::c_int;
-    pub static FILES_SIZES: libc::c_ulonglong;
-    pub static WD: libc::c_char;
-}
+include!(concat!(env!("OUT_DIR"), "/fixtures.rs"));
@@ -4,7 +4,10 @@ use std::{
     str::FromStr,
 };
 
-use crate::{FILE_TYPE_CACHE, TRIE, WORKING_DIR, initialize_trie, util};
+use crate::{
+    FILE_TYPE_CACHE, TRIE, current_working_dir, has_working_dir, initialize_trie, is_strict_mode,
+    set_working_dir, util,
+};
 
 #[unsafe(no_mangle)]
 pub fn mmap_from_fs(
@@ -20,14 +23,19 @@ pub fn mmap_from_fs(
     }
 
     if util::is_fd_exists_in_kompo(fd) {
+        if offset < 0 {
+            errno::set_errno(errno::Errno(libc::EINVAL));
+            return libc::MAP_FAILED;
+        }
+
         let mm = unsafe {
             kompo_wrap::MMAP_HANDLE(
                 addr,
                 length,
-                libc::PROT_READ | libc::PROT_WRITE, // write by read_from_fs()
+                libc::PROT_READ | libc::PROT_WRITE, // filled by read_at() below
                 libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
                 -1,
-                offset,
+                0,
             )
         };
 
@@ -35,9 +43,14 @@ pub fn mmap_from_fs(
             return mm;
         }
 
-        if read_from_fs(fd, mm, length) >= 0 {
+        let buf = unsafe { std::slice::from_raw_parts_mut(mm as *mut u8, length) };
+        let trie = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie));
+        if let Some(read_size) = trie.read_at(fd, buf, offset as u64) {
+            // Bytes past the end of the file read as zero, as with a real file mapping.
+            buf[read_size as usize..].fill(0);
             mm
         } else {
+            unsafe { libc::munmap(mm, length) };
             errno::set_errno(errno::Errno(libc::EBADF));
             libc::MAP_FAILED
         }
@@ -46,6 +59,28 @@ pub fn mmap_from_fs(
     }
 }
 
+// The VFS is read-only, so an `O_CREAT` open of a path it resolves is refused rather
+// than opened as if the flag were absent: an embedded path already exists and a
+// missing one can't be created. `mode` only matters for the file created, so it is
+// ignored either way.
+fn refuse_vfs_create(exists: bool) -> libc::c_int {
+    let errno = if exists { libc::EEXIST } else { libc::EROFS };
+    errno::set_errno(errno::Errno(errno));
+    -1
+}
+
+// Whether `path`, resolved against the VFS working directory when relative, names
+// an embedded file or directory.
+pub(crate) fn exists_in_kompo(path: *const libc::c_char) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = unsafe { CStr::from_ptr(path) };
+    let path = util::resolve_kompo_path(Path::new(std::ffi::OsStr::from_bytes(path.to_bytes())));
+
+    TRIE.get_or_init(initialize_trie)
+        .contains(&path.iter().collect())
+}
+
 #[unsafe(no_mangle)]
 pub fn open_from_fs(path: *const libc::c_char, oflag: libc::c_int, mode: libc::mode_t) -> i32 {
     fn inner_open(path: *const libc::c_char, oflag: libc::c_int) -> libc::c_int {
@@ -55,42 +90,34 @@ pub fn open_from_fs(path: *const libc::c_char, oflag: libc::c_int, mode: libc::m
 
         let trie = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie));
 
-        #[cfg(target_os = "macos")]
-        let o_directory = libc::O_DIRECTORY;
-        #[cfg(target_os = "linux")]
-        let o_directory = libc::O_DIRECTORY;
-
-        if oflag & o_directory == o_directory {
-            let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
-            match trie.stat(&path_vec, &mut stat_buf) {
-                Some(_) => {
-                    if stat_buf.st_mode & libc::S_IFMT == libc::S_IFDIR {
-                        trie.open(&path_vec).unwrap_or_else(|| {
-                            errno::set_errno(errno::Errno(libc::ENOENT));
-                            -1
-                        })
-                    } else {
-                        errno::set_errno(errno::Errno(libc::ENOTDIR));
-                        -1
-                    }
-                }
-                None => {
-                    errno::set_errno(errno::Errno(libc::ENOENT));
-                    -1
-                }
-            }
-        } else {
-            trie.open(&path_vec).unwrap_or_else(|| {
-                errno::set_errno(errno::Errno(libc::ENOENT));
-                -1
-            })
+        if oflag & libc::O_CREAT == libc::O_CREAT {
+            return refuse_vfs_create(trie.contains(&path_vec));
         }
+
+        trie.open(&path_vec, oflag).unwrap_or_else(|err| {
+            errno::set_errno(errno::Errno(err.errno()));
+            -1
+        })
     }
 
-    if WORKING_DIR.read().unwrap().is_some() && unsafe { *path } != b'/'.try_into().unwrap() {
-        let expand_path = unsafe { util::expand_kompo_path(path) };
+    if path.is_null() {
+        errno::set_errno(errno::Errno(libc::EFAULT));
+        return -1;
+    }
 
-        inner_open(expand_path, oflag)
+    if unsafe { util::is_empty_path(path) } {
+        errno::set_errno(errno::Errno(libc::ENOENT));
+        return -1;
+    }
+
+    if let Some(host_path) = unsafe { util::host_path(path) } {
+        return unsafe { kompo_wrap::OPEN_HANDLE(host_path.as_ptr(), oflag, mode) };
+    }
+
+    if has_working_dir() && unsafe { util::is_relative_path(path) } {
+        let expand_path = unsafe { util::expanded_kompo_path(path) };
+
+        inner_open(expand_path.as_ptr(), oflag)
     } else if unsafe { util::is_under_kompo_working_dir(path) } {
         inner_open(path, oflag)
     } else {
@@ -108,28 +135,46 @@ pub unsafe fn openat_from_fs(
     fn inner_openat(
         _dirfd: libc::c_int,
         pathname: *const libc::c_char,
-        _flags: libc::c_int,
+        flags: libc::c_int,
         _mode: libc::mode_t,
     ) -> libc::c_int {
         let path = unsafe { CStr::from_ptr(pathname) };
         let path = PathBuf::from_str(path.to_str().expect("invalid path")).unwrap();
 
-        let current_dir = WORKING_DIR.read().unwrap();
-        let current_dir = current_dir.clone().expect("not found current dir");
-        let mut current_dir = PathBuf::from(current_dir);
+        let mut current_dir = current_working_dir().expect("not found current dir");
 
-        util::canonicalize_path(&mut current_dir, &path);
+        util::canonicalize_join_path(&mut current_dir, &path);
 
         let path = current_dir.iter().collect::<Vec<_>>();
 
         let trie = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie));
 
-        trie.open(&path).unwrap_or_else(|| {
-            errno::set_errno(errno::Errno(libc::ENOENT));
+        if flags & libc::O_CREAT == libc::O_CREAT {
+            return refuse_vfs_create(trie.contains(&path));
+        }
+
+        trie.open(&path, flags).unwrap_or_else(|err| {
+            errno::set_errno(errno::Errno(err.errno()));
             -1
         })
     }
 
+    if pathname.is_null() {
+        errno::set_errno(errno::Errno(libc::EFAULT));
+        return -1;
+    }
+
+    if unsafe { util::is_empty_path(pathname) } {
+        errno::set_errno(errno::Errno(libc::ENOENT));
+        return -1;
+    }
+
+    if (dirfd == libc::AT_FDCWD || !unsafe { util::is_relative_path(pathname) })
+        && let Some(host_path) = unsafe { util::host_path(pathname) }
+    {
+        return unsafe { kompo_wrap::OPENAT_HANDLE(dirfd, host_path.as_ptr(), flags, mode) };
+    }
+
     #[cfg(target_os = "linux")]
     let is_create_flag =
         flags & libc::O_CREAT == libc::O_CREAT || flags & libc::O_TMPFILE == libc::O_TMPFILE;
@@ -137,7 +182,20 @@ pub unsafe fn openat_from_fs(
     #[cfg(not(target_os = "linux"))]
     let is_create_flag = flags & libc::O_CREAT == libc::O_CREAT;
 
-    if is_create_flag {
+    let is_kompo_path = unsafe { util::is_under_kompo_working_dir(pathname) }
+        || (dirfd == libc::AT_FDCWD
+            && has_working_dir()
+            && unsafe { util::is_relative_path(pathname) });
+    // A create of a path the VFS resolves never reaches the host, where it could
+    // open a file planted at the same path. Strict mode also logs a missing one.
+    if is_create_flag && is_kompo_path {
+        let exists = exists_in_kompo(pathname);
+        if is_strict_mode() && !exists {
+            return util::refuse_host_fallback(unsafe { CStr::from_ptr(pathname) });
+        }
+        // `O_TMPFILE` names the directory to create in, so its existing is no EEXIST
+        return refuse_vfs_create(exists && flags & libc::O_CREAT == libc::O_CREAT);
+    } else if is_create_flag {
         return unsafe { kompo_wrap::OPENAT_HANDLE(dirfd, pathname, flags, mode) };
     }
 
@@ -145,10 +203,7 @@ pub unsafe fn openat_from_fs(
         return open_from_fs(pathname, flags, mode);
     }
 
-    if dirfd == libc::AT_FDCWD
-        && WORKING_DIR.read().unwrap().is_some()
-        && unsafe { *pathname } != b'/'.try_into().unwrap()
-    {
+    if dirfd == libc::AT_FDCWD && has_working_dir() && unsafe { util::is_relative_path(pathname) } {
         return inner_openat(dirfd, pathname, flags, mode);
     }
 
@@ -158,7 +213,12 @@ pub unsafe fn openat_from_fs(
 #[unsafe(no_mangle)]
 pub fn close_from_fs(fd: i32) -> i32 {
     if util::is_fd_exists_in_kompo(fd) {
-        std::sync::Arc::clone(TRIE.get_or_init(initialize_trie)).close(fd);
+        // Another thread may have closed it since the check above. Its dup'd fd is
+        // then already closed (or reused), so it must not be closed again here.
+        if let Err(err) = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie)).close(fd) {
+            errno::set_errno(errno::Errno(err.errno()));
+            return -1;
+        }
     };
 
     unsafe { kompo_wrap::CLOSE_HANDLE(fd) } // kompo_fs' inner fd made by dup(). so, close it.
@@ -180,8 +240,9 @@ pub fn stat_from_fs(path: *const libc::c_char, stat: *mut libc::stat) -> i32 {
             .collect::<Vec<_>>();
 
         // TODO: move to trie.stat()
-        if let Some(cache) = FILE_TYPE_CACHE.read().unwrap().get(&path) {
-            unsafe { *stat = *cache };
+        let trie = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie));
+        if let Some(cache) = FILE_TYPE_CACHE.get(&path) {
+            unsafe { *stat = trie.stat_from_cached(&cache) };
             return 0;
         }
 
@@ -190,10 +251,12 @@ pub fn stat_from_fs(path: *const libc::c_char, stat: *mut libc::stat) -> i32 {
             .map(|os_str| os_str.as_os_str())
             .collect::<Vec<_>>();
 
-        let trie = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie));
         let ret = trie.stat(&sarch_path, unsafe { &mut *stat });
         if ret.is_some() {
-            unsafe { FILE_TYPE_CACHE.write().unwrap().insert(path, *stat) };
+            FILE_TYPE_CACHE.insert(
+                path,
+                kompo_storage::CachedStat::from_stat(unsafe { &*stat }),
+            );
             0
         } else {
             errno::set_errno(errno::Errno(libc::ENOENT));
@@ -201,11 +264,25 @@ pub fn stat_from_fs(path: *const libc::c_char, stat: *mut libc::stat) -> i32 {
         }
     }
 
-    if WORKING_DIR.read().unwrap().is_some() && unsafe { *path } != b'/'.try_into().unwrap() {
-        let expand_path = unsafe { util::expand_kompo_path(path) };
+    if path.is_null() {
+        errno::set_errno(errno::Errno(libc::EFAULT));
+        return -1;
+    }
 
-        inner_stat(expand_path, stat)
-    } else if unsafe { util::is_under_kompo_working_dir(path) } {
+    if unsafe { util::is_empty_path(path) } {
+        errno::set_errno(errno::Errno(libc::ENOENT));
+        return -1;
+    }
+
+    if let Some(host_path) = unsafe { util::host_path(path) } {
+        return unsafe { kompo_wrap::STAT_HANDLE(host_path.as_ptr(), stat) };
+    }
+
+    if has_working_dir() && unsafe { util::is_relative_path(path) } {
+        let expand_path = unsafe { util::expanded_kompo_path(path) };
+
+        inner_stat(expand_path.as_ptr(), stat)
+    } else if unsafe { util::is_under_kompo_working_dir(path) || util::is_kompo_root(path) } {
         inner_stat(path, stat)
     } else {
         unsafe { kompo_wrap::STAT_HANDLE(path, stat) }
@@ -220,7 +297,7 @@ pub unsafe fn fstatat_from_fs(
     flags: libc::c_int,
 ) -> i32 {
     fn inner_fstatat(
-        _dirfd: libc::c_int,
+        mut current_dir: PathBuf,
         path: *const libc::c_char,
         stat: *mut libc::stat,
         _flags: libc::c_int,
@@ -233,10 +310,6 @@ pub unsafe fn fstatat_from_fs(
         let path = unsafe { CStr::from_ptr(path) };
         let path = PathBuf::from_str(path.to_str().expect("invalid path")).expect("invalid path");
 
-        let current_dir = WORKING_DIR.read().unwrap();
-        let current_dir = current_dir.clone().expect("not found current dir");
-        let mut current_dir = PathBuf::from(current_dir);
-
         util::canonicalize_path(&mut current_dir, &path);
 
         let sarch_path = current_dir.iter().collect::<Vec<_>>();
@@ -251,15 +324,50 @@ pub unsafe fn fstatat_from_fs(
         }
     }
 
+    if pathname.is_null() {
+        errno::set_errno(errno::Errno(libc::EFAULT));
+        return -1;
+    }
+
+    // On Linux an empty path names dirfd itself with AT_EMPTY_PATH; other empty
+    // paths fall through to the real fstatat
+    if unsafe { util::is_empty_path(pathname) } {
+        #[cfg(target_os = "linux")]
+        if flags & libc::AT_EMPTY_PATH != 0 && util::is_fd_exists_in_kompo(dirfd) {
+            return fstat_from_fs(dirfd, buf);
+        }
+        return unsafe { kompo_wrap::FSTATAT_HANDLE(dirfd, pathname, buf, flags) };
+    }
+
+    if (dirfd == libc::AT_FDCWD || !unsafe { util::is_relative_path(pathname) })
+        && let Some(host_path) = unsafe { util::host_path(pathname) }
+    {
+        return unsafe { kompo_wrap::FSTATAT_HANDLE(dirfd, host_path.as_ptr(), buf, flags) };
+    }
+
     if unsafe { util::is_under_kompo_working_dir(pathname) } {
         return stat_from_fs(pathname, buf);
     }
 
+    // Read once, so a concurrent reset of the working dir can't leave inner_fstatat
+    // without one. Relative paths outside the VFS go to the real fstatat.
     if dirfd == libc::AT_FDCWD
-        && WORKING_DIR.read().unwrap().is_some()
-        && unsafe { *pathname } != b'/'.try_into().unwrap()
+        && unsafe { util::is_relative_path(pathname) }
+        && let Some(current_dir) = current_working_dir()
     {
-        return inner_fstatat(dirfd, pathname, buf, flags);
+        return inner_fstatat(current_dir, pathname, buf, flags);
+    }
+
+    // Relative to a directory open in the VFS
+    if unsafe { util::is_relative_path(pathname) } && util::is_fd_exists_in_kompo(dirfd) {
+        let trie = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie));
+        return match trie.dir_path(dirfd) {
+            Some(dir) => inner_fstatat(dir, pathname, buf, flags),
+            None => {
+                errno::set_errno(errno::Errno(libc::ENOTDIR));
+                -1
+            }
+        };
     }
 
     unsafe { kompo_wrap::FSTATAT_HANDLE(dirfd, pathname, buf, flags) }
@@ -281,8 +389,9 @@ pub fn lstat_from_fs(path: *const libc::c_char, stat: *mut libc::stat) -> i32 {
             .collect::<Vec<_>>();
 
         // TODO: move to trie.stat()
-        if let Some(cache) = FILE_TYPE_CACHE.read().unwrap().get(&path) {
-            unsafe { *stat = *cache };
+        let trie = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie));
+        if let Some(cache) = FILE_TYPE_CACHE.get(&path) {
+            unsafe { *stat = trie.stat_from_cached(&cache) };
             return 0;
         }
 
@@ -291,10 +400,12 @@ pub fn lstat_from_fs(path: *const libc::c_char, stat: *mut libc::stat) -> i32 {
             .map(|os_str| os_str.as_os_str())
             .collect::<Vec<_>>();
 
-        let trie = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie));
         let ret = trie.lstat(&sarch_path, unsafe { &mut *stat });
         if ret.is_some() {
-            unsafe { FILE_TYPE_CACHE.write().unwrap().insert(path, *stat) };
+            FILE_TYPE_CACHE.insert(
+                path,
+                kompo_storage::CachedStat::from_stat(unsafe { &*stat }),
+            );
             0
         } else {
             errno::set_errno(errno::Errno(libc::ENOENT));
@@ -302,11 +413,25 @@ pub fn lstat_from_fs(path: *const libc::c_char, stat: *mut libc::stat) -> i32 {
         }
     }
 
-    if WORKING_DIR.read().unwrap().is_some() && unsafe { *path } != b'/'.try_into().unwrap() {
-        let expand_path = unsafe { util::expand_kompo_path(path) };
+    if path.is_null() {
+        errno::set_errno(errno::Errno(libc::EFAULT));
+        return -1;
+    }
 
-        inner_lstat(expand_path, stat)
-    } else if unsafe { util::is_under_kompo_working_dir(path) } {
+    if unsafe { util::is_empty_path(path) } {
+        errno::set_errno(errno::Errno(libc::ENOENT));
+        return -1;
+    }
+
+    if let Some(host_path) = unsafe { util::host_path(path) } {
+        return unsafe { kompo_wrap::LSTAT_HANDLE(host_path.as_ptr(), stat) };
+    }
+
+    if has_working_dir() && unsafe { util::is_relative_path(path) } {
+        let expand_path = unsafe { util::expanded_kompo_path(path) };
+
+        inner_lstat(expand_path.as_ptr(), stat)
+    } else if unsafe { util::is_under_kompo_working_dir(path) || util::is_kompo_root(path) } {
         inner_lstat(path, stat)
     } else {
         unsafe { kompo_wrap::LSTAT_HANDLE(path, stat) }
@@ -340,38 +465,277 @@ pub fn fstat_from_fs(fd: i32, stat: *mut libc::stat) -> i32 {
 }
 
 #[unsafe(no_mangle)]
-pub fn read_from_fs(fd: i32, buf: *mut libc::c_void, count: libc::size_t) -> isize {
-    fn inner_read(fd: i32, buf: *mut libc::c_void, count: libc::size_t) -> isize {
-        let buf = unsafe { std::slice::from_raw_parts_mut(buf as *mut u8, count) };
+pub fn statfs_from_fs(path: *const libc::c_char, buf: *mut libc::statfs) -> i32 {
+    fn inner_statfs(path: *const libc::c_char, buf: *mut libc::statfs) -> i32 {
+        if buf.is_null() {
+            errno::set_errno(errno::Errno(libc::EFAULT));
+            return -1;
+        }
 
-        let trie = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie));
-        let ret = trie.read(fd, buf);
+        use std::os::unix::ffi::OsStrExt;
 
-        if let Some(read_bytes) = ret {
-            read_bytes
+        let path = unsafe { CStr::from_ptr(path) };
+        let path = Path::new(std::ffi::OsStr::from_bytes(path.to_bytes()));
+        let search_path = path.iter().collect::<Vec<_>>();
+
+        let trie = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie));
+        if trie.statfs(&search_path, unsafe { &mut *buf }).is_some() {
+            0
         } else {
             errno::set_errno(errno::Errno(libc::ENOENT));
             -1
         }
     }
 
+    if path.is_null() {
+        errno::set_errno(errno::Errno(libc::EFAULT));
+        return -1;
+    }
+
+    if unsafe { util::is_empty_path(path) } {
+        errno::set_errno(errno::Errno(libc::ENOENT));
+        return -1;
+    }
+
+    if let Some(host_path) = unsafe { util::host_path(path) } {
+        return unsafe { kompo_wrap::STATFS_HANDLE(host_path.as_ptr(), buf) };
+    }
+
+    if has_working_dir() && unsafe { util::is_relative_path(path) } {
+        let expand_path = unsafe { util::expanded_kompo_path(path) };
+
+        inner_statfs(expand_path.as_ptr(), buf)
+    } else if unsafe { util::is_under_kompo_working_dir(path) || util::is_kompo_root(path) } {
+        inner_statfs(path, buf)
+    } else {
+        unsafe { kompo_wrap::STATFS_HANDLE(path, buf) }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub fn fstatfs_from_fs(fd: i32, buf: *mut libc::statfs) -> i32 {
+    fn inner_fstatfs(fd: i32, buf: *mut libc::statfs) -> i32 {
+        if buf.is_null() {
+            errno::set_errno(errno::Errno(libc::EFAULT));
+            return -1;
+        }
+
+        let trie = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie));
+        if trie.fstatfs(fd, unsafe { &mut *buf }).is_some() {
+            0
+        } else {
+            errno::set_errno(errno::Errno(libc::EBADF));
+            -1
+        }
+    }
+
+    if util::is_fd_exists_in_kompo(fd) {
+        inner_fstatfs(fd, buf)
+    } else {
+        unsafe { kompo_wrap::FSTATFS_HANDLE(fd, buf) }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[unsafe(no_mangle)]
+pub fn posix_fadvise_from_fs(
+    fd: i32,
+    offset: libc::off_t,
+    len: libc::off_t,
+    advice: libc::c_int,
+) -> libc::c_int {
+    if util::is_fd_exists_in_kompo(fd) {
+        // VFS files are already in memory, so there is nothing to advise.
+        0
+    } else {
+        unsafe { kompo_wrap::POSIX_FADVISE_HANDLE(fd, offset, len, advice) }
+    }
+}
+
+/// # Safety
+/// `how` must point to a readable `libc::open_how` of at least `size` bytes, or be null.
+#[cfg(target_os = "linux")]
+#[unsafe(no_mangle)]
+pub unsafe fn openat2_from_fs(
+    dirfd: libc::c_int,
+    pathname: *const libc::c_char,
+    how: *mut libc::c_void,
+    size: libc::size_t,
+) -> libc::c_int {
+    // libc may not export openat2 at all, so the real one is the raw syscall.
+    fn real_openat2(
+        dirfd: libc::c_int,
+        pathname: *const libc::c_char,
+        how: *mut libc::c_void,
+        size: libc::size_t,
+    ) -> libc::c_int {
+        unsafe { libc::syscall(libc::SYS_openat2, dirfd, pathname, how, size) as libc::c_int }
+    }
+
+    if pathname.is_null() || how.is_null() {
+        errno::set_errno(errno::Errno(libc::EFAULT));
+        return -1;
+    }
+
+    if unsafe { util::is_empty_path(pathname) } {
+        errno::set_errno(errno::Errno(libc::ENOENT));
+        return -1;
+    }
+
+    if size < std::mem::size_of::<libc::open_how>() {
+        errno::set_errno(errno::Errno(libc::EINVAL));
+        return -1;
+    }
+
+    // As the kernel does, a larger `how` from a newer caller is accepted only if the
+    // fields this one doesn't know are all unset
+    let extra = unsafe {
+        std::slice::from_raw_parts(
+            (how as *const u8).add(std::mem::size_of::<libc::open_how>()),
+            size - std::mem::size_of::<libc::open_how>(),
+        )
+    };
+    if extra.iter().any(|b| *b != 0) {
+        errno::set_errno(errno::Errno(libc::E2BIG));
+        return -1;
+    }
+
+    let open_how = unsafe { &*(how as *const libc::open_how) };
+    let flags = open_how.flags as libc::c_int;
+    let mode = open_how.mode as libc::mode_t;
+
+    // The same paths openat_from_fs serves from the VFS
+    let is_create_flag =
+        flags & libc::O_CREAT == libc::O_CREAT || flags & libc::O_TMPFILE == libc::O_TMPFILE;
+    let is_relative = unsafe { util::is_relative_path(pathname) };
+    let is_kompo_path = !is_create_flag
+        && (unsafe { util::is_under_kompo_working_dir(pathname) }
+            || (dirfd == libc::AT_FDCWD && has_working_dir() && is_relative));
+
+    if !is_kompo_path {
+        return real_openat2(dirfd, pathname, how, size);
+    }
+
+    // The VFS has no symlinks, so RESOLVE_NO_SYMLINKS always holds. It only has to
+    // keep RESOLVE_BENEATH and RESOLVE_IN_ROOT paths from leaving the start dir.
+    if open_how.resolve & (libc::RESOLVE_BENEATH | libc::RESOLVE_IN_ROOT) != 0 {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = unsafe { CStr::from_ptr(pathname) };
+        if util::escapes_beneath(Path::new(std::ffi::OsStr::from_bytes(path.to_bytes()))) {
+            errno::set_errno(errno::Errno(libc::EXDEV));
+            return -1;
+        }
+    }
+
+    unsafe { openat_from_fs(dirfd, pathname, flags, mode) }
+}
+
+#[cfg(target_os = "linux")]
+#[unsafe(no_mangle)]
+pub fn sendfile_from_fs(
+    out_fd: libc::c_int,
+    in_fd: libc::c_int,
+    offset: *mut libc::off_t,
+    count: libc::size_t,
+) -> libc::ssize_t {
+    fn inner_sendfile(
+        out_fd: libc::c_int,
+        in_fd: libc::c_int,
+        offset: *mut libc::off_t,
+        count: libc::size_t,
+    ) -> libc::ssize_t {
+        const CHUNK_SIZE: usize = 64 * 1024;
+
+        let trie = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie));
+        // With a null `offset`, sendfile reads from and advances the file offset.
+        let Some(mut pos) = (if offset.is_null() {
+            trie.file_offset(in_fd)
+        } else {
+            u64::try_from(unsafe { *offset }).ok()
+        }) else {
+            errno::set_errno(errno::Errno(libc::EINVAL));
+            return -1;
+        };
+
+        let mut buf = vec![0u8; count.min(CHUNK_SIZE)];
+        let mut sent = 0usize;
+        while sent < count {
+            let chunk = (count - sent).min(buf.len());
+            let read_size = match trie.read_at(in_fd, &mut buf[..chunk], pos) {
+                Some(0) => break,
+                Some(read_size) => read_size as usize,
+                None => {
+                    errno::set_errno(errno::Errno(libc::EINVAL));
+                    return -1;
+                }
+            };
+
+            let written = unsafe { libc::write(out_fd, buf.as_ptr() as *const _, read_size) };
+            if written < 0 {
+                if sent == 0 {
+                    return -1;
+                }
+                break;
+            }
+
+            sent += written as usize;
+            pos += written as u64;
+            if (written as usize) < read_size {
+                break;
+            }
+        }
+
+        if offset.is_null() {
+            trie.set_file_offset(in_fd, pos);
+        } else {
+            unsafe { *offset = pos as libc::off_t };
+        }
+
+        sent as libc::ssize_t
+    }
+
+    if util::is_fd_exists_in_kompo(in_fd) {
+        inner_sendfile(out_fd, in_fd, offset, count)
+    } else {
+        unsafe { kompo_wrap::SENDFILE_HANDLE(out_fd, in_fd, offset, count) }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub fn read_from_fs(fd: i32, buf: *mut libc::c_void, count: libc::size_t) -> isize {
     if util::is_fd_exists_in_kompo(fd) {
-        inner_read(fd, buf, count)
+        read_kompo_fd(fd, buf, count)
     } else {
         unsafe { kompo_wrap::READ_HANDLE(fd, buf, count) }
     }
 }
 
+// Reads the VFS fd `fd`. It may have been closed by another thread since the caller
+// checked it, in which case it fails with EBADF as the host's `read` would.
+pub(crate) fn read_kompo_fd(fd: i32, buf: *mut libc::c_void, count: libc::size_t) -> isize {
+    let buf = unsafe { std::slice::from_raw_parts_mut(buf as *mut u8, count) };
+
+    let trie = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie));
+    let ret = trie.read(fd, buf);
+
+    if let Some(read_bytes) = ret {
+        read_bytes
+    } else if trie.dir_path(fd).is_some() {
+        errno::set_errno(errno::Errno(libc::EISDIR));
+        -1
+    } else {
+        errno::set_errno(errno::Errno(libc::EBADF));
+        -1
+    }
+}
+
 #[unsafe(no_mangle)]
 pub fn getcwd_from_fs(buf: *mut libc::c_char, count: libc::size_t) -> *const libc::c_char {
     fn inner_getcwd(buf: *mut libc::c_char, count: libc::size_t) -> *const libc::c_char {
-        let working_dir = WORKING_DIR.read().unwrap();
-
-        if working_dir.is_none() {
+        let Some(working_dir) = current_working_dir() else {
             return std::ptr::null();
-        }
-
-        let working_dir = working_dir.clone().unwrap();
+        };
 
         if buf.is_null() {
             if count == 0 {
@@ -390,7 +754,7 @@ pub fn getcwd_from_fs(buf: *mut libc::c_char, count: libc::size_t) -> *const lib
         }
     }
 
-    if WORKING_DIR.read().unwrap().is_some() {
+    if has_working_dir() {
         inner_getcwd(buf, count)
     } else {
         unsafe { kompo_wrap::GETCWD_HANDLE(buf, count) }
@@ -405,26 +769,38 @@ pub fn chdir_from_fs(path: *const libc::c_char) -> libc::c_int {
 
         let search_path = path.iter().collect::<Vec<_>>();
         let trie = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie));
-        let bool = trie.is_dir_exists_from_path(&search_path);
 
-        if bool {
-            let changed_path = path.as_os_str().to_os_string();
-            *WORKING_DIR.write().unwrap() = Some(changed_path);
+        if trie.is_dir_exists_from_path(&search_path) {
+            set_working_dir(Some(path));
 
-            1
+            0
+        } else if trie.contains(&search_path) {
+            errno::set_errno(errno::Errno(libc::ENOTDIR));
+            -1
         } else {
+            errno::set_errno(errno::Errno(libc::ENOENT));
             -1
         }
     }
 
-    let change_dir = unsafe { util::expand_kompo_path(path) };
+    if path.is_null() {
+        errno::set_errno(errno::Errno(libc::EFAULT));
+        return -1;
+    }
+
+    if unsafe { util::is_empty_path(path) } {
+        errno::set_errno(errno::Errno(libc::ENOENT));
+        return -1;
+    }
 
-    if unsafe { util::is_under_kompo_working_dir(change_dir) } {
-        inner_chdir(change_dir)
+    let change_dir = unsafe { util::expanded_kompo_path(path) };
+
+    if unsafe { util::is_under_kompo_working_dir(change_dir.as_ptr()) } {
+        inner_chdir(change_dir.as_ptr())
     } else {
         let ret = unsafe { kompo_wrap::CHDIR_HANDLE(path) };
         if ret == 0 {
-            *WORKING_DIR.write().unwrap() = None;
+            set_working_dir(None);
         }
 
         ret
@@ -436,10 +812,7 @@ pub fn fdopendir_from_fs(fd: i32) -> *mut libc::DIR {
     fn inner_fdopendir(fd: i32) -> *mut libc::DIR {
         let trie = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie));
         match trie.fdopendir(fd) {
-            Some(dir) => {
-                let dir = Box::new(dir);
-                Box::into_raw(dir) as *mut libc::DIR
-            }
+            Some(dir) => util::into_dir_stream(dir),
             None => std::ptr::null_mut(),
         }
     }
@@ -454,22 +827,17 @@ pub fn fdopendir_from_fs(fd: i32) -> *mut libc::DIR {
 #[unsafe(no_mangle)]
 pub fn readdir_from_fs(dir: *mut libc::DIR) -> *mut libc::dirent {
     fn inner_readdir(dir: *mut libc::DIR) -> *mut libc::dirent {
-        let mut dir = unsafe { Box::from_raw(dir as *mut kompo_storage::FsDir) };
-
         let trie = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie));
-        match trie.readdir(&mut dir) {
-            Some(dirent) => {
-                let _ = Box::into_raw(dir);
-                dirent
-            }
-            None => {
-                let _ = Box::into_raw(dir);
+        match util::with_dir_stream(dir, |dir| trie.readdir(dir)).and_then(|result| result) {
+            Ok(dirent) => dirent,
+            Err(err) => {
+                errno::set_errno(errno::Errno(err.errno()));
                 std::ptr::null_mut()
             }
         }
     }
 
-    if unsafe { util::is_dir_exists_in_kompo(dir) } {
+    if util::is_dir_exists_in_kompo(dir) {
         inner_readdir(dir)
     } else {
         unsafe { kompo_wrap::READDIR_HANDLE(dir) }
@@ -478,11 +846,14 @@ pub fn readdir_from_fs(dir: *mut libc::DIR) -> *mut libc::dirent {
 
 #[unsafe(no_mangle)]
 pub fn closedir_from_fs(dir: *mut libc::DIR) -> i32 {
-    if unsafe { util::is_dir_exists_in_kompo(dir) } {
-        let dir = unsafe { Box::from_raw(dir as *mut kompo_storage::FsDir) };
-        std::sync::Arc::clone(TRIE.get_or_init(initialize_trie)).closedir(&dir);
-
-        unsafe { kompo_wrap::CLOSE_HANDLE(dir.fd) }
+    if util::is_dir_exists_in_kompo(dir) {
+        match util::close_dir_stream(dir) {
+            Ok(fd) => unsafe { kompo_wrap::CLOSE_HANDLE(fd) },
+            Err(err) => {
+                errno::set_errno(errno::Errno(err.errno()));
+                -1
+            }
+        }
     } else {
         unsafe { kompo_wrap::CLOSEDIR_HANDLE(dir) }
     }
@@ -498,18 +869,29 @@ pub fn opendir_from_fs(path: *const libc::c_char) -> *mut libc::DIR {
 
         let trie = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie));
         match trie.opendir(&path) {
-            Some(dir) => {
-                let dir = Box::new(dir);
-                Box::into_raw(dir) as *mut libc::DIR
-            }
+            Some(dir) => util::into_dir_stream(dir),
             None => std::ptr::null_mut(),
         }
     }
 
-    if WORKING_DIR.read().unwrap().is_some() && unsafe { *path } != b'/'.try_into().unwrap() {
-        let expand_path = unsafe { util::expand_kompo_path(path) };
-        inner_opendir(expand_path)
-    } else if unsafe { util::is_under_kompo_working_dir(path) } {
+    if path.is_null() {
+        errno::set_errno(errno::Errno(libc::EFAULT));
+        return std::ptr::null_mut();
+    }
+
+    if unsafe { util::is_empty_path(path) } {
+        errno::set_errno(errno::Errno(libc::ENOENT));
+        return std::ptr::null_mut();
+    }
+
+    if let Some(host_path) = unsafe { util::host_path(path) } {
+        return unsafe { kompo_wrap::OPENDIR_HANDLE(host_path.as_ptr()) };
+    }
+
+    if has_working_dir() && unsafe { util::is_relative_path(path) } {
+        let expand_path = unsafe { util::expanded_kompo_path(path) };
+        inner_opendir(expand_path.as_ptr())
+    } else if unsafe { util::is_under_kompo_working_dir(path) || util::is_kompo_root(path) } {
         inner_opendir(path)
     } else {
         unsafe { kompo_wrap::OPENDIR_HANDLE(path) }
@@ -519,14 +901,12 @@ pub fn opendir_from_fs(path: *const libc::c_char) -> *mut libc::DIR {
 #[unsafe(no_mangle)]
 pub fn rewinddir_from_fs(dir: *mut libc::DIR) {
     fn inner_rewinddir(dir: *mut libc::DIR) {
-        let mut dir = unsafe { Box::from_raw(dir as *mut kompo_storage::FsDir) };
-
         let trie = std::sync::Arc::clone(TRIE.get_or_init(initialize_trie));
-        trie.rewinddir(&mut dir);
-        let _ = Box::into_raw(dir);
+        // `rewinddir` reports no errors, so a closed stream is left alone
+        let _ = util::with_dir_stream(dir, |dir| trie.rewinddir(dir));
     }
 
-    if unsafe { util::is_dir_exists_in_kompo(dir) } {
+    if util::is_dir_exists_in_kompo(dir) {
         inner_rewinddir(dir)
     } else {
         unsafe { kompo_wrap::REWINDDIR_HANDLE(dir) }
@@ -542,24 +922,52 @@ pub unsafe extern "C-unwind" fn realpath_from_fs(
         path: *const libc::c_char,
         resolved_path: *mut libc::c_char,
     ) -> *const libc::c_char {
-        if resolved_path.is_null() {
-            unsafe { util::expand_kompo_path(path) }
-        } else {
-            let expand_path = unsafe { CStr::from_ptr(util::expand_kompo_path(path)) };
-            let bytes = expand_path.to_bytes_with_nul();
-            unsafe {
-                std::ptr::copy_nonoverlapping(
-                    bytes.as_ptr() as *const libc::c_char,
-                    resolved_path,
-                    bytes.len(),
-                );
-            }
+        let expand_path = unsafe { util::expanded_kompo_path(path) };
+        let bytes = expand_path.as_bytes_with_nul();
+        // The caller's buffer holds PATH_MAX bytes, terminator included
+        if bytes.len() > libc::PATH_MAX as usize {
+            errno::set_errno(errno::Errno(libc::ENAMETOOLONG));
+            return std::ptr::null();
+        }
 
+        // Without a buffer, the result is malloc'd for the caller to free
+        let resolved_path = if resolved_path.is_null() {
+            let buf = unsafe { libc::malloc(bytes.len()) } as *mut libc::c_char;
+            if buf.is_null() {
+                errno::set_errno(errno::Errno(libc::ENOMEM));
+                return std::ptr::null();
+            }
+            buf
+        } else {
             resolved_path
+        };
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr() as *const libc::c_char,
+                resolved_path,
+                bytes.len(),
+            );
         }
+
+        resolved_path
+    }
+
+    if path.is_null() {
+        errno::set_errno(errno::Errno(libc::EFAULT));
+        return std::ptr::null();
     }
 
-    if (WORKING_DIR.read().unwrap().is_some() && unsafe { *path } != b'/'.try_into().unwrap())
+    if unsafe { util::is_empty_path(path) } {
+        errno::set_errno(errno::Errno(libc::ENOENT));
+        return std::ptr::null();
+    }
+
+    if let Some(host_path) = unsafe { util::host_path(path) } {
+        return unsafe { kompo_wrap::REALPATH_HANDLE(host_path.as_ptr(), resolved_path) };
+    }
+
+    if (has_working_dir() && unsafe { util::is_relative_path(path) })
         || unsafe { util::is_under_kompo_working_dir(path) }
     {
         unsafe { inner_realpath(path, resolved_path) }
@@ -571,24 +979,40 @@ pub unsafe extern "C-unwind" fn realpath_from_fs(
 #[unsafe(no_mangle)]
 pub fn mkdir_from_fs(path: *const libc::c_char, mode: libc::mode_t) -> libc::c_int {
     fn inner_mkdir(path: *const libc::c_char) -> libc::c_int {
-        let layout = std::alloc::Layout::new::<libc::stat>();
-        let stat_buf = unsafe { std::alloc::alloc(layout) as *mut libc::stat };
-
-        let ret = stat_from_fs(path, stat_buf);
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
 
-        unsafe { std::alloc::dealloc(stat_buf as *mut u8, layout) };
+        if stat_from_fs(path, &mut stat_buf) != 0 {
+            // The VFS is read-only, so nothing new can be created in it.
+            errno::set_errno(errno::Errno(libc::EROFS));
+            return -1;
+        }
 
-        if ret == 0 {
-            return 0;
+        if stat_buf.st_mode & libc::S_IFMT == libc::S_IFDIR {
+            // Already there, so treat it as created.
+            0
+        } else {
+            errno::set_errno(errno::Errno(libc::EEXIST));
+            -1
         }
+    }
+
+    if path.is_null() {
+        errno::set_errno(errno::Errno(libc::EFAULT));
+        return -1;
+    }
 
+    if unsafe { util::is_empty_path(path) } {
         errno::set_errno(errno::Errno(libc::ENOENT));
-        -1
+        return -1;
+    }
+
+    if let Some(host_path) = unsafe { util::host_path(path) } {
+        return unsafe { kompo_wrap::MKDIR_HANDLE(host_path.as_ptr(), mode) };
     }
 
-    if WORKING_DIR.read().unwrap().is_some() && unsafe { *path } != b'/'.try_into().unwrap() {
-        let expand_path = unsafe { util::expand_kompo_path(path) };
-        inner_mkdir(expand_path)
+    if has_working_dir() && unsafe { util::is_relative_path(path) } {
+        let expand_path = unsafe { util::expanded_kompo_path(path) };
+        inner_mkdir(expand_path.as_ptr())
     } else if unsafe { util::is_under_kompo_working_dir(path) } {
         inner_mkdir(path)
     } else {
@@ -633,9 +1057,31 @@ pub fn getattrlist_from_fs(
         }
     }
 
-    if WORKING_DIR.read().unwrap().is_some() && unsafe { *path } != b'/'.try_into().unwrap() {
-        let expand_path = unsafe { util::expand_kompo_path(path) };
-        inner_getattrlist(expand_path, attr_list, attr_buf, attr_buf_size)
+    if path.is_null() {
+        errno::set_errno(errno::Errno(libc::EFAULT));
+        return -1;
+    }
+
+    if unsafe { util::is_empty_path(path) } {
+        errno::set_errno(errno::Errno(libc::ENOENT));
+        return -1;
+    }
+
+    if let Some(host_path) = unsafe { util::host_path(path) } {
+        return unsafe {
+            kompo_wrap::GETATTRLIST_HANDLE(
+                host_path.as_ptr(),
+                attr_list,
+                attr_buf,
+                attr_buf_size,
+                options,
+            )
+        };
+    }
+
+    if has_working_dir() && unsafe { util::is_relative_path(path) } {
+        let expand_path = unsafe { util::expanded_kompo_path(path) };
+        inner_getattrlist(expand_path.as_ptr(), attr_list, attr_buf, attr_buf_size)
     } else if unsafe { util::is_under_kompo_working_dir(path) } {
         inner_getattrlist(path, attr_list, attr_buf, attr_buf_size)
     } else {
@@ -0,0 +1,144 @@
+//! gzip decoding for [`kompo_storage::Fs::add_gzip_assets`], through the zlib the
+//! crate already links for compressed bundles.
+
+// Largest decompressed contents of gzip assets kept in memory at once.
+pub const GZIP_ASSETS_CACHE_BYTES: usize = 16 * 1024 * 1024;
+
+const Z_OK: libc::c_int = 0;
+const Z_STREAM_END: libc::c_int = 1;
+const Z_BUF_ERROR: libc::c_int = -5;
+const Z_NO_FLUSH: libc::c_int = 0;
+// 15 bits of window, plus 16 to expect a gzip header and trailer
+const GZIP_WINDOW_BITS: libc::c_int = 15 + 16;
+
+#[repr(C)]
+struct ZStream {
+    next_in: *const u8,
+    avail_in: libc::c_uint,
+    total_in: libc::c_ulong,
+    next_out: *mut u8,
+    avail_out: libc::c_uint,
+    total_out: libc::c_ulong,
+    msg: *const libc::c_char,
+    state: *mut libc::c_void,
+    zalloc: *const libc::c_void,
+    zfree: *const libc::c_void,
+    opaque: *mut libc::c_void,
+    data_type: libc::c_int,
+    adler: libc::c_ulong,
+    reserved: libc::c_ulong,
+}
+
+unsafe extern "C" {
+    fn zlibVersion() -> *const libc::c_char;
+    fn inflateInit2_(
+        strm: *mut ZStream,
+        window_bits: libc::c_int,
+        version: *const libc::c_char,
+        stream_size: libc::c_int,
+    ) -> libc::c_int;
+    fn inflate(strm: *mut ZStream, flush: libc::c_int) -> libc::c_int;
+    fn inflateEnd(strm: *mut ZStream) -> libc::c_int;
+}
+
+/// Decompresses the single gzip member `compressed`, or returns `None` if it is
+/// corrupt or truncated. Bytes after the member are ignored.
+pub fn gunzip(compressed: &[u8]) -> Option<Vec<u8>> {
+    // Null allocators make zlib use its own
+    let mut stream: ZStream = unsafe { std::mem::zeroed() };
+    stream.next_in = compressed.as_ptr();
+    stream.avail_in = libc::c_uint::try_from(compressed.len()).ok()?;
+
+    let ret = unsafe {
+        inflateInit2_(
+            &mut stream,
+            GZIP_WINDOW_BITS,
+            zlibVersion(),
+            std::mem::size_of::<ZStream>() as libc::c_int,
+        )
+    };
+    if ret != Z_OK {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(compressed.len().saturating_mul(4).max(64));
+    let result = loop {
+        if out.len() == out.capacity() {
+            out.reserve(out.capacity());
+        }
+        let spare = out.spare_capacity_mut();
+        let avail = spare.len().min(libc::c_uint::MAX as usize);
+        stream.next_out = spare.as_mut_ptr() as *mut u8;
+        stream.avail_out = avail as libc::c_uint;
+
+        let ret = unsafe { inflate(&mut stream, Z_NO_FLUSH) };
+        let written = avail - stream.avail_out as usize;
+        // SAFETY: zlib initialized the `written` bytes it reports
+        unsafe { out.set_len(out.len() + written) };
+
+        match ret {
+            Z_STREAM_END => break Some(out),
+            // More output space lets it continue; no input left means truncated
+            Z_OK | Z_BUF_ERROR if stream.avail_out == 0 => continue,
+            _ => break None,
+        }
+    };
+
+    unsafe { inflateEnd(&mut stream) };
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    // `console.log('hi');\n`, as compressed by gzip
+    const APP_JS_GZ: [u8; 39] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x4b, 0xce, 0xcf, 0x2b, 0xce,
+        0xcf, 0x49, 0xd5, 0xcb, 0xc9, 0x4f, 0xd7, 0x50, 0xcf, 0xc8, 0x54, 0xd7, 0xb4, 0xe6, 0x02,
+        0x00, 0x3b, 0x55, 0x79, 0x51, 0x13, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_gunzip() {
+        assert_eq!(gunzip(&APP_JS_GZ).unwrap(), b"console.log('hi');\n");
+        assert_eq!(gunzip(&APP_JS_GZ[..20]), None);
+        assert_eq!(gunzip(b"not gzip"), None);
+
+        // 100000 `a`s, which decompress to far more than the initial buffer
+        let mut compressed = vec![0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03];
+        compressed.extend_from_slice(&[
+            0xed, 0xc1, 0x31, 0x01, 0x00, 0x00, 0x00, 0xc2, 0xa0, 0xac, 0xeb, 0x5f, 0xc2, 0x1a,
+            0x1e, 0x40, 0x01,
+        ]);
+        compressed.extend_from_slice(&[0; 96]);
+        compressed.extend_from_slice(&[0xaf, 0x06, 0x87, 0xfa, 0xe2, 0x1b, 0xa0, 0x86, 0x01, 0x00]);
+        assert_eq!(gunzip(&compressed).unwrap(), vec![b'a'; 100000]);
+    }
+
+    #[test]
+    fn test_gzip_assets_served_decompressed() {
+        let mut builder = trie_rs::map::TrieBuilder::new();
+        let path = ["/", "assets", "app.js.gz"].map(OsStr::new).to_vec();
+        builder.push(path.clone(), &APP_JS_GZ[..]);
+        let fs = kompo_storage::Fs::new(builder);
+        assert_eq!(fs.add_gzip_assets(gunzip, GZIP_ASSETS_CACHE_BYTES), 1);
+
+        let read_all = |path: &Vec<&OsStr>| {
+            let fd = fs.open(path, libc::O_RDONLY).unwrap();
+            let mut buf = [0u8; 64];
+            let n = fs.read(fd, &mut buf).unwrap() as usize;
+            fs.close(fd).unwrap();
+            buf[..n].to_vec()
+        };
+        assert_eq!(read_all(&path), APP_JS_GZ);
+        let stripped = ["/", "assets", "app.js"].map(OsStr::new).to_vec();
+        assert_eq!(read_all(&stripped), b"console.log('hi');\n");
+
+        // As recorded in the trailer
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        assert!(fs.stat(&stripped, &mut stat).is_some());
+        assert_eq!(stat.st_size, 19);
+    }
+}
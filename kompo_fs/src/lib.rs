@@ -1,23 +1,72 @@
 mod glue;
+#[cfg(feature = "gzip-assets")]
+mod gzip;
+mod tables;
 pub mod util;
 use std::ffi::CStr;
 use std::ffi::CString;
 use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+use std::path::PathBuf;
 use trie_rs::map::TrieBuilder;
 
 static TRIE: std::sync::OnceLock<std::sync::Arc<kompo_storage::Fs>> = std::sync::OnceLock::new();
 
+/// Returns the VFS if it has been built, without building it. It is built from the
+/// embedded tables on the first hooked call that needs it, so until then nothing can
+/// be open in it and fd-based checks answer from this alone.
+///
+/// Building does not wait for `Init_kompo_fs`, which only defines the `Kompo` Ruby
+/// class, so a hook is never called too early. Tables that are inconsistent, as in
+/// a binary the packer did not produce, abort with a message naming the problem.
+pub fn initialized_trie() -> Option<&'static std::sync::Arc<kompo_storage::Fs<'static>>> {
+    TRIE.get()
+}
+
+/// Returns whether the VFS has been built from the embedded tables.
+pub fn is_initialized() -> bool {
+    initialized_trie().is_some()
+}
+
 pub static WORKING_DIR: std::sync::RwLock<Option<std::ffi::OsString>> =
     std::sync::RwLock::new(None);
 
+// Every update replaces the whole value, so a poisoned lock is used as is.
+fn working_dir() -> std::sync::RwLockReadGuard<'static, Option<std::ffi::OsString>> {
+    WORKING_DIR
+        .read()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// Returns the VFS working directory, or `None` while the process is outside the
+/// VFS, as before the first `chdir` into it.
+pub fn current_working_dir() -> Option<PathBuf> {
+    working_dir().clone().map(PathBuf::from)
+}
+
+/// Returns whether a VFS working directory is set, without copying it.
+pub fn has_working_dir() -> bool {
+    working_dir().is_some()
+}
+
+/// Sets the VFS working directory, or leaves the VFS with `None`. The path is taken
+/// as is: it is not checked to exist in the VFS.
+pub fn set_working_dir(path: Option<&Path>) {
+    *WORKING_DIR
+        .write()
+        .unwrap_or_else(std::sync::PoisonError::into_inner) =
+        path.map(|path| path.as_os_str().to_os_string());
+}
+
+// Keyed by `pthread_self()` as an integer, since `pthread_t` is a pointer on musl
+// and would make this static neither `Send` nor `Sync`.
 pub static THREAD_CONTEXT: std::sync::OnceLock<
-    std::sync::Arc<std::sync::RwLock<std::collections::HashMap<libc::pthread_t, bool>>>,
+    std::sync::Arc<std::sync::RwLock<std::collections::HashMap<usize, bool>>>,
 > = std::sync::OnceLock::new();
 
-static FILE_TYPE_CACHE: std::sync::LazyLock<
-    std::sync::RwLock<std::collections::HashMap<Vec<std::ffi::OsString>, libc::stat>>,
-> = std::sync::LazyLock::new(|| std::sync::RwLock::new(std::collections::HashMap::new()));
+static FILE_TYPE_CACHE: std::sync::LazyLock<kompo_storage::StatCache> =
+    std::sync::LazyLock::new(kompo_storage::StatCache::new);
 
 #[allow(clippy::upper_case_acronyms)]
 type VALUE = u64;
@@ -33,6 +82,9 @@ unsafe extern "C" {
     static FILES: libc::c_char;
     static FILES_SIZES: libc::c_ulonglong;
     static FILES_SIZE: libc::c_int;
+    // XXH3 hash of the uncompressed FILES blob
+    #[cfg(feature = "integrity")]
+    static FILES_HASH: libc::c_ulonglong;
     static PATHS: libc::c_char;
     static PATHS_SIZE: libc::c_int;
     static WD: libc::c_char;
@@ -65,6 +117,7 @@ unsafe extern "C" {
     static rb_cObject: VALUE;
     fn rb_define_class(name: *const libc::c_char, rb_super: VALUE) -> VALUE;
     // fn rb_string_value_ptr(v: *const VALUE) -> *const libc::c_char;
+    fn rb_string_value_cstr(v: *mut VALUE) -> *const libc::c_char;
     fn rb_define_singleton_method(
         object: VALUE,
         name: *const libc::c_char,
@@ -80,9 +133,59 @@ unsafe extern "C" {
         data2: VALUE,
     ) -> VALUE;
     fn rb_yield(v: VALUE) -> VALUE;
+    fn rb_ary_new() -> VALUE;
+    fn rb_ary_push(ary: VALUE, item: VALUE) -> VALUE;
+    fn rb_str_new(ptr: *const libc::c_char, len: libc::c_long) -> VALUE;
+    fn rb_int2inum(n: isize) -> VALUE;
+    fn rb_gv_get(name: *const libc::c_char) -> VALUE;
+    fn rb_ary_entry(ary: VALUE, offset: libc::c_long) -> VALUE;
+    fn rb_funcall(recv: VALUE, mid: VALUE, n: libc::c_int, ...) -> VALUE;
+    fn rb_num2long(num: VALUE) -> libc::c_long;
+    fn rb_string_value_ptr(v: *mut VALUE) -> *const libc::c_char;
+    fn rb_str_bytesize(str: VALUE) -> VALUE;
+    fn rb_check_string_type(v: VALUE) -> VALUE;
+    fn rb_hash_new() -> VALUE;
+    fn rb_hash_aset(hash: VALUE, key: VALUE, value: VALUE) -> VALUE;
+    fn rb_intern(name: *const libc::c_char) -> VALUE;
+    fn rb_id2sym(id: VALUE) -> VALUE;
+    fn rb_ull2inum(n: libc::c_ulonglong) -> VALUE;
+    fn rb_syserr_fail_str(errno: libc::c_int, message: VALUE) -> !;
+
+    // Resolved against whichever kompo_storage object ends up linked
+    fn kompo_storage_abi_version() -> u32;
+}
+
+include!(concat!(env!("OUT_DIR"), "/abi_version.rs"));
+
+/// Aborts if the linked kompo_storage was built with a different ABI version than
+/// this crate expects, rather than misreading its data structures.
+fn check_storage_abi_version() {
+    let actual = unsafe { kompo_storage_abi_version() };
+    if actual != EXPECTED_STORAGE_ABI_VERSION {
+        eprintln!(
+            "kompo_fs: kompo_storage ABI version mismatch (expected {}, found {})",
+            EXPECTED_STORAGE_ABI_VERSION, actual
+        );
+        std::process::abort();
+    }
+}
+
+#[cfg(feature = "integrity")]
+fn verify_files_integrity(files: &[u8]) {
+    let expected = unsafe { FILES_HASH };
+    if !kompo_storage::verify_content_hash(files, expected) {
+        eprintln!(
+            "kompo_fs: embedded files were modified (expected hash {:016x}, found {:016x})",
+            expected,
+            kompo_storage::content_hash(files)
+        );
+        std::process::abort();
+    }
 }
 
 fn initialize_trie() -> std::sync::Arc<kompo_storage::Fs<'static>> {
+    check_storage_abi_version();
+
     std::sync::Arc::new(initialize_fs())
 }
 
@@ -108,29 +211,42 @@ fn decompress_all_files() {
     }
 }
 
+// Every update is a single insert or remove, so a thread panicking while it holds
+// the lock cannot leave the map inconsistent and a poisoned lock is used as is.
+// Leaving the block removes the entry rather than storing false, so threads that
+// exit don't leave entries behind for a reused `pthread_t` to find.
+fn set_thread_context(in_context: bool) {
+    let binding = std::sync::Arc::clone(THREAD_CONTEXT.get_or_init(Default::default));
+    let mut binding = binding
+        .write()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    let thread_id = unsafe { libc::pthread_self() } as usize;
+
+    if in_context {
+        binding.insert(thread_id, true);
+    } else {
+        binding.remove(&thread_id);
+    }
+}
+
+// Threads that never entered a `Kompo.context` block are not in the map.
+fn is_thread_in_context() -> bool {
+    let binding = std::sync::Arc::clone(THREAD_CONTEXT.get_or_init(Default::default));
+    let binding = binding
+        .read()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    let thread_id = unsafe { libc::pthread_self() } as usize;
+
+    binding.get(&thread_id).copied().unwrap_or(false)
+}
+
 unsafe extern "C" fn context_func(_: VALUE, _: VALUE) -> VALUE {
     unsafe { rb_need_block() };
 
-    let binding = std::sync::Arc::clone(
-        THREAD_CONTEXT
-            .get()
-            .expect("not initialized THREAD_CONTEXT"),
-    );
-    {
-        let mut binding = binding.write().expect("THREAD_CONTEXT is posioned");
-        binding.insert(unsafe { libc::pthread_self() }, true);
-    }
+    set_thread_context(true);
 
     unsafe extern "C" fn close(_: VALUE) -> VALUE {
-        let binding = std::sync::Arc::clone(
-            THREAD_CONTEXT
-                .get()
-                .expect("not initialized THREAD_CONTEXT"),
-        );
-        {
-            let mut binding = binding.write().expect("THREAD_CONTEXT is posioned");
-            binding.insert(unsafe { libc::pthread_self() }, false);
-        }
+        set_thread_context(false);
 
         Ruby::NIL as VALUE
     }
@@ -138,25 +254,10 @@ unsafe extern "C" fn context_func(_: VALUE, _: VALUE) -> VALUE {
     unsafe { rb_ensure(rb_yield, Ruby::NIL as VALUE, close, Ruby::NIL as VALUE) }
 }
 
+// `Kompo.context?`: whether the calling thread is inside a `Kompo.context` block.
+// False for threads that never entered one, like the main thread or pool threads.
 unsafe extern "C" fn is_context_func(_: VALUE, _: VALUE) -> VALUE {
-    let binding = std::sync::Arc::clone(
-        THREAD_CONTEXT
-            .get()
-            .expect("not initialized THREAD_CONTEXT"),
-    );
-    {
-        let binding = binding.read().expect("THREAD_CONTEXT is posioned");
-        let thread_id = unsafe { libc::pthread_self() };
-        if let Some(bool) = binding.get(&thread_id) {
-            if *bool {
-                Ruby::TRUE as VALUE
-            } else {
-                Ruby::FALSE as VALUE
-            }
-        } else {
-            unreachable!("not found pthread_t")
-        }
-    }
+    ruby_bool(is_thread_in_context())
 }
 
 pub fn initialize_fs() -> kompo_storage::Fs<'static> {
@@ -169,40 +270,50 @@ pub fn initialize_fs() -> kompo_storage::Fs<'static> {
 
     let mut builder = TrieBuilder::new();
 
-    let path_slice = unsafe {
-        std::slice::from_raw_parts(&PATHS as *const libc::c_char as *const u8, PATHS_SIZE as _)
-    };
-
-    // Use FILES_BUFFER when compression is enabled, FILES otherwise
-    let file_slice = if compression_enabled {
-        unsafe {
-            std::slice::from_raw_parts(
+    // Use FILES_BUFFER and ORIGINAL_SIZES when compression is enabled, FILES and
+    // FILES_SIZES otherwise
+    let tables = unsafe {
+        if compression_enabled {
+            tables::embedded_tables(
+                &PATHS as *const libc::c_char as *const u8,
+                PATHS_SIZE,
                 std::ptr::addr_of!(FILES_BUFFER) as *const libc::c_char as *const u8,
-                FILES_BUFFER_SIZE as _,
+                FILES_BUFFER_SIZE,
+                &ORIGINAL_SIZES,
+            )
+        } else {
+            tables::embedded_tables(
+                &PATHS as *const libc::c_char as *const u8,
+                PATHS_SIZE,
+                &FILES as *const libc::c_char as *const u8,
+                FILES_SIZE,
+                &FILES_SIZES,
             )
-        }
-    } else {
-        unsafe {
-            std::slice::from_raw_parts(&FILES as *const libc::c_char as *const u8, FILES_SIZE as _)
         }
     };
-
-    let splited_path_array = path_slice
-        .split_inclusive(|a| *a == b'\0')
-        .collect::<Vec<_>>();
-
-    // Use ORIGINAL_SIZES when compression is enabled, FILES_SIZES otherwise
-    let files_sizes = if compression_enabled {
-        unsafe { std::slice::from_raw_parts(&ORIGINAL_SIZES, splited_path_array.len() + 1) }
-    } else {
-        unsafe { std::slice::from_raw_parts(&FILES_SIZES, splited_path_array.len() + 1) }
-    };
-
-    for (i, path_byte) in splited_path_array.into_iter().enumerate() {
-        let path = Path::new(unsafe {
-            let bytes = std::slice::from_raw_parts(path_byte.as_ptr(), path_byte.len());
-            CStr::from_bytes_with_nul_unchecked(bytes).to_str().unwrap()
-        });
+    let tables = tables.unwrap_or_else(|e| {
+        eprintln!("kompo_fs: invalid embedded tables ({e}); was this binary built by kompo?");
+        std::process::abort();
+    });
+    let file_slice = tables.files;
+    let files_sizes = tables.files_sizes;
+
+    #[cfg(feature = "integrity")]
+    verify_files_integrity(file_slice);
+
+    let splited_path_array = tables::split_paths(tables.paths).unwrap_or_else(|e| {
+        eprintln!("kompo_fs: invalid embedded tables ({e}); was this binary built by kompo?");
+        std::process::abort();
+    });
+
+    // The packer marks an empty directory with a path ending in "/" and no contents
+    let mut empty_dirs = Vec::new();
+    for (i, path_cstr) in splited_path_array.into_iter().enumerate() {
+        let path = Path::new(std::ffi::OsStr::from_bytes(path_cstr.to_bytes()));
+        if path_cstr.to_bytes().ends_with(b"/") {
+            empty_dirs.push(path.iter().collect::<Vec<_>>());
+            continue;
+        }
         let path = path.iter().collect::<Vec<_>>();
 
         let range: Range<usize> = files_sizes[i] as usize..files_sizes[i + 1] as usize;
@@ -212,7 +323,167 @@ pub fn initialize_fs() -> kompo_storage::Fs<'static> {
         builder.push(path, file);
     }
 
-    kompo_storage::Fs::new(builder)
+    let fs = kompo_storage::Fs::with_empty_dirs(builder, &empty_dirs, Default::default());
+    #[cfg(feature = "gzip-assets")]
+    fs.add_gzip_assets(gzip::gunzip, gzip::GZIP_ASSETS_CACHE_BYTES);
+
+    fs
+}
+
+fn kompo_exists(path: &Path) -> bool {
+    let path = util::resolve_kompo_path(path);
+    let trie = TRIE.get_or_init(initialize_trie);
+
+    trie.contains(&path.iter().collect())
+}
+
+fn kompo_is_directory(path: &Path) -> bool {
+    let path = util::resolve_kompo_path(path);
+    let trie = TRIE.get_or_init(initialize_trie);
+
+    trie.is_dir_exists_from_path(&path.iter().collect())
+}
+
+fn kompo_stat(path: &Path) -> Option<libc::stat> {
+    let path = util::resolve_kompo_path(path);
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+
+    TRIE.get_or_init(initialize_trie)
+        .stat(&path.iter().collect(), &mut stat)
+        .map(|_| stat)
+}
+
+// Load paths are taken relative to the VFS working directory, as `require` would.
+fn kompo_which(feature: &str, load_paths: &[PathBuf]) -> Option<PathBuf> {
+    let load_paths = load_paths
+        .iter()
+        .map(|path| util::resolve_kompo_path(path))
+        .collect::<Vec<_>>();
+    let load_paths = load_paths
+        .iter()
+        .map(|path| path.as_os_str())
+        .collect::<Vec<_>>();
+
+    TRIE.get_or_init(initialize_trie)
+        .resolve_require(feature, &load_paths)
+}
+
+fn kompo_open_fds() -> Vec<(i32, std::path::PathBuf)> {
+    initialized_trie().map_or_else(Vec::new, |trie| trie.open_fds())
+}
+
+fn ruby_bool(value: bool) -> VALUE {
+    if value {
+        Ruby::TRUE as VALUE
+    } else {
+        Ruby::FALSE as VALUE
+    }
+}
+
+// The bytes of the String `value`. Neither call raises for a String, unlike `rb_string_value_cstr` for one holding a NUL.
+unsafe fn ruby_string_bytes<'a>(mut value: VALUE) -> &'a [u8] {
+    unsafe {
+        let ptr = rb_string_value_ptr(&mut value);
+        // A Fixnum, tagged in its low bit
+        let len = (rb_str_bytesize(value) >> 1) as usize;
+        std::slice::from_raw_parts(ptr as *const u8, len)
+    }
+}
+
+// Raises a Ruby TypeError unless `path` is a String without NUL bytes.
+unsafe fn ruby_path(mut path: VALUE) -> std::path::PathBuf {
+    let path = unsafe { CStr::from_ptr(rb_string_value_cstr(&mut path)) };
+    Path::new(std::ffi::OsStr::from_bytes(path.to_bytes())).to_path_buf()
+}
+
+unsafe extern "C" fn exist_func(_: VALUE, path: VALUE) -> VALUE {
+    ruby_bool(kompo_exists(&unsafe { ruby_path(path) }))
+}
+
+unsafe extern "C" fn directory_func(_: VALUE, path: VALUE) -> VALUE {
+    ruby_bool(kompo_is_directory(&unsafe { ruby_path(path) }))
+}
+
+// `Kompo.stat(path)`: `{size:, mode:, ino:, directory?:}` for the embedded file or
+// directory at `path`. Raises `Errno::ENOENT` if it is not embedded.
+unsafe extern "C" fn stat_func(_: VALUE, path: VALUE) -> VALUE {
+    unsafe {
+        let path = ruby_path(path);
+        let Some(stat) = kompo_stat(&path) else {
+            let bytes = path.as_os_str().as_bytes();
+            let message = rb_str_new(bytes.as_ptr() as *const _, bytes.len() as _);
+            // Raising never returns, so nothing would drop it
+            drop(path);
+            rb_syserr_fail_str(libc::ENOENT, message);
+        };
+
+        let hash = rb_hash_new();
+        let set = |key: &CStr, value: VALUE| {
+            rb_hash_aset(hash, rb_id2sym(rb_intern(key.as_ptr())), value);
+        };
+        set(c"size", rb_int2inum(stat.st_size as isize));
+        set(c"mode", rb_int2inum(stat.st_mode as isize));
+        set(c"ino", rb_ull2inum(stat.st_ino as _));
+        set(
+            c"directory?",
+            ruby_bool(stat.st_mode & libc::S_IFMT == libc::S_IFDIR),
+        );
+
+        hash
+    }
+}
+
+// `Kompo.which(feature)`: the embedded file `require feature` would load from
+// `$LOAD_PATH`, or nil.
+unsafe extern "C" fn which_func(_: VALUE, feature: VALUE) -> VALUE {
+    unsafe {
+        // Everything that may raise comes first, so that no Rust value is live when
+        // it unwinds through this frame
+        let mut feature = feature;
+        let feature = CStr::from_ptr(rb_string_value_cstr(&mut feature));
+        let load_path = rb_gv_get(c"$LOAD_PATH".as_ptr());
+        let len = rb_num2long(rb_funcall(load_path, rb_intern(c"length".as_ptr()), 0));
+        // Entries that are not Strings and have no `to_str`, such as Pathnames, are
+        // skipped rather than converted with `to_path`
+        let strings = rb_ary_new();
+        let mut strings_len = 0;
+        for i in 0..len {
+            let entry = rb_check_string_type(rb_ary_entry(load_path, i));
+            if entry != Ruby::NIL as VALUE {
+                rb_ary_push(strings, entry);
+                strings_len += 1;
+            }
+        }
+
+        let load_paths = (0..strings_len)
+            .map(|i| rb_ary_entry(strings, i))
+            .map(|entry| PathBuf::from(std::ffi::OsStr::from_bytes(ruby_string_bytes(entry))))
+            .collect::<Vec<_>>();
+
+        match kompo_which(&feature.to_string_lossy(), &load_paths) {
+            Some(path) => {
+                let path = path.as_os_str().as_bytes();
+                rb_str_new(path.as_ptr() as *const _, path.len() as _)
+            }
+            None => Ruby::NIL as VALUE,
+        }
+    }
+}
+
+// `Kompo.open_fds`: `[fd, path]` for every file and directory open in the VFS.
+unsafe extern "C" fn open_fds_func(_: VALUE, _: VALUE) -> VALUE {
+    unsafe {
+        let fds = rb_ary_new();
+        for (fd, path) in kompo_open_fds() {
+            let path = path.as_os_str().as_bytes();
+            let pair = rb_ary_new();
+            rb_ary_push(pair, rb_int2inum(fd as isize));
+            rb_ary_push(pair, rb_str_new(path.as_ptr() as *const _, path.len() as _));
+            rb_ary_push(fds, pair);
+        }
+
+        fds
+    }
 }
 
 /// # Safety
@@ -226,6 +497,20 @@ pub unsafe extern "C-unwind" fn Init_kompo_fs() {
         let class = rb_define_class(c_name.as_ptr(), rb_cObject);
         rb_define_singleton_method(class, context.as_ptr(), context_func, 0);
         rb_define_singleton_method(class, is_context.as_ptr(), is_context_func, 0);
+
+        let exist = CString::new("exist?").unwrap();
+        let directory = CString::new("directory?").unwrap();
+        rb_define_singleton_method(class, exist.as_ptr(), exist_func, 1);
+        rb_define_singleton_method(class, directory.as_ptr(), directory_func, 1);
+
+        let stat = CString::new("stat").unwrap();
+        rb_define_singleton_method(class, stat.as_ptr(), stat_func, 1);
+
+        let which = CString::new("which").unwrap();
+        rb_define_singleton_method(class, which.as_ptr(), which_func, 1);
+
+        let open_fds = CString::new("open_fds").unwrap();
+        rb_define_singleton_method(class, open_fds.as_ptr(), open_fds_func, 0);
     }
 }
 
@@ -241,8 +526,316 @@ pub unsafe extern "C" fn kompo_fs_set_entrypoint_dir(entrypoint_path: *const lib
     let path = Path::new(path_cstr.to_str().expect("invalid entrypoint path"));
 
     if let Some(parent) = path.parent() {
-        let parent_os_str = parent.as_os_str().to_os_string();
-        *WORKING_DIR.write().unwrap() = Some(parent_os_str);
+        set_working_dir(Some(parent));
+    }
+}
+
+/// Forgets the VFS working directory and every cached `stat`, as before the first
+/// `chdir` into the VFS. The trie itself is kept: it is built from the embedded
+/// data, which never changes, and open fds still refer to it.
+pub fn reset_state() {
+    set_working_dir(None);
+    FILE_TYPE_CACHE.clear();
+}
+
+/// Returns the total size in bytes of all files embedded in the VFS.
+#[unsafe(no_mangle)]
+pub extern "C" fn kompo_fs_total_size() -> u64 {
+    TRIE.get_or_init(initialize_trie).total_size()
+}
+
+/// Returns 1 if the VFS has been loaded, 0 otherwise.
+#[unsafe(no_mangle)]
+pub extern "C" fn kompo_fs_is_initialized() -> libc::c_int {
+    is_initialized() as libc::c_int
+}
+
+/// Returns the number of files and directories currently open in the VFS.
+#[unsafe(no_mangle)]
+pub extern "C" fn kompo_fs_open_fd_count() -> libc::c_int {
+    initialized_trie().map_or(0, |trie| trie.open_fd_count() as libc::c_int)
+}
+
+/// Returns 1 if `fd` is a file or directory open in the VFS, 0 if it is a real
+/// descriptor (or not open at all), so C code can avoid kernel-specific operations on
+/// it.
+#[unsafe(no_mangle)]
+pub extern "C" fn kompo_fs_is_virtual_fd(fd: libc::c_int) -> libc::c_int {
+    util::is_fd_exists_in_kompo(fd) as libc::c_int
+}
+
+/// Returns 1 if `path` names an embedded file or directory, taken relative to the VFS
+/// working directory when relative, and 0 if it is null or left to the host. Lets
+/// hooks defined outside this crate, like `chmod`'s, tell the two apart.
+///
+/// # Safety
+/// `path` must be null or a valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kompo_fs_is_vfs_path(path: *const libc::c_char) -> libc::c_int {
+    if path.is_null() {
+        return 0;
+    }
+
+    let is_kompo_path = unsafe { util::is_under_kompo_working_dir(path) }
+        || (has_working_dir() && unsafe { util::is_relative_path(path) });
+    (is_kompo_path && glue::exists_in_kompo(path)) as libc::c_int
+}
+
+/// Asks the kernel to read in the embedded contents of the `count` files in `paths`
+/// ahead of a burst of `require`s. Relative paths are taken relative to the VFS
+/// working directory, and paths that are not embedded files are skipped. Returns 0,
+/// or -1 with errno set to `EFAULT` if `paths` or one of its entries is null.
+///
+/// # Safety
+/// `paths` must be null or point to `count` pointers, each null or a valid
+/// NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kompo_fs_prefetch(
+    paths: *const *const libc::c_char,
+    count: libc::size_t,
+) -> libc::c_int {
+    if paths.is_null() {
+        errno::set_errno(errno::Errno(libc::EFAULT));
+        return -1;
+    }
+
+    let paths = unsafe { std::slice::from_raw_parts(paths, count) };
+    if paths.iter().any(|path| path.is_null()) {
+        errno::set_errno(errno::Errno(libc::EFAULT));
+        return -1;
+    }
+
+    let resolved = paths
+        .iter()
+        .map(|path| {
+            let path = unsafe { CStr::from_ptr(*path) };
+            util::resolve_kompo_path(Path::new(std::ffi::OsStr::from_bytes(path.to_bytes())))
+        })
+        .collect::<Vec<_>>();
+    let components = resolved
+        .iter()
+        .map(|path| path.iter().collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let components = components.iter().map(Vec::as_slice).collect::<Vec<_>>();
+
+    TRIE.get_or_init(initialize_trie).prefetch(&components);
+
+    0
+}
+
+/// Copies the VFS working directory into `buf` as a NUL-terminated string.
+/// Returns 0 on success. Otherwise returns -1 and sets errno to `ENOENT` when no
+/// working directory is set, or to `ERANGE` when it does not fit in `len` bytes.
+///
+/// # Safety
+/// `buf` must be null or valid for writes of `len` bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kompo_fs_working_dir(
+    buf: *mut libc::c_char,
+    len: libc::size_t,
+) -> libc::c_int {
+    if buf.is_null() {
+        errno::set_errno(errno::Errno(libc::EFAULT));
+        return -1;
+    }
+
+    let Some(working_dir) = current_working_dir() else {
+        errno::set_errno(errno::Errno(libc::ENOENT));
+        return -1;
+    };
+
+    let bytes = working_dir.as_os_str().as_bytes();
+    if bytes.len() >= len {
+        errno::set_errno(errno::Errno(libc::ERANGE));
+        return -1;
+    }
+
+    unsafe {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), buf as *mut u8, bytes.len());
+        *buf.add(bytes.len()) = 0;
+    }
+
+    0
+}
+
+/// Looks up the embedded file at `path`, which is taken relative to the VFS working
+/// directory when it is not absolute, and stores the address of its contents in
+/// `*out` and their length in `*out_len`. The contents are never freed or moved.
+/// Returns 0 on success. Otherwise returns -1 and sets errno to `ENOENT` when
+/// `path` is not embedded, `EISDIR` when it is a directory, `EINVAL` when its
+/// contents are generated and have no fixed address, or `EFAULT` for a null argument.
+///
+/// # Safety
+/// `path` must be null or a valid NUL-terminated string, and `out` and `out_len`
+/// must be null or valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kompo_fs_file_slice(
+    path: *const libc::c_char,
+    out: *mut *const u8,
+    out_len: *mut libc::size_t,
+) -> libc::c_int {
+    if path.is_null() || out.is_null() || out_len.is_null() {
+        errno::set_errno(errno::Errno(libc::EFAULT));
+        return -1;
+    }
+
+    let path = unsafe { CStr::from_ptr(path) };
+    let path = util::resolve_kompo_path(Path::new(std::ffi::OsStr::from_bytes(path.to_bytes())));
+    let path = path.iter().collect::<Vec<_>>();
+    let trie = TRIE.get_or_init(initialize_trie);
+
+    let Some((ptr, len)) = trie.file_slice(&path) else {
+        let errno = if !trie.contains(&path) {
+            libc::ENOENT
+        } else if trie.is_dir_exists_from_path(&path) {
+            libc::EISDIR
+        } else {
+            libc::EINVAL
+        };
+        errno::set_errno(errno::Errno(errno));
+        return -1;
+    };
+
+    unsafe {
+        *out = ptr;
+        *out_len = len;
+    }
+
+    0
+}
+
+/// Lists the names of the children of the VFS directory at `path`, which is taken
+/// relative to the VFS working directory when it is not absolute.
+///
+/// On success, stores in `*out` an array of `*out_len` NUL-terminated names and
+/// returns their count. The array and every name in it are allocated with
+/// `malloc`: the caller frees each name and then the array with `free`. An empty
+/// directory stores a null array. On failure, returns -1 and sets errno to
+/// `ENOENT` or `ENOTDIR` for `path`, `EFAULT` for a null argument, or `ENOMEM`.
+///
+/// # Safety
+/// `path` must be null or a valid NUL-terminated string, and `out` and `out_len`
+/// must be null or valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn kompo_fs_list_dir(
+    path: *const libc::c_char,
+    out: *mut *mut *mut libc::c_char,
+    out_len: *mut libc::size_t,
+) -> libc::c_int {
+    if path.is_null() || out.is_null() || out_len.is_null() {
+        errno::set_errno(errno::Errno(libc::EFAULT));
+        return -1;
+    }
+
+    let path = unsafe { CStr::from_ptr(path) };
+    let path = util::resolve_kompo_path(Path::new(std::ffi::OsStr::from_bytes(path.to_bytes())));
+    let names = match TRIE
+        .get_or_init(initialize_trie)
+        .list_dir(&path.iter().collect())
+    {
+        Ok(names) => names,
+        Err(err) => {
+            errno::set_errno(errno::Errno(err.errno()));
+            return -1;
+        }
+    };
+
+    let mut array: *mut *mut libc::c_char = std::ptr::null_mut();
+    if !names.is_empty() {
+        array = unsafe { libc::malloc(names.len() * std::mem::size_of::<*mut libc::c_char>()) }
+            as *mut *mut libc::c_char;
+        if array.is_null() {
+            errno::set_errno(errno::Errno(libc::ENOMEM));
+            return -1;
+        }
+    }
+
+    for (i, name) in names.iter().enumerate() {
+        let bytes = name.as_bytes();
+        let copy = unsafe { libc::malloc(bytes.len() + 1) } as *mut libc::c_char;
+        if copy.is_null() {
+            unsafe {
+                for j in 0..i {
+                    libc::free(*array.add(j) as *mut libc::c_void);
+                }
+                libc::free(array as *mut libc::c_void);
+            }
+            errno::set_errno(errno::Errno(libc::ENOMEM));
+            return -1;
+        }
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), copy as *mut u8, bytes.len());
+            *copy.add(bytes.len()) = 0;
+            *array.add(i) = copy;
+        }
+    }
+
+    unsafe {
+        *out = array;
+        *out_len = names.len();
+    }
+
+    names.len() as libc::c_int
+}
+
+static STRICT_MODE: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Returns whether paths in the VFS that are not embedded fail with `ENOENT`
+/// rather than ever being passed to the real syscall.
+pub fn is_strict_mode() -> bool {
+    STRICT_MODE.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+pub fn set_strict_mode(strict: bool) {
+    STRICT_MODE.store(strict, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Enables strict mode when `strict` is nonzero, so that a path in the VFS that is
+/// not embedded can't be served by a file planted at the same path on the host.
+#[unsafe(no_mangle)]
+pub extern "C" fn kompo_fs_set_strict_mode(strict: libc::c_int) {
+    set_strict_mode(strict != 0);
+}
+
+/// Callback told the path of every lookup strict mode refuses.
+pub type StrictModeLog = unsafe extern "C" fn(path: *const libc::c_char);
+
+static STRICT_MODE_LOG: std::sync::RwLock<Option<StrictModeLog>> = std::sync::RwLock::new(None);
+
+/// Returns the callback set by [`kompo_fs_set_strict_mode_log`], if any.
+pub fn strict_mode_log() -> Option<StrictModeLog> {
+    *STRICT_MODE_LOG
+        .read()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// Registers `callback` to be called with each path in the VFS that strict mode
+/// refuses to look up on the host, since one may be a planted file being probed
+/// for. `require` probes many missing paths, so nothing is logged unless a callback
+/// is set. Passing null removes the callback.
+#[unsafe(no_mangle)]
+pub extern "C" fn kompo_fs_set_strict_mode_log(callback: Option<StrictModeLog>) {
+    *STRICT_MODE_LOG
+        .write()
+        .unwrap_or_else(std::sync::PoisonError::into_inner) = callback;
+}
+
+/// Registers `callback` to be called with the fd and path of every file opened
+/// from the VFS. Passing null removes the callback.
+#[unsafe(no_mangle)]
+pub extern "C" fn kompo_fs_set_open_hook(
+    callback: Option<unsafe extern "C" fn(fd: i32, path: *const libc::c_char)>,
+) {
+    let trie = TRIE.get_or_init(initialize_trie);
+
+    match callback {
+        Some(callback) => trie.set_open_hook(Box::new(move |fd, path| {
+            let path = path.iter().collect::<std::path::PathBuf>();
+            let path = CString::new(path.as_os_str().as_bytes()).expect("invalid path");
+            unsafe { callback(fd, path.as_ptr()) };
+        })),
+        None => trie.clear_open_hook(),
     }
 }
 
@@ -261,137 +854,1208 @@ mod tests {
         let path = std::path::Path::new("/test/hello.txt");
         let path_vec: Vec<&std::ffi::OsStr> = path.iter().collect();
 
-        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
-        let result = fs.stat(&path_vec, &mut stat_buf);
-        assert!(result.is_some());
-        assert_eq!(stat_buf.st_size, 13); // "Hello, World!" is 13 bytes
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+        let result = fs.stat(&path_vec, &mut stat_buf);
+        assert!(result.is_some());
+        assert_eq!(stat_buf.st_size, 13); // "Hello, World!" is 13 bytes
+    }
+
+    #[test]
+    #[serial]
+    fn test_kompo_exists_and_is_directory() {
+        set_working_dir(None);
+
+        assert!(kompo_exists(Path::new("/test/hello.txt")));
+        assert!(kompo_is_directory(Path::new("/test")));
+        assert!(!kompo_is_directory(Path::new("/test/hello.txt")));
+        assert!(!kompo_exists(Path::new("/test/missing.txt")));
+
+        // Relative paths resolve against the embedded working directory
+        assert!(kompo_exists(Path::new("hello.txt")));
+        assert!(kompo_exists(Path::new("data/small.bin")));
+        assert!(kompo_is_directory(Path::new("data")));
+
+        // and against WORKING_DIR once it is set
+        set_working_dir(Some(Path::new("/test/data")));
+        assert!(kompo_exists(Path::new("small.bin")));
+        assert!(kompo_is_directory(Path::new("..")));
+        assert!(!kompo_exists(Path::new("hello.txt")));
+        set_working_dir(None);
+    }
+
+    #[test]
+    fn test_is_context_func_on_fresh_thread() {
+        let result = std::thread::spawn(|| unsafe { is_context_func(0, 0) })
+            .join()
+            .unwrap();
+
+        assert_eq!(result, Ruby::FALSE as VALUE);
+    }
+
+    #[test]
+    #[serial]
+    fn test_is_context_func_while_another_thread_is_in_context() {
+        let (entered_tx, entered_rx) = std::sync::mpsc::channel();
+        let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+
+        let in_context = std::thread::spawn(move || {
+            set_thread_context(true);
+            entered_tx.send(()).unwrap();
+            done_rx.recv().unwrap();
+            set_thread_context(false);
+        });
+        entered_rx.recv().unwrap();
+
+        let result = std::thread::spawn(|| unsafe { is_context_func(0, 0) })
+            .join()
+            .unwrap();
+        done_tx.send(()).unwrap();
+        in_context.join().unwrap();
+
+        assert_eq!(result, Ruby::FALSE as VALUE);
+    }
+
+    #[test]
+    #[serial]
+    fn test_thread_context_poison_recovery() {
+        let poisoned = std::thread::spawn(|| {
+            let _guard = THREAD_CONTEXT
+                .get_or_init(Default::default)
+                .write()
+                .unwrap();
+            panic!("poison THREAD_CONTEXT");
+        })
+        .join();
+        assert!(poisoned.is_err());
+        assert!(THREAD_CONTEXT.get().unwrap().is_poisoned());
+
+        let (inside, outside) = std::thread::spawn(|| {
+            set_thread_context(true);
+            let inside = unsafe { is_context_func(0, 0) };
+            set_thread_context(false);
+            (inside, unsafe { is_context_func(0, 0) })
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(inside, Ruby::TRUE as VALUE);
+        assert_eq!(outside, Ruby::FALSE as VALUE);
+    }
+
+    #[test]
+    #[serial]
+    fn test_thread_context_bounded_by_live_threads() {
+        let context = THREAD_CONTEXT.get_or_init(Default::default);
+        let before = context
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .len();
+
+        for _ in 0..200 {
+            std::thread::spawn(|| {
+                set_thread_context(true);
+                set_thread_context(false);
+            })
+            .join()
+            .unwrap();
+        }
+
+        let after = context
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .len();
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    #[serial]
+    fn test_reset_state() {
+        let path = CString::new("/test/hello.txt").unwrap();
+        let cache_key = ["/", "test", "hello.txt"].map(std::ffi::OsString::from);
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+
+        // What one test leaves behind...
+        set_working_dir(Some(Path::new("/test")));
+        assert_eq!(glue::stat_from_fs(path.as_ptr(), &mut stat_buf), 0);
+        assert!(FILE_TYPE_CACHE.get(&cache_key).is_some());
+
+        reset_state();
+
+        // ...is gone for the next one
+        assert!(!has_working_dir());
+        assert!(FILE_TYPE_CACHE.get(&cache_key).is_none());
+        assert_eq!(glue::stat_from_fs(path.as_ptr(), &mut stat_buf), 0);
+        assert_eq!(stat_buf.st_size, 13);
+    }
+
+    #[test]
+    #[serial]
+    fn test_stat_cache_hit_matches_uncached() {
+        fn bytes(stat: &libc::stat) -> &[u8] {
+            unsafe {
+                std::slice::from_raw_parts(
+                    stat as *const libc::stat as *const u8,
+                    std::mem::size_of::<libc::stat>(),
+                )
+            }
+        }
+
+        let trie = TRIE.get_or_init(initialize_trie);
+        for path in ["/test/hello.txt", "/test/data", "/test/data/large.bin"] {
+            let components = Path::new(path).iter().collect::<Vec<_>>();
+            let mut uncached: libc::stat = unsafe { std::mem::zeroed() };
+            assert!(trie.stat(&components, &mut uncached).is_some());
+
+            // The first call fills the cache if no other test did, the second hits it
+            let path = CString::new(path).unwrap();
+            let mut cached: libc::stat = unsafe { std::mem::zeroed() };
+            for _ in 0..2 {
+                assert_eq!(glue::stat_from_fs(path.as_ptr(), &mut cached), 0);
+            }
+            let cache_key = components
+                .iter()
+                .map(|s| s.to_os_string())
+                .collect::<Vec<_>>();
+            assert!(FILE_TYPE_CACHE.get(&cache_key).is_some());
+
+            assert_eq!(bytes(&cached), bytes(&uncached), "{path:?}");
+        }
+    }
+
+    #[test]
+    fn test_storage_abi_version() {
+        assert_eq!(
+            unsafe { kompo_storage_abi_version() },
+            EXPECTED_STORAGE_ABI_VERSION
+        );
+        assert_eq!(
+            kompo_storage::KOMPO_STORAGE_ABI_VERSION,
+            EXPECTED_STORAGE_ABI_VERSION
+        );
+        check_storage_abi_version();
+    }
+
+    #[test]
+    fn test_kompo_fs_total_size() {
+        // hello.txt, world.txt, data/small.bin, data/large.bin and lib/greeting.rb
+        assert_eq!(kompo_fs_total_size(), 13 + 12 + 100 + 4 * 16384 + 100 + 9);
+    }
+
+    #[test]
+    #[serial]
+    fn test_kompo_fs_set_open_hook() {
+        static OPENED: std::sync::Mutex<Vec<(i32, String)>> = std::sync::Mutex::new(Vec::new());
+
+        unsafe extern "C" fn record(fd: i32, path: *const libc::c_char) {
+            let path = unsafe { CStr::from_ptr(path) }
+                .to_str()
+                .unwrap()
+                .to_string();
+            OPENED.lock().unwrap().push((fd, path));
+        }
+
+        kompo_fs_set_open_hook(Some(record));
+        let path = CString::new("/test/hello.txt").unwrap();
+        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY, 0);
+        kompo_fs_set_open_hook(None);
+
+        assert!(fd >= 0);
+        assert!(
+            OPENED
+                .lock()
+                .unwrap()
+                .contains(&(fd, "/test/hello.txt".to_string()))
+        );
+        glue::close_from_fs(fd);
+    }
+
+    #[test]
+    fn test_kompo_fs_is_initialized() {
+        TRIE.get_or_init(initialize_trie);
+
+        assert_eq!(kompo_fs_is_initialized(), 1);
+    }
+
+    #[test]
+    fn test_kompo_fs_open_fd_count() {
+        let path = CString::new("/test/hello.txt").unwrap();
+        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY, 0);
+        assert!(fd >= 0);
+
+        // Other tests may have fds open at the same time
+        assert!(kompo_fs_open_fd_count() >= 1);
+
+        glue::close_from_fs(fd);
+    }
+
+    #[test]
+    #[serial]
+    fn test_kompo_stat() {
+        reset_state();
+
+        let stat = kompo_stat(Path::new("/test/hello.txt")).unwrap();
+        assert_eq!(stat.st_size, 13);
+        assert_eq!(stat.st_mode & libc::S_IFMT, libc::S_IFREG);
+
+        set_working_dir(Some(Path::new("/test")));
+        let stat = kompo_stat(Path::new("data")).unwrap();
+        assert_eq!(stat.st_mode & libc::S_IFMT, libc::S_IFDIR);
+        assert!(kompo_stat(Path::new("missing.txt")).is_none());
+        reset_state();
+    }
+
+    #[test]
+    #[serial]
+    fn test_kompo_which() {
+        reset_state();
+        let load_paths = [PathBuf::from("/test"), PathBuf::from("/test/lib")];
+
+        assert_eq!(
+            kompo_which("greeting", &load_paths),
+            Some(PathBuf::from("/test/lib/greeting.rb"))
+        );
+        assert_eq!(kompo_which("hello", &load_paths), None);
+
+        // Relative load paths are in the VFS working directory
+        set_working_dir(Some(Path::new("/test")));
+        assert_eq!(
+            kompo_which("greeting.rb", &[PathBuf::from("lib")]),
+            Some(PathBuf::from("/test/lib/greeting.rb"))
+        );
+        reset_state();
+    }
+
+    #[test]
+    fn test_kompo_open_fds() {
+        let hello = CString::new("/test/hello.txt").unwrap();
+        let world = CString::new("/test/world.txt").unwrap();
+        let hello_fd = glue::open_from_fs(hello.as_ptr(), libc::O_RDONLY, 0);
+        let world_fd = glue::open_from_fs(world.as_ptr(), libc::O_RDONLY, 0);
+        assert!(hello_fd >= 0 && world_fd >= 0);
+
+        // Other tests may have fds open at the same time
+        let fds = kompo_open_fds();
+        assert!(fds.contains(&(hello_fd, PathBuf::from("/test/hello.txt"))));
+        assert!(fds.contains(&(world_fd, PathBuf::from("/test/world.txt"))));
+
+        glue::close_from_fs(hello_fd);
+        glue::close_from_fs(world_fd);
+    }
+
+    #[test]
+    fn test_kompo_fs_is_virtual_fd() {
+        use std::os::fd::AsRawFd;
+
+        let path = CString::new("/test/hello.txt").unwrap();
+        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY, 0);
+        assert!(fd >= 0);
+        assert_eq!(kompo_fs_is_virtual_fd(fd), 1);
+
+        let real = std::fs::File::open("/dev/null").unwrap();
+        assert_eq!(kompo_fs_is_virtual_fd(real.as_raw_fd()), 0);
+
+        glue::close_from_fs(fd);
+    }
+
+    #[test]
+    #[serial]
+    fn test_kompo_fs_working_dir() {
+        let mut buf = [0 as libc::c_char; 16];
+
+        reset_state();
+        let unset = unsafe { kompo_fs_working_dir(buf.as_mut_ptr(), buf.len()) };
+        let unset_errno = errno::errno().0;
+
+        set_working_dir(Some(Path::new("/test")));
+        let result = unsafe { kompo_fs_working_dir(buf.as_mut_ptr(), buf.len()) };
+        let too_small = unsafe { kompo_fs_working_dir(buf.as_mut_ptr(), 5) };
+        let too_small_errno = errno::errno().0;
+        reset_state();
+
+        assert_eq!(unset, -1);
+        assert_eq!(unset_errno, libc::ENOENT);
+        assert_eq!(result, 0);
+        assert_eq!(
+            unsafe { CStr::from_ptr(buf.as_ptr()) }.to_str().unwrap(),
+            "/test"
+        );
+        assert_eq!(too_small, -1);
+        assert_eq!(too_small_errno, libc::ERANGE);
+    }
+
+    #[test]
+    fn test_kompo_fs_prefetch() {
+        let hello = CString::new("/test/hello.txt").unwrap();
+        let missing = CString::new("/test/missing.txt").unwrap();
+        let paths = [hello.as_ptr(), missing.as_ptr()];
+
+        assert_eq!(unsafe { kompo_fs_prefetch(paths.as_ptr(), paths.len()) }, 0);
+
+        let with_null = [hello.as_ptr(), std::ptr::null()];
+        assert_eq!(unsafe { kompo_fs_prefetch(with_null.as_ptr(), 2) }, -1);
+        assert_eq!(errno::errno().0, libc::EFAULT);
+    }
+
+    #[test]
+    fn test_kompo_fs_list_dir() {
+        let path = CString::new("/test").unwrap();
+        let mut out: *mut *mut libc::c_char = std::ptr::null_mut();
+        let mut out_len: libc::size_t = 0;
+
+        let count = unsafe { kompo_fs_list_dir(path.as_ptr(), &mut out, &mut out_len) };
+        assert_eq!(count as usize, out_len);
+
+        let names = (0..out_len)
+            .map(|i| unsafe {
+                let name = *out.add(i);
+                let owned = CStr::from_ptr(name).to_str().unwrap().to_string();
+                libc::free(name as *mut libc::c_void);
+                owned
+            })
+            .collect::<Vec<_>>();
+        unsafe { libc::free(out as *mut libc::c_void) };
+
+        assert!(names.contains(&"hello.txt".to_string()));
+        assert!(names.contains(&"world.txt".to_string()));
+
+        let file = CString::new("/test/hello.txt").unwrap();
+        let result = unsafe { kompo_fs_list_dir(file.as_ptr(), &mut out, &mut out_len) };
+        assert_eq!(result, -1);
+        assert_eq!(errno::errno().0, libc::ENOTDIR);
+    }
+
+    #[test]
+    fn test_kompo_fs_file_slice() {
+        let path = CString::new("/test/hello.txt").unwrap();
+        let mut out: *const u8 = std::ptr::null();
+        let mut out_len: libc::size_t = 0;
+
+        assert_eq!(
+            unsafe { kompo_fs_file_slice(path.as_ptr(), &mut out, &mut out_len) },
+            0
+        );
+        assert_eq!(out_len, 13);
+        let contents = unsafe { std::slice::from_raw_parts(out, out_len) };
+        assert_eq!(contents, b"Hello, World!");
+
+        for (path, errno) in [("/test", libc::EISDIR), ("/test/missing.txt", libc::ENOENT)] {
+            let path = CString::new(path).unwrap();
+            let result = unsafe { kompo_fs_file_slice(path.as_ptr(), &mut out, &mut out_len) };
+            assert_eq!(result, -1);
+            assert_eq!(errno::errno().0, errno);
+        }
+
+        let result =
+            unsafe { kompo_fs_file_slice(path.as_ptr(), std::ptr::null_mut(), &mut out_len) };
+        assert_eq!(result, -1);
+        assert_eq!(errno::errno().0, libc::EFAULT);
+    }
+
+    #[test]
+    fn test_stat_from_fs_existing_file() {
+        let path = CString::new("/test/hello.txt").unwrap();
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+
+        let result = glue::stat_from_fs(path.as_ptr(), &mut stat_buf);
+        assert_eq!(result, 0);
+        assert_eq!(stat_buf.st_size, 13);
+    }
+
+    #[test]
+    fn test_stat_from_fs_nonexistent_file() {
+        let path = CString::new("/test/nonexistent.txt").unwrap();
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+
+        let result = glue::stat_from_fs(path.as_ptr(), &mut stat_buf);
+        assert_eq!(result, -1);
+        assert_eq!(errno::errno().0, libc::ENOENT);
+    }
+
+    #[test]
+    fn test_stat_from_fs_null_stat() {
+        let path = CString::new("/test/hello.txt").unwrap();
+
+        let result = glue::stat_from_fs(path.as_ptr(), std::ptr::null_mut());
+        assert_eq!(result, -1);
+        assert_eq!(errno::errno().0, libc::EFAULT);
+    }
+
+    #[test]
+    fn test_stat_from_fs_null_path() {
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+
+        let result = glue::stat_from_fs(std::ptr::null(), &mut stat_buf);
+        assert_eq!(result, -1);
+        assert_eq!(errno::errno().0, libc::EFAULT);
+    }
+
+    #[test]
+    #[serial]
+    fn test_fstatat_from_fs_relative_without_working_dir() {
+        set_working_dir(None);
+        // Relative to the host cwd, which cargo sets to this crate's directory
+        let path = CString::new("Cargo.toml").unwrap();
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+
+        let result =
+            unsafe { glue::fstatat_from_fs(libc::AT_FDCWD, path.as_ptr(), &mut stat_buf, 0) };
+        assert_eq!(result, 0);
+        assert_eq!(stat_buf.st_mode & libc::S_IFMT, libc::S_IFREG);
+    }
+
+    #[test]
+    #[serial]
+    fn test_open_exclusive_create_existing() {
+        let flags = libc::O_CREAT | libc::O_EXCL | libc::O_WRONLY;
+        let path = CString::new("/test/hello.txt").unwrap();
+
+        assert_eq!(glue::open_from_fs(path.as_ptr(), flags, 0o644), -1);
+        assert_eq!(errno::errno().0, libc::EEXIST);
+
+        let fd = unsafe { glue::openat_from_fs(libc::AT_FDCWD, path.as_ptr(), flags, 0o644) };
+        assert_eq!(fd, -1);
+        assert_eq!(errno::errno().0, libc::EEXIST);
+
+        // Also when the path is relative to the VFS working directory
+        set_working_dir(Some(Path::new("/test")));
+        let relative = CString::new("hello.txt").unwrap();
+        assert_eq!(glue::open_from_fs(relative.as_ptr(), flags, 0o644), -1);
+        assert_eq!(errno::errno().0, libc::EEXIST);
+        let fd = unsafe { glue::openat_from_fs(libc::AT_FDCWD, relative.as_ptr(), flags, 0o644) };
+        assert_eq!(fd, -1);
+        assert_eq!(errno::errno().0, libc::EEXIST);
+        reset_state();
+    }
+
+    #[test]
+    #[serial]
+    fn test_open_create_vfs_path() {
+        let existing = CString::new("/test/hello.txt").unwrap();
+        let missing = CString::new("/test/new.txt").unwrap();
+
+        let openat = |path: &CString, flags| unsafe {
+            glue::openat_from_fs(libc::AT_FDCWD, path.as_ptr(), flags, 0o644)
+        };
+
+        for flags in [
+            libc::O_CREAT | libc::O_WRONLY,
+            libc::O_CREAT | libc::O_RDONLY,
+        ] {
+            assert_eq!(glue::open_from_fs(existing.as_ptr(), flags, 0o644), -1);
+            assert_eq!(errno::errno().0, libc::EEXIST);
+            assert_eq!(glue::open_from_fs(missing.as_ptr(), flags, 0o644), -1);
+            assert_eq!(errno::errno().0, libc::EROFS);
+
+            assert_eq!(openat(&existing, flags), -1);
+            assert_eq!(errno::errno().0, libc::EEXIST);
+            assert_eq!(openat(&missing, flags), -1);
+            assert_eq!(errno::errno().0, libc::EROFS);
+        }
+
+        // Also when the path is relative to the VFS working directory
+        set_working_dir(Some(Path::new("/test")));
+        let flags = libc::O_CREAT | libc::O_WRONLY | libc::O_TRUNC;
+        let relative = CString::new("hello.txt").unwrap();
+        assert_eq!(glue::open_from_fs(relative.as_ptr(), flags, 0o644), -1);
+        assert_eq!(errno::errno().0, libc::EEXIST);
+        assert_eq!(openat(&relative, flags), -1);
+        assert_eq!(errno::errno().0, libc::EEXIST);
+        let relative = CString::new("data/new.bin").unwrap();
+        assert_eq!(glue::open_from_fs(relative.as_ptr(), flags, 0o644), -1);
+        assert_eq!(errno::errno().0, libc::EROFS);
+        assert_eq!(openat(&relative, flags), -1);
+        assert_eq!(errno::errno().0, libc::EROFS);
+        reset_state();
+
+        // Without O_CREAT the mode is ignored as before
+        let fd = glue::open_from_fs(existing.as_ptr(), libc::O_RDONLY, 0o644);
+        assert!(fd >= 0);
+        assert_eq!(glue::close_from_fs(fd), 0);
+    }
+
+    #[test]
+    fn test_strict_mode_does_not_serve_host_files() {
+        // Changes the real working directory, which other tests resolve paths against
+        if !in_own_process("test_strict_mode_does_not_serve_host_files") {
+            return;
+        }
+
+        // A file planted in the real working directory, while the program believes
+        // it is in /test of the VFS
+        let host_dir = std::env::temp_dir().join(format!("kompo_strict_{}", std::process::id()));
+        std::fs::create_dir_all(&host_dir).unwrap();
+        std::fs::write(host_dir.join("planted.txt"), b"planted").unwrap();
+        std::env::set_current_dir(&host_dir).unwrap();
+        set_working_dir(Some(Path::new("/test")));
+
+        // Refused as read-only even without strict mode...
+        let planted = CString::new("planted.txt").unwrap();
+        let flags = libc::O_RDONLY | libc::O_CREAT;
+        let fd = unsafe { glue::openat_from_fs(libc::AT_FDCWD, planted.as_ptr(), flags, 0o644) };
+        assert_eq!(fd, -1);
+        assert_eq!(errno::errno().0, libc::EROFS);
+
+        // ...and reported missing, with the attempt logged, in it
+        static LOGGED: std::sync::Mutex<Vec<CString>> = std::sync::Mutex::new(Vec::new());
+        unsafe extern "C" fn log(path: *const libc::c_char) {
+            LOGGED
+                .lock()
+                .unwrap()
+                .push(unsafe { CStr::from_ptr(path) }.into());
+        }
+        kompo_fs_set_strict_mode_log(Some(log));
+        set_strict_mode(true);
+        let fd = unsafe { glue::openat_from_fs(libc::AT_FDCWD, planted.as_ptr(), flags, 0o644) };
+        assert_eq!(fd, -1);
+        assert_eq!(errno::errno().0, libc::ENOENT);
+        let absolute = CString::new("/test/planted.txt").unwrap();
+        let fd = unsafe { glue::openat_from_fs(libc::AT_FDCWD, absolute.as_ptr(), flags, 0o644) };
+        assert_eq!(fd, -1);
+        assert_eq!(errno::errno().0, libc::ENOENT);
+        assert_eq!(*LOGGED.lock().unwrap(), [planted.clone(), absolute]);
+        kompo_fs_set_strict_mode_log(None);
+
+        // Embedded files are resolved in the VFS, which can't create them
+        let hello = CString::new("hello.txt").unwrap();
+        let fd = unsafe { glue::openat_from_fs(libc::AT_FDCWD, hello.as_ptr(), flags, 0o644) };
+        assert_eq!(fd, -1);
+        assert_eq!(errno::errno().0, libc::EEXIST);
+        let fd = unsafe { glue::openat_from_fs(libc::AT_FDCWD, hello.as_ptr(), libc::O_RDONLY, 0) };
+        assert!(util::is_fd_exists_in_kompo(fd));
+        assert_eq!(glue::close_from_fs(fd), 0);
+
+        set_strict_mode(false);
+        reset_state();
+        std::fs::remove_dir_all(&host_dir).unwrap();
+    }
+
+    #[test]
+    fn test_fstatat_from_fs_vfs_dirfd() {
+        let dir = CString::new("/test").unwrap();
+        let dirfd = glue::open_from_fs(dir.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY, 0);
+        assert!(dirfd >= 0);
+
+        let path = CString::new("hello.txt").unwrap();
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+        let result = unsafe { glue::fstatat_from_fs(dirfd, path.as_ptr(), &mut stat_buf, 0) };
+        assert_eq!(result, 0);
+        assert_eq!(stat_buf.st_size, 13);
+
+        let nested = CString::new("data/small.bin").unwrap();
+        let result = unsafe { glue::fstatat_from_fs(dirfd, nested.as_ptr(), &mut stat_buf, 0) };
+        assert_eq!(result, 0);
+        assert_eq!(stat_buf.st_size, 100);
+
+        // A VFS file is not a directory to resolve against
+        let file = CString::new("/test/hello.txt").unwrap();
+        let fd = glue::open_from_fs(file.as_ptr(), libc::O_RDONLY, 0);
+        let result = unsafe { glue::fstatat_from_fs(fd, path.as_ptr(), &mut stat_buf, 0) };
+        assert_eq!(result, -1);
+        assert_eq!(errno::errno().0, libc::ENOTDIR);
+
+        assert_eq!(glue::close_from_fs(fd), 0);
+        assert_eq!(glue::close_from_fs(dirfd), 0);
+    }
+
+    #[test]
+    fn test_open_from_fs_null_path() {
+        let result = glue::open_from_fs(std::ptr::null(), libc::O_RDONLY, 0);
+        assert_eq!(result, -1);
+        assert_eq!(errno::errno().0, libc::EFAULT);
+    }
+
+    #[test]
+    #[serial]
+    fn test_open_from_fs_empty_path() {
+        let path = CString::new("").unwrap();
+
+        set_working_dir(Some(Path::new("/test")));
+        let result = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY, 0);
+        let err = errno::errno().0;
+        reset_state();
+
+        assert_eq!(result, -1);
+        assert_eq!(err, libc::ENOENT);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_fstatat_from_fs_empty_path_on_vfs_fd() {
+        let dir = CString::new("/test").unwrap();
+        let empty = CString::new("").unwrap();
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+
+        let dirfd = glue::open_from_fs(dir.as_ptr(), libc::O_RDONLY, 0);
+        assert!(dirfd >= 0);
+
+        let result = unsafe {
+            glue::fstatat_from_fs(dirfd, empty.as_ptr(), &mut stat_buf, libc::AT_EMPTY_PATH)
+        };
+        assert_eq!(result, 0);
+        assert_eq!(stat_buf.st_mode & libc::S_IFMT, libc::S_IFDIR);
+
+        assert_eq!(glue::close_from_fs(dirfd), 0);
+    }
+
+    #[test]
+    #[serial]
+    fn test_open_from_fs_dev_null_with_working_dir() {
+        let absolute = CString::new("/dev/null").unwrap();
+        let relative = CString::new("../dev/null").unwrap();
+
+        set_working_dir(Some(Path::new("/test")));
+        let fds = [
+            glue::open_from_fs(absolute.as_ptr(), libc::O_WRONLY, 0),
+            glue::open_from_fs(relative.as_ptr(), libc::O_WRONLY, 0),
+        ];
+        reset_state();
+
+        for fd in fds {
+            assert!(fd >= 0);
+            assert!(!util::is_fd_exists_in_kompo(fd));
+            assert_eq!(unsafe { libc::close(fd) }, 0);
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_stat_from_fs_relative_path_leaving_vfs() {
+        let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+        let relative = Path::new("..").join(manifest.strip_prefix("/").unwrap());
+        let relative = CString::new(relative.as_os_str().as_bytes()).unwrap();
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+
+        set_working_dir(Some(Path::new("/test")));
+        let result = glue::stat_from_fs(relative.as_ptr(), &mut stat_buf);
+        reset_state();
+
+        assert_eq!(result, 0);
+        assert_eq!(
+            stat_buf.st_size as u64,
+            std::fs::metadata(&manifest).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_lstat_from_fs_existing_file() {
+        let path = CString::new("/test/world.txt").unwrap();
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+
+        let result = glue::lstat_from_fs(path.as_ptr(), &mut stat_buf);
+        assert_eq!(result, 0);
+        assert_eq!(stat_buf.st_size, 12); // "Test Content" is 12 bytes
+    }
+
+    // Runs the test `name` again in a fresh copy of the test binary running only it,
+    // for tests that need the process to themselves. Returns true in that copy,
+    // which goes on with the test, and false in the original once the copy passed.
+    fn in_own_process(name: &str) -> bool {
+        const CHILD_ENV: &str = "KOMPO_FS_OWN_PROCESS_TEST";
+
+        if std::env::var_os(CHILD_ENV).is_some() {
+            return true;
+        }
+
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", &format!("tests::{name}"), "--test-threads=1"])
+            .env(CHILD_ENV, "1")
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+        false
+    }
+
+    #[test]
+    fn test_uninitialized_vfs() {
+        // Other tests build the VFS as soon as they run
+        if !in_own_process("test_uninitialized_vfs") {
+            return;
+        }
+
+        assert!(!is_initialized());
+        assert_eq!(kompo_fs_is_initialized(), 0);
+        assert!(!util::is_fd_exists_in_kompo(0));
+        assert_eq!(kompo_fs_open_fd_count(), 0);
+        assert!(kompo_open_fds().is_empty());
+        // Hooks on fds the VFS never opened go to the real syscall
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+        assert_eq!(glue::fstat_from_fs(0, &mut stat_buf), 0);
+        // None of the above built it
+        assert!(!is_initialized());
+
+        let path = CString::new("/test/hello.txt").unwrap();
+        assert_eq!(glue::stat_from_fs(path.as_ptr(), &mut stat_buf), 0);
+        assert!(is_initialized());
+    }
+
+    // Runs stat and lstat of a cached path in a forked child under seccomp strict
+    // mode, where any syscall but read, write and exit kills the child. Forking
+    // while other tests hold the VFS locks could leave the child waiting on them,
+    // so the check runs in a process of its own.
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_stat_cached_path_is_syscall_free() {
+        // Exit status of a child that could not enter strict mode
+        const SECCOMP_UNAVAILABLE: i32 = 2;
+
+        if !in_own_process("test_stat_cached_path_is_syscall_free") {
+            return;
+        }
+
+        let path = CString::new("/test/hello.txt").unwrap();
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+        assert_eq!(glue::stat_from_fs(path.as_ptr(), &mut stat_buf), 0);
+        assert_eq!(glue::lstat_from_fs(path.as_ptr(), &mut stat_buf), 0);
+
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            if unsafe { libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_STRICT) } != 0 {
+                unsafe { libc::_exit(SECCOMP_UNAVAILABLE) };
+            }
+            let is_found = glue::stat_from_fs(path.as_ptr(), &mut stat_buf) == 0
+                && glue::lstat_from_fs(path.as_ptr(), &mut stat_buf) == 0
+                && stat_buf.st_size == 13;
+            // exit_group, which libc::_exit uses, is not allowed in strict mode
+            unsafe { libc::syscall(libc::SYS_exit, if is_found { 0 } else { 1 }) };
+            unreachable!();
+        }
+
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(
+            !libc::WIFSIGNALED(status),
+            "stat made a syscall (killed by signal {})",
+            libc::WTERMSIG(status)
+        );
+        // Without seccomp, as in some sandboxes, nothing was checked
+        if libc::WEXITSTATUS(status) == SECCOMP_UNAVAILABLE {
+            eprintln!("skipping test_stat_cached_path_is_syscall_free: seccomp unavailable");
+            return;
+        }
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+
+    #[test]
+    fn test_lstat_from_fs_null_stat() {
+        let path = CString::new("/test/hello.txt").unwrap();
+
+        let result = glue::lstat_from_fs(path.as_ptr(), std::ptr::null_mut());
+        assert_eq!(result, -1);
+        assert_eq!(errno::errno().0, libc::EFAULT);
+    }
+
+    #[test]
+    fn test_open_and_close_from_fs() {
+        let path = CString::new("/test/hello.txt").unwrap();
+
+        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY, 0);
+        assert!(fd >= 0, "open should return non-negative fd");
+
+        let result = glue::close_from_fs(fd);
+        // close returns 0 on success (for real fd) or may vary for virtual fd
+        assert!(result == 0 || result == -1);
+    }
+
+    #[test]
+    fn test_open_nonexistent_file() {
+        let path = CString::new("/test/nonexistent.txt").unwrap();
+
+        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY, 0);
+        assert_eq!(fd, -1);
+        assert_eq!(errno::errno().0, libc::ENOENT);
+    }
+
+    #[test]
+    fn test_open_directory_with_o_directory_flag() {
+        let path = CString::new("/test").unwrap();
+
+        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY, 0);
+        assert!(fd >= 0, "open with O_DIRECTORY on directory should succeed");
+
+        glue::close_from_fs(fd);
+    }
+
+    #[test]
+    fn test_open_file_with_o_directory_flag() {
+        let path = CString::new("/test/hello.txt").unwrap();
+
+        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY, 0);
+        assert_eq!(fd, -1, "open with O_DIRECTORY on file should fail");
+        assert_eq!(errno::errno().0, libc::ENOTDIR);
+    }
+
+    #[test]
+    fn test_open_nonexistent_with_o_directory_flag() {
+        let path = CString::new("/test/nonexistent").unwrap();
+
+        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY, 0);
+        assert_eq!(
+            fd, -1,
+            "open with O_DIRECTORY on nonexistent path should fail"
+        );
+        assert_eq!(errno::errno().0, libc::ENOENT);
+    }
+
+    #[test]
+    fn test_fstat_from_fs() {
+        let path = CString::new("/test/hello.txt").unwrap();
+        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY, 0);
+        assert!(fd >= 0);
+
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+        let result = glue::fstat_from_fs(fd, &mut stat_buf);
+        assert_eq!(result, 0);
+        assert_eq!(stat_buf.st_size, 13);
+
+        glue::close_from_fs(fd);
+    }
+
+    #[test]
+    fn test_statfs_from_fs() {
+        let path = CString::new("/test/hello.txt").unwrap();
+        let mut statfs: libc::statfs = unsafe { std::mem::zeroed() };
+        assert_eq!(glue::statfs_from_fs(path.as_ptr(), &mut statfs), 0);
+        #[cfg(target_os = "linux")]
+        assert_eq!(statfs.f_type as u32, kompo_storage::KOMPO_FS_MAGIC);
+        #[cfg(target_os = "macos")]
+        assert_eq!(
+            unsafe { CStr::from_ptr(statfs.f_fstypename.as_ptr()) }.to_bytes(),
+            kompo_storage::KOMPO_FS_TYPENAME.as_bytes()
+        );
+
+        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY, 0);
+        assert!(fd >= 0);
+        let mut fstatfs: libc::statfs = unsafe { std::mem::zeroed() };
+        assert_eq!(glue::fstatfs_from_fs(fd, &mut fstatfs), 0);
+        assert_eq!(fstatfs.f_blocks, statfs.f_blocks);
+        glue::close_from_fs(fd);
+
+        let missing = CString::new("/test/missing.txt").unwrap();
+        assert_eq!(glue::statfs_from_fs(missing.as_ptr(), &mut statfs), -1);
+        assert_eq!(errno::errno().0, libc::ENOENT);
+
+        let not_utf8 = CString::new(b"/test/\xff.txt".to_vec()).unwrap();
+        assert_eq!(glue::statfs_from_fs(not_utf8.as_ptr(), &mut statfs), -1);
+        assert_eq!(errno::errno().0, libc::ENOENT);
+    }
+
+    #[test]
+    fn test_fstat_from_fs_null_stat() {
+        let path = CString::new("/test/hello.txt").unwrap();
+        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY, 0);
+        assert!(fd >= 0);
+
+        let result = glue::fstat_from_fs(fd, std::ptr::null_mut());
+        assert_eq!(result, -1);
+        assert_eq!(errno::errno().0, libc::EFAULT);
+
+        glue::close_from_fs(fd);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_posix_fadvise_from_fs() {
+        let path = CString::new("/test/hello.txt").unwrap();
+        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY, 0);
+        assert!(fd >= 0);
+
+        let result = glue::posix_fadvise_from_fs(fd, 0, 0, libc::POSIX_FADV_SEQUENTIAL);
+        assert_eq!(result, 0);
+
+        glue::close_from_fs(fd);
+    }
+
+    #[test]
+    fn test_mmap_from_fs_with_offset() {
+        let path = CString::new("/test/data/large.bin").unwrap();
+        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY, 0);
+        assert!(fd >= 0);
+
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mm = glue::mmap_from_fs(
+            std::ptr::null_mut(),
+            page_size,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE,
+            fd,
+            page_size as libc::off_t,
+        );
+        assert_ne!(mm, libc::MAP_FAILED);
+
+        let mapped = unsafe { std::slice::from_raw_parts(mm as *const u8, page_size) };
+        let expected = (page_size..page_size * 2)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        assert_eq!(mapped, &expected[..]);
+
+        unsafe { libc::munmap(mm, page_size) };
+        glue::close_from_fs(fd);
+    }
+
+    #[test]
+    fn test_mmap_from_fs_past_eof() {
+        let path = CString::new("/test/data/small.bin").unwrap();
+        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY, 0);
+        assert!(fd >= 0);
+
+        let length = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize * 2;
+        let mm = glue::mmap_from_fs(
+            std::ptr::null_mut(),
+            length,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE,
+            fd,
+            0,
+        );
+        assert_ne!(mm, libc::MAP_FAILED);
+
+        let mapped = unsafe { std::slice::from_raw_parts(mm as *const u8, length) };
+        assert!(mapped[..100].iter().all(|&b| b == 0xAB));
+        assert!(mapped[100..].iter().all(|&b| b == 0));
+
+        unsafe { libc::munmap(mm, length) };
+        glue::close_from_fs(fd);
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(target_os = "linux")]
+    fn test_openat2_from_fs() {
+        let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+        how.flags = libc::O_RDONLY as u64;
+        let how_ptr = &mut how as *mut libc::open_how as *mut libc::c_void;
+        let size = std::mem::size_of::<libc::open_how>();
+
+        let path = CString::new("/test/hello.txt").unwrap();
+        let fd = unsafe { glue::openat2_from_fs(libc::AT_FDCWD, path.as_ptr(), how_ptr, size) };
+        assert!(fd >= 0);
+        assert!(util::is_fd_exists_in_kompo(fd));
+
+        let mut buf = [0u8; 13];
+        let n = glue::read_from_fs(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        assert_eq!(n, 13);
+        assert_eq!(&buf, b"Hello, World!");
+        assert_eq!(glue::close_from_fs(fd), 0);
+
+        // RESOLVE_BENEATH keeps relative paths inside the start directory
+        set_working_dir(Some(Path::new("/test/data")));
+        unsafe { (*(how_ptr as *mut libc::open_how)).resolve = libc::RESOLVE_BENEATH };
+        let inside = CString::new("small.bin").unwrap();
+        let fd = unsafe { glue::openat2_from_fs(libc::AT_FDCWD, inside.as_ptr(), how_ptr, size) };
+        assert!(fd >= 0);
+        assert_eq!(glue::close_from_fs(fd), 0);
+
+        let outside = CString::new("../hello.txt").unwrap();
+        let fd = unsafe { glue::openat2_from_fs(libc::AT_FDCWD, outside.as_ptr(), how_ptr, size) };
+        assert_eq!(fd, -1);
+        assert_eq!(errno::errno().0, libc::EXDEV);
+        reset_state();
     }
 
     #[test]
-    fn test_stat_from_fs_existing_file() {
+    #[cfg(target_os = "linux")]
+    fn test_openat2_from_fs_larger_how() {
+        // An `open_how` followed by a field of a newer kernel
+        #[repr(C)]
+        struct NewerOpenHow {
+            how: libc::open_how,
+            extra: u64,
+        }
+
+        let mut how: NewerOpenHow = unsafe { std::mem::zeroed() };
+        how.how.flags = libc::O_RDONLY as u64;
+        let how_ptr = &mut how as *mut NewerOpenHow as *mut libc::c_void;
+        let size = std::mem::size_of::<NewerOpenHow>();
         let path = CString::new("/test/hello.txt").unwrap();
-        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
 
-        let result = glue::stat_from_fs(path.as_ptr(), &mut stat_buf);
-        assert_eq!(result, 0);
-        assert_eq!(stat_buf.st_size, 13);
+        let fd = unsafe { glue::openat2_from_fs(libc::AT_FDCWD, path.as_ptr(), how_ptr, size) };
+        assert!(util::is_fd_exists_in_kompo(fd));
+        assert_eq!(glue::close_from_fs(fd), 0);
+
+        unsafe { (*(how_ptr as *mut NewerOpenHow)).extra = 1 };
+        let fd = unsafe { glue::openat2_from_fs(libc::AT_FDCWD, path.as_ptr(), how_ptr, size) };
+        assert_eq!(fd, -1);
+        assert_eq!(errno::errno().0, libc::E2BIG);
     }
 
     #[test]
-    fn test_stat_from_fs_nonexistent_file() {
-        let path = CString::new("/test/nonexistent.txt").unwrap();
-        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+    #[cfg(target_os = "linux")]
+    fn test_sendfile_from_fs() {
+        let path = CString::new("/test/hello.txt").unwrap();
+        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY, 0);
+        assert!(fd >= 0);
 
-        let result = glue::stat_from_fs(path.as_ptr(), &mut stat_buf);
-        assert_eq!(result, -1);
-        assert_eq!(errno::errno().0, libc::ENOENT);
+        let mut pipe_fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(pipe_fds.as_mut_ptr()) }, 0);
+
+        // Explicit offset: the file offset stays put
+        let mut offset: libc::off_t = 7;
+        let sent = glue::sendfile_from_fs(pipe_fds[1], fd, &mut offset, 100);
+        assert_eq!(sent, 6);
+        assert_eq!(offset, 13);
+
+        // Null offset: reads from and advances the file offset
+        let sent = glue::sendfile_from_fs(pipe_fds[1], fd, std::ptr::null_mut(), 5);
+        assert_eq!(sent, 5);
+
+        let mut buf = [0u8; 32];
+        let n = unsafe { libc::read(pipe_fds[0], buf.as_mut_ptr() as *mut _, buf.len()) };
+        assert_eq!(&buf[..n as usize], b"World!Hello");
+
+        let n = glue::read_from_fs(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        assert_eq!(&buf[..n as usize], b", World!");
+
+        unsafe {
+            libc::close(pipe_fds[0]);
+            libc::close(pipe_fds[1]);
+        }
+        glue::close_from_fs(fd);
     }
 
     #[test]
-    fn test_stat_from_fs_null_stat() {
-        let path = CString::new("/test/hello.txt").unwrap();
+    fn test_mkdir_from_fs_existing_dir() {
+        let path = CString::new("/test").unwrap();
 
-        let result = glue::stat_from_fs(path.as_ptr(), std::ptr::null_mut());
-        assert_eq!(result, -1);
-        assert_eq!(errno::errno().0, libc::EFAULT);
+        let result = glue::mkdir_from_fs(path.as_ptr(), 0o755);
+        assert_eq!(result, 0);
     }
 
     #[test]
-    fn test_lstat_from_fs_existing_file() {
-        let path = CString::new("/test/world.txt").unwrap();
-        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+    fn test_mkdir_from_fs_nonexistent() {
+        let path = CString::new("/test/new_dir").unwrap();
 
-        let result = glue::lstat_from_fs(path.as_ptr(), &mut stat_buf);
-        assert_eq!(result, 0);
-        assert_eq!(stat_buf.st_size, 12); // "Test Content" is 12 bytes
+        let result = glue::mkdir_from_fs(path.as_ptr(), 0o755);
+        assert_eq!(result, -1);
+        assert_eq!(errno::errno().0, libc::EROFS);
     }
 
     #[test]
-    fn test_lstat_from_fs_null_stat() {
+    fn test_mkdir_from_fs_existing_file() {
         let path = CString::new("/test/hello.txt").unwrap();
 
-        let result = glue::lstat_from_fs(path.as_ptr(), std::ptr::null_mut());
+        let result = glue::mkdir_from_fs(path.as_ptr(), 0o755);
         assert_eq!(result, -1);
-        assert_eq!(errno::errno().0, libc::EFAULT);
+        assert_eq!(errno::errno().0, libc::EEXIST);
     }
 
     #[test]
-    fn test_open_and_close_from_fs() {
-        let path = CString::new("/test/hello.txt").unwrap();
+    fn test_mkdir_from_fs_outside_vfs() {
+        let dir = std::env::temp_dir().join(format!("kompo_mkdir_test_{}", std::process::id()));
+        let path = CString::new(dir.to_str().unwrap()).unwrap();
 
-        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY, 0);
-        assert!(fd >= 0, "open should return non-negative fd");
+        let result = glue::mkdir_from_fs(path.as_ptr(), 0o755);
+        assert_eq!(result, 0);
+        assert!(dir.is_dir());
 
-        let result = glue::close_from_fs(fd);
-        // close returns 0 on success (for real fd) or may vary for virtual fd
-        assert!(result == 0 || result == -1);
+        std::fs::remove_dir(&dir).unwrap();
     }
 
     #[test]
-    fn test_open_nonexistent_file() {
-        let path = CString::new("/test/nonexistent.txt").unwrap();
+    fn test_chmod_refuses_vfs_paths() {
+        use std::os::unix::fs::PermissionsExt;
 
-        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY, 0);
-        assert_eq!(fd, -1);
-        assert_eq!(errno::errno().0, libc::ENOENT);
+        // The test binary links kompo_wrap, so this is its inline `chmod` hook
+        let path = CString::new("/test/hello.txt").unwrap();
+        assert_eq!(unsafe { libc::chmod(path.as_ptr(), 0o600) }, -1);
+        assert_eq!(errno::errno().0, libc::EROFS);
+
+        let file = std::env::temp_dir().join(format!("kompo_chmod_test_{}", std::process::id()));
+        std::fs::write(&file, b"").unwrap();
+        let path = CString::new(file.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::chmod(path.as_ptr(), 0o600) }, 0);
+        let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
     }
 
     #[test]
-    fn test_open_directory_with_o_directory_flag() {
-        let path = CString::new("/test").unwrap();
+    #[serial]
+    fn test_chdir_from_fs_into_embedded_dir() {
+        reset_state();
+        let path = CString::new("/test/data").unwrap();
 
-        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY, 0);
-        assert!(fd >= 0, "open with O_DIRECTORY on directory should succeed");
+        assert_eq!(glue::chdir_from_fs(path.as_ptr()), 0);
 
-        glue::close_from_fs(fd);
+        let cwd = glue::getcwd_from_fs(std::ptr::null_mut(), 0);
+        assert!(!cwd.is_null());
+        let cwd = unsafe { CString::from_raw(cwd as *mut libc::c_char) };
+        assert_eq!(cwd.to_bytes(), b"/test/data");
+
+        reset_state();
     }
 
     #[test]
-    fn test_open_file_with_o_directory_flag() {
+    #[serial]
+    fn test_chdir_from_fs_into_file() {
+        reset_state();
         let path = CString::new("/test/hello.txt").unwrap();
 
-        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY, 0);
-        assert_eq!(fd, -1, "open with O_DIRECTORY on file should fail");
+        assert_eq!(glue::chdir_from_fs(path.as_ptr()), -1);
         assert_eq!(errno::errno().0, libc::ENOTDIR);
+        assert!(!has_working_dir());
     }
 
     #[test]
-    fn test_open_nonexistent_with_o_directory_flag() {
-        let path = CString::new("/test/nonexistent").unwrap();
+    #[serial]
+    fn test_chdir_from_fs_nonexistent() {
+        reset_state();
+        let path = CString::new("/test/missing").unwrap();
 
-        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY, 0);
-        assert_eq!(
-            fd, -1,
-            "open with O_DIRECTORY on nonexistent path should fail"
-        );
+        assert_eq!(glue::chdir_from_fs(path.as_ptr()), -1);
         assert_eq!(errno::errno().0, libc::ENOENT);
+        assert!(!has_working_dir());
     }
 
     #[test]
-    fn test_fstat_from_fs() {
-        let path = CString::new("/test/hello.txt").unwrap();
-        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY, 0);
-        assert!(fd >= 0);
-
-        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
-        let result = glue::fstat_from_fs(fd, &mut stat_buf);
-        assert_eq!(result, 0);
-        assert_eq!(stat_buf.st_size, 13);
+    #[serial]
+    fn test_realpath_from_fs_null_buffer() {
+        let path = CString::new("data/small.bin").unwrap();
+        set_working_dir(Some(Path::new("/test")));
+
+        for _ in 0..3 {
+            let resolved = unsafe { glue::realpath_from_fs(path.as_ptr(), std::ptr::null_mut()) };
+            assert!(!resolved.is_null());
+            assert_eq!(
+                unsafe { CStr::from_ptr(resolved) }.to_bytes(),
+                b"/test/data/small.bin"
+            );
+            // Allocated with malloc, so free must accept it
+            unsafe { libc::free(resolved as *mut libc::c_void) };
+        }
 
-        glue::close_from_fs(fd);
+        reset_state();
     }
 
     #[test]
-    fn test_fstat_from_fs_null_stat() {
-        let path = CString::new("/test/hello.txt").unwrap();
-        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY, 0);
-        assert!(fd >= 0);
+    #[serial]
+    fn test_realpath_from_fs_too_long() {
+        const SENTINEL: libc::c_char = 0x55;
+        let path = CString::new("hello.txt").unwrap();
+        // Guard bytes past PATH_MAX catch a copy that ignores the buffer size
+        let mut buf = vec![SENTINEL; libc::PATH_MAX as usize + 64];
+
+        set_working_dir(Some(Path::new("/test")));
+        let resolved = unsafe { glue::realpath_from_fs(path.as_ptr(), buf.as_mut_ptr()) };
+        assert_eq!(resolved, buf.as_ptr());
+        assert_eq!(
+            unsafe { CStr::from_ptr(resolved) }.to_bytes(),
+            b"/test/hello.txt"
+        );
 
-        let result = glue::fstat_from_fs(fd, std::ptr::null_mut());
-        assert_eq!(result, -1);
-        assert_eq!(errno::errno().0, libc::EFAULT);
+        let long_dir = format!("/test/{}", "d/".repeat(libc::PATH_MAX as usize / 2));
+        set_working_dir(Some(Path::new(&long_dir)));
+        buf.fill(SENTINEL);
+        let resolved = unsafe { glue::realpath_from_fs(path.as_ptr(), buf.as_mut_ptr()) };
+        assert!(resolved.is_null());
+        assert_eq!(errno::errno().0, libc::ENAMETOOLONG);
+        assert!(buf.iter().all(|b| *b == SENTINEL));
 
-        glue::close_from_fs(fd);
+        reset_state();
     }
 
     #[test]
@@ -463,6 +2127,14 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_opendir_null_path() {
+        let dir = glue::opendir_from_fs(std::ptr::null());
+
+        assert!(dir.is_null());
+        assert_eq!(errno::errno().0, libc::EFAULT);
+    }
+
     #[test]
     fn test_readdir_from_fs() {
         let path = CString::new("/test").unwrap();
@@ -498,6 +2170,189 @@ mod tests {
         glue::closedir_from_fs(dir);
     }
 
+    #[test]
+    fn test_opendir_from_fs_empty_dir() {
+        let path = CString::new("/test/tmp").unwrap();
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+        assert_eq!(glue::stat_from_fs(path.as_ptr(), &mut stat_buf), 0);
+        assert_eq!(stat_buf.st_mode & libc::S_IFMT, libc::S_IFDIR);
+
+        let dir = glue::opendir_from_fs(path.as_ptr());
+        assert!(!dir.is_null());
+
+        let mut entries = Vec::new();
+        loop {
+            let entry = glue::readdir_from_fs(dir);
+            if entry.is_null() {
+                break;
+            }
+            entries.push(unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) }.to_owned());
+        }
+        assert_eq!(entries, [c".", c".."]);
+
+        assert_eq!(glue::closedir_from_fs(dir), 0);
+    }
+
+    #[test]
+    fn test_read_kompo_fd_unknown_fd() {
+        // Stands in for a VFS fd closed after `read_from_fs` checked it. Held open, so
+        // no VFS fd can take its number meanwhile
+        let host = std::fs::File::open("/dev/null").unwrap();
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&host);
+
+        let mut buf = [0u8; 16];
+        let result = glue::read_kompo_fd(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        assert_eq!(result, -1);
+        assert_eq!(errno::errno().0, libc::EBADF);
+    }
+
+    // `IO#sysread` sizes its read from `fstat` and then calls `read(2)` directly,
+    // bypassing Ruby's buffering
+    #[test]
+    fn test_sysread_sequence() {
+        let path = CString::new("/test/data/large.bin").unwrap();
+        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC, 0);
+        assert!(fd >= 0);
+
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+        assert_eq!(glue::fstat_from_fs(fd, &mut stat_buf), 0);
+        assert_eq!(stat_buf.st_mode & libc::S_IFMT, libc::S_IFREG);
+        let size = stat_buf.st_size as usize;
+
+        // A partial sysread, then the rest in one read bounded by st_size
+        let mut buf = vec![0u8; size];
+        let head = glue::read_from_fs(fd, buf.as_mut_ptr() as *mut libc::c_void, 100);
+        assert_eq!(head, 100);
+        let rest = glue::read_from_fs(fd, buf[100..].as_mut_ptr() as *mut libc::c_void, size - 100);
+        assert_eq!(rest as usize, size - 100);
+        assert!(buf.iter().enumerate().all(|(i, b)| *b == (i % 251) as u8));
+
+        // Ruby raises EOFError on the 0 that follows
+        let mut more = [0u8; 16];
+        let eof = glue::read_from_fs(fd, more.as_mut_ptr() as *mut libc::c_void, more.len());
+        assert_eq!(eof, 0);
+        assert_eq!(glue::close_from_fs(fd), 0);
+
+        // `File.open` on a directory succeeds and sysread fails with EISDIR
+        let path = CString::new("/test/data").unwrap();
+        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC, 0);
+        assert!(fd >= 0);
+        assert_eq!(glue::fstat_from_fs(fd, &mut stat_buf), 0);
+        assert_eq!(stat_buf.st_mode & libc::S_IFMT, libc::S_IFDIR);
+        let result = glue::read_from_fs(fd, more.as_mut_ptr() as *mut libc::c_void, more.len());
+        assert_eq!(result, -1);
+        assert_eq!(errno::errno().0, libc::EISDIR);
+        assert_eq!(glue::close_from_fs(fd), 0);
+    }
+
+    #[test]
+    fn test_read_from_fs_on_opendir_fd() {
+        let path = CString::new("/test").unwrap();
+        let dir = glue::opendir_from_fs(path.as_ptr());
+        assert!(!dir.is_null());
+        let fd = util::with_dir_stream(dir, |dir| dir.fd).unwrap();
+
+        let mut buf = [0u8; 16];
+        let result = glue::read_from_fs(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        assert_eq!(result, -1);
+        assert_eq!(errno::errno().0, libc::EISDIR);
+
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+        assert_eq!(glue::fstat_from_fs(fd, &mut stat_buf), 0);
+        assert_eq!(stat_buf.st_mode & libc::S_IFMT, libc::S_IFDIR);
+
+        assert_eq!(glue::closedir_from_fs(dir), 0);
+    }
+
+    #[test]
+    fn test_readdir_after_closedir() {
+        let path = CString::new("/test").unwrap();
+        let dir = glue::opendir_from_fs(path.as_ptr());
+        assert!(!dir.is_null());
+        assert_eq!(glue::closedir_from_fs(dir), 0);
+
+        // The closed fd's number is free to be reused by the host, and another
+        // stream may be opened in the meantime...
+        let host = std::fs::File::open("/dev/null").unwrap();
+        let host_fd = std::os::fd::AsRawFd::as_raw_fd(&host);
+        let other = glue::opendir_from_fs(path.as_ptr());
+        assert!(!other.is_null());
+        assert_ne!(other, dir);
+
+        // ...while the stale stream fails without touching either
+        assert!(glue::readdir_from_fs(dir).is_null());
+        assert_eq!(errno::errno().0, libc::EBADF);
+        assert_eq!(glue::closedir_from_fs(dir), -1);
+        assert_eq!(errno::errno().0, libc::EBADF);
+        assert_ne!(unsafe { libc::fcntl(host_fd, libc::F_GETFD) }, -1);
+
+        let entry = glue::readdir_from_fs(other);
+        assert!(!entry.is_null());
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+        assert_eq!(name.to_bytes(), b".");
+        assert_eq!(glue::closedir_from_fs(other), 0);
+    }
+
+    #[test]
+    fn test_closedir_frees_stream() {
+        let path = CString::new("/test").unwrap();
+        let opened = (0..1000)
+            .map(|_| {
+                let dir = glue::opendir_from_fs(path.as_ptr());
+                assert!(!dir.is_null());
+                assert_eq!(glue::closedir_from_fs(dir), 0);
+                dir
+            })
+            .collect::<std::collections::HashSet<_>>();
+
+        // Each stream got its own handle, while their slots were reused
+        assert_eq!(opened.len(), 1000);
+        assert!(opened.iter().all(|dir| util::is_dir_exists_in_kompo(*dir)));
+        assert!(util::dir_stream_slots() < 1000);
+
+        // A host stream is told apart without a lookup
+        let host_dir = unsafe { libc::opendir(c"/".as_ptr()) };
+        assert!(!host_dir.is_null());
+        assert!(!util::is_dir_exists_in_kompo(host_dir));
+        assert_eq!(unsafe { libc::closedir(host_dir) }, 0);
+    }
+
+    #[test]
+    fn test_fdopendir_matches_opendir() {
+        fn read_names(dir: *mut libc::DIR) -> Vec<String> {
+            let mut names = Vec::new();
+            loop {
+                let entry = glue::readdir_from_fs(dir);
+                if entry.is_null() {
+                    break;
+                }
+                let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+                names.push(name.to_string_lossy().to_string());
+            }
+            names
+        }
+
+        let path = CString::new("/test").unwrap();
+        let dir = glue::opendir_from_fs(path.as_ptr());
+        assert!(!dir.is_null());
+        let opendir_names = read_names(dir);
+        assert_eq!(glue::closedir_from_fs(dir), 0);
+
+        let fd = glue::open_from_fs(path.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY, 0);
+        assert!(fd >= 0);
+        let dir = glue::fdopendir_from_fs(fd);
+        assert!(!dir.is_null());
+        let fdopendir_names = read_names(dir);
+
+        assert_eq!(fdopendir_names, opendir_names);
+        assert!(fdopendir_names.iter().any(|name| name == "hello.txt"));
+        assert!(fdopendir_names.iter().any(|name| name == "data"));
+
+        // The DIR owns the fd, so closing it closes the fd as well
+        assert_eq!(glue::closedir_from_fs(dir), 0);
+        assert!(!util::is_fd_exists_in_kompo(fd));
+    }
+
     #[test]
     fn test_stat_directory() {
         let path = CString::new("/test").unwrap();
@@ -512,30 +2367,153 @@ mod tests {
         );
     }
 
+    #[test]
+    #[serial]
+    fn test_stat_vfs_root() {
+        set_working_dir(Some(Path::new("/test")));
+
+        let path = CString::new("/").unwrap();
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+        let result = glue::stat_from_fs(path.as_ptr(), &mut stat_buf);
+
+        reset_state();
+
+        assert_eq!(result, 0);
+        assert_eq!(stat_buf.st_mode & libc::S_IFMT, libc::S_IFDIR);
+    }
+
+    #[test]
+    #[serial]
+    fn test_opendir_vfs_root() {
+        set_working_dir(Some(Path::new("/test")));
+
+        let path = CString::new("/").unwrap();
+        let dir = glue::opendir_from_fs(path.as_ptr());
+        assert!(!dir.is_null());
+
+        let mut entries = Vec::new();
+        loop {
+            let entry = glue::readdir_from_fs(dir);
+            if entry.is_null() {
+                break;
+            }
+            let name = unsafe {
+                CStr::from_ptr((*entry).d_name.as_ptr())
+                    .to_string_lossy()
+                    .to_string()
+            };
+            entries.push(name);
+        }
+        glue::closedir_from_fs(dir);
+
+        reset_state();
+
+        assert_eq!(entries, [".", "..", "test"]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_stat_current_dir() {
+        set_working_dir(Some(Path::new("/test")));
+
+        let path = CString::new(".").unwrap();
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+        let result = glue::stat_from_fs(path.as_ptr(), &mut stat_buf);
+
+        let path = CString::new("./hello.txt").unwrap();
+        let mut file_stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+        let file_result = glue::stat_from_fs(path.as_ptr(), &mut file_stat_buf);
+
+        reset_state();
+
+        assert_eq!(result, 0);
+        assert_eq!(stat_buf.st_mode & libc::S_IFMT, libc::S_IFDIR);
+        assert_eq!(file_result, 0);
+        assert_eq!(file_stat_buf.st_size, 13);
+    }
+
+    #[test]
+    #[serial]
+    fn test_opendir_current_dir() {
+        set_working_dir(Some(Path::new("/test")));
+
+        let path = CString::new(".").unwrap();
+        let dir = glue::opendir_from_fs(path.as_ptr());
+        assert!(!dir.is_null());
+
+        let mut entries = Vec::new();
+        loop {
+            let entry = glue::readdir_from_fs(dir);
+            if entry.is_null() {
+                break;
+            }
+            let name = unsafe {
+                CStr::from_ptr((*entry).d_name.as_ptr())
+                    .to_string_lossy()
+                    .to_string()
+            };
+            entries.push(name);
+        }
+        glue::closedir_from_fs(dir);
+
+        reset_state();
+
+        assert!(entries.iter().any(|e| e == "hello.txt"));
+        assert!(entries.iter().any(|e| e == "world.txt"));
+    }
+
     #[test]
     #[serial]
     fn test_kompo_fs_set_entrypoint_dir_with_valid_path() {
         let path = CString::new("/app/bin/main.rb").unwrap();
 
         // Clear WORKING_DIR before test
-        WORKING_DIR.write().unwrap().take();
+        reset_state();
 
         unsafe {
             kompo_fs_set_entrypoint_dir(path.as_ptr());
         }
 
         // Verify WORKING_DIR is set to the parent directory
-        let working_dir = WORKING_DIR.write().unwrap().take();
-        assert!(working_dir.is_some());
-        let dir_path = working_dir.unwrap();
-        assert_eq!(dir_path.to_str().unwrap(), "/app/bin");
+        assert_eq!(current_working_dir(), Some(PathBuf::from("/app/bin")));
+
+        reset_state();
+    }
+
+    #[test]
+    #[serial]
+    fn test_set_working_dir_roundtrip() {
+        reset_state();
+        assert_eq!(current_working_dir(), None);
+        assert!(!has_working_dir());
+
+        set_working_dir(Some(Path::new("/test/data")));
+        assert_eq!(current_working_dir(), Some(PathBuf::from("/test/data")));
+        assert!(has_working_dir());
+
+        // Seen by the C API as well
+        let mut buf = [0 as libc::c_char; 32];
+        assert_eq!(
+            unsafe { kompo_fs_working_dir(buf.as_mut_ptr(), buf.len()) },
+            0
+        );
+        assert_eq!(
+            unsafe { CStr::from_ptr(buf.as_ptr()) }.to_bytes(),
+            b"/test/data"
+        );
+
+        set_working_dir(None);
+        assert_eq!(current_working_dir(), None);
+        assert!(!has_working_dir());
+
+        reset_state();
     }
 
     #[test]
     #[serial]
     fn test_kompo_fs_set_entrypoint_dir_with_null() {
         // Clear WORKING_DIR before test
-        WORKING_DIR.write().unwrap().take();
+        reset_state();
 
         // Should not panic when passing null
         unsafe {
@@ -543,8 +2521,7 @@ mod tests {
         }
 
         // Verify WORKING_DIR is still None
-        let working_dir = WORKING_DIR.write().unwrap().take();
-        assert!(working_dir.is_none());
+        assert_eq!(current_working_dir(), None);
     }
 
     #[test]
@@ -553,16 +2530,15 @@ mod tests {
         let path = CString::new("/main.rb").unwrap();
 
         // Clear WORKING_DIR before test
-        WORKING_DIR.write().unwrap().take();
+        reset_state();
 
         unsafe {
             kompo_fs_set_entrypoint_dir(path.as_ptr());
         }
 
         // Verify WORKING_DIR is set to root
-        let working_dir = WORKING_DIR.write().unwrap().take();
-        assert!(working_dir.is_some());
-        let dir_path = working_dir.unwrap();
-        assert_eq!(dir_path.to_str().unwrap(), "/");
+        assert_eq!(current_working_dir(), Some(PathBuf::from("/")));
+
+        reset_state();
     }
 }
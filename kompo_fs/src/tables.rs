@@ -0,0 +1,178 @@
+//! Validated access to the path and file tables the packer links into the binary.
+
+/// The embedded tables, checked to be consistent with each other.
+#[derive(Debug)]
+pub struct EmbeddedTables {
+    /// NUL-terminated paths, one per file.
+    pub paths: &'static [u8],
+    /// Contents of every file, concatenated in path order.
+    pub files: &'static [u8],
+    /// Offset of each file in `files`, plus the end of the last one.
+    pub files_sizes: &'static [u64],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TableError {
+    /// A table symbol resolved to a null address.
+    NullTable(&'static str),
+    /// A size symbol is negative.
+    NegativeSize(&'static str),
+    /// `PATHS` does not end with a NUL byte.
+    UnterminatedPaths,
+    /// `FILES_SIZES` does not describe consecutive ranges covering `FILES`.
+    InvalidOffsets,
+}
+
+impl std::fmt::Display for TableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TableError::NullTable(name) => write!(f, "{name} is null"),
+            TableError::NegativeSize(name) => write!(f, "{name} is negative"),
+            TableError::UnterminatedPaths => write!(f, "PATHS is not NUL-terminated"),
+            TableError::InvalidOffsets => {
+                write!(f, "FILES_SIZES does not match the paths and FILES")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TableError {}
+
+fn checked_size(size: libc::c_int, name: &'static str) -> Result<usize, TableError> {
+    usize::try_from(size).map_err(|_| TableError::NegativeSize(name))
+}
+
+fn checked_ptr<T>(ptr: *const T, name: &'static str) -> Result<*const T, TableError> {
+    if ptr.is_null() {
+        Err(TableError::NullTable(name))
+    } else {
+        Ok(ptr)
+    }
+}
+
+/// Splits `paths` into its NUL-terminated paths. A last path missing its NUL is
+/// rejected rather than read as a C string running past the table.
+pub fn split_paths(paths: &[u8]) -> Result<Vec<&std::ffi::CStr>, TableError> {
+    paths
+        .split_inclusive(|b| *b == b'\0')
+        .map(|path| {
+            std::ffi::CStr::from_bytes_with_nul(path).map_err(|_| TableError::UnterminatedPaths)
+        })
+        .collect()
+}
+
+/// Builds slices over the embedded tables once the sizes are known to be
+/// non-negative and `files_sizes` holds non-decreasing offsets from 0 to
+/// `files_size`, one per path plus the end. `files_sizes` is never read past
+/// that many entries.
+///
+/// # Safety
+/// `paths` and `files` must point to at least `paths_size` and `files_size`
+/// readable bytes, and `files_sizes` to one offset per NUL in `paths` plus one,
+/// all living for the rest of the program.
+pub unsafe fn embedded_tables(
+    paths: *const u8,
+    paths_size: libc::c_int,
+    files: *const u8,
+    files_size: libc::c_int,
+    files_sizes: *const u64,
+) -> Result<EmbeddedTables, TableError> {
+    let paths_size = checked_size(paths_size, "PATHS_SIZE")?;
+    let files_size = checked_size(files_size, "FILES_SIZE")?;
+    let paths = unsafe { std::slice::from_raw_parts(checked_ptr(paths, "PATHS")?, paths_size) };
+    let files = unsafe { std::slice::from_raw_parts(checked_ptr(files, "FILES")?, files_size) };
+
+    if paths.last().is_some_and(|b| *b != b'\0') {
+        return Err(TableError::UnterminatedPaths);
+    }
+
+    let count = paths.iter().filter(|b| **b == b'\0').count();
+    let files_sizes =
+        unsafe { std::slice::from_raw_parts(checked_ptr(files_sizes, "FILES_SIZES")?, count + 1) };
+
+    let is_consecutive = files_sizes[0] == 0
+        && files_sizes.windows(2).all(|w| w[0] <= w[1])
+        && files_sizes[count] == files_size as u64;
+    if !is_consecutive {
+        return Err(TableError::InvalidOffsets);
+    }
+
+    Ok(EmbeddedTables {
+        paths,
+        files,
+        files_sizes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedded_tables_from_test_data() {
+        let tables = unsafe {
+            embedded_tables(
+                &crate::PATHS as *const libc::c_char as *const u8,
+                crate::PATHS_SIZE,
+                &crate::FILES as *const libc::c_char as *const u8,
+                crate::FILES_SIZE,
+                &crate::FILES_SIZES,
+            )
+        }
+        .unwrap();
+
+        let count = tables.paths.iter().filter(|b| **b == b'\0').count();
+        assert!(count > 0);
+        assert_eq!(tables.files_sizes.len(), count + 1);
+        assert_eq!(tables.files.len() as u64, tables.files_sizes[count]);
+    }
+
+    #[test]
+    fn test_split_paths() {
+        let paths = split_paths(b"/a\0/b/c\0").unwrap();
+        assert_eq!(paths, [c"/a", c"/b/c"]);
+        assert!(split_paths(b"").unwrap().is_empty());
+
+        // The table ends in the middle of the buffer, after "/a\0/b"
+        let buffer = *b"/a\0/b/c";
+        assert_eq!(
+            split_paths(&buffer[..5]).unwrap_err(),
+            TableError::UnterminatedPaths
+        );
+    }
+
+    #[test]
+    fn test_embedded_tables_invalid() {
+        static PATHS: [u8; 4] = *b"a\0b\0";
+        static FILES: [u8; 3] = *b"xyz";
+
+        let tables = |paths: &'static [u8], files_size, files_sizes: &'static [u64]| unsafe {
+            embedded_tables(
+                paths.as_ptr(),
+                paths.len() as _,
+                FILES.as_ptr(),
+                files_size,
+                files_sizes.as_ptr(),
+            )
+        };
+
+        assert!(tables(&PATHS, 3, &[0, 1, 3]).is_ok());
+        assert_eq!(
+            tables(&PATHS, -1, &[0, 1, 3]).unwrap_err(),
+            TableError::NegativeSize("FILES_SIZE")
+        );
+        assert_eq!(
+            tables(&PATHS[..3], 3, &[0, 1, 3]).unwrap_err(),
+            TableError::UnterminatedPaths
+        );
+        assert_eq!(
+            tables(&PATHS, 3, &[0, 2, 1]).unwrap_err(),
+            TableError::InvalidOffsets
+        );
+        // The last offset must be the end of FILES
+        assert_eq!(
+            tables(&PATHS[..2], 3, &[0, 1, 3]).unwrap_err(),
+            TableError::InvalidOffsets
+        );
+    }
+}
@@ -5,9 +5,10 @@ use std::{
     os::unix::ffi::OsStrExt,
     path::{Path, PathBuf},
     str::FromStr,
+    sync::{Arc, Mutex},
 };
 
-use crate::{TRIE, WD, WORKING_DIR};
+use crate::{TRIE, WD, current_working_dir, has_working_dir};
 
 /// # Safety
 /// `other_path` must be a valid pointer to a null-terminated C string.
@@ -18,6 +19,32 @@ pub unsafe fn is_under_kompo_working_dir(other_path: *const libc::c_char) -> boo
     other_path.to_bytes().starts_with(wd.to_bytes())
 }
 
+/// Returns true when `path` is `/` while the working directory is inside the VFS,
+/// in which case the root refers to the VFS root rather than the host one.
+///
+/// # Safety
+/// `path` must be a valid pointer to a null-terminated C string.
+pub unsafe fn is_kompo_root(path: *const libc::c_char) -> bool {
+    has_working_dir() && unsafe { CStr::from_ptr(path) }.to_bytes() == b"/"
+}
+
+/// Returns true when `path` is the empty string, which names no file.
+///
+/// # Safety
+/// `path` must be a valid pointer to a null-terminated C string.
+pub unsafe fn is_empty_path(path: *const libc::c_char) -> bool {
+    unsafe { *path == 0 }
+}
+
+/// Returns true when `path` is neither empty nor absolute.
+///
+/// # Safety
+/// `path` must be a valid pointer to a null-terminated C string.
+pub unsafe fn is_relative_path(path: *const libc::c_char) -> bool {
+    let first = unsafe { *path };
+    first != 0 && first != b'/' as libc::c_char
+}
+
 pub fn canonicalize_path(base: &mut PathBuf, join_path: &Path) {
     for comp in join_path.components() {
         match comp {
@@ -38,42 +65,117 @@ pub fn canonicalize_path(base: &mut PathBuf, join_path: &Path) {
     }
 }
 
+/// Like [`canonicalize_path`], but an absolute `join_path` replaces `base` entirely,
+/// as POSIX does when resolving an absolute path relative to a directory.
+pub fn canonicalize_join_path(base: &mut PathBuf, join_path: &Path) {
+    if join_path.is_absolute() {
+        *base = PathBuf::from("/");
+    }
+
+    canonicalize_path(base, join_path);
+}
+
+/// Returns true when resolving `path` from a directory would leave it, either
+/// because `path` is absolute or because a `..` climbs above the start. This is
+/// what `openat2` refuses under `RESOLVE_BENEATH`.
+pub fn escapes_beneath(path: &Path) -> bool {
+    let mut depth = 0usize;
+
+    for comp in path.components() {
+        match comp {
+            std::path::Component::RootDir | std::path::Component::Prefix(_) => return true,
+            std::path::Component::ParentDir => match depth.checked_sub(1) {
+                Some(d) => depth = d,
+                None => return true,
+            },
+            std::path::Component::Normal(_) => depth += 1,
+            std::path::Component::CurDir => {}
+        }
+    }
+
+    false
+}
+
+/// Resolves `path` for lookups made on behalf of Ruby code. A relative path is taken
+/// relative to `WORKING_DIR`, or to the embedded working directory before `chdir`.
+pub fn resolve_kompo_path(path: &Path) -> PathBuf {
+    let mut base = match current_working_dir() {
+        Some(wd) => wd,
+        None => PathBuf::from(std::ffi::OsStr::from_bytes(
+            unsafe { CStr::from_ptr(&WD) }.to_bytes(),
+        )),
+    };
+
+    canonicalize_join_path(&mut base, path);
+    base
+}
+
+/// Returns `path` made absolute against `WORKING_DIR` when it names something
+/// outside the VFS, so the hooks pass it to the real syscalls instead of failing
+/// with `ENOENT`: anything under `/dev`, and relative paths that `..` takes out of
+/// the embedded working directory to a path the VFS does not have.
+///
+/// # Safety
+/// `path` must be a valid pointer to a null-terminated C string.
+pub unsafe fn host_path(path: *const libc::c_char) -> Option<CString> {
+    let bytes = unsafe { CStr::from_ptr(path) }.to_bytes();
+    // Keep the common absolute lookups free of locking and allocation
+    if bytes.starts_with(b"/") && !bytes.starts_with(b"/dev") {
+        return None;
+    }
+
+    let path = Path::new(std::ffi::OsStr::from_bytes(bytes));
+    let mut resolved = match current_working_dir() {
+        Some(wd) => wd,
+        None if path.is_absolute() => PathBuf::from("/"),
+        None => return None,
+    };
+
+    canonicalize_join_path(&mut resolved, path);
+    let is_host_path =
+        resolved.starts_with("/dev") || (path.is_relative() && !is_kompo_resolved_path(&resolved));
+    if !is_host_path {
+        return None;
+    }
+
+    CString::new(resolved.into_os_string().into_encoded_bytes()).ok()
+}
+
+fn is_kompo_resolved_path(path: &Path) -> bool {
+    let wd = Path::new(std::ffi::OsStr::from_bytes(
+        unsafe { CStr::from_ptr(&WD) }.to_bytes(),
+    ));
+
+    path == Path::new("/")
+        || path.starts_with(wd)
+        || TRIE
+            .get_or_init(crate::initialize_trie)
+            .contains(&path.iter().collect())
+}
+
+/// Resolves `raw_path` against `WORKING_DIR` when it is relative. `.` and `..`
+/// components are folded away so the result can be looked up in the trie as is.
+///
 /// # Safety
 /// `raw_path` must be a valid pointer to a null-terminated C string.
-pub unsafe fn expand_kompo_path(raw_path: *const libc::c_char) -> *const libc::c_char {
+pub unsafe fn expanded_kompo_path(raw_path: *const libc::c_char) -> CString {
     let path = unsafe { CStr::from_ptr(raw_path) };
     let path = PathBuf::from_str(path.to_str().expect("invalid path")).expect("invalid path");
 
     if path.is_absolute() {
-        let path = CString::new(path.to_str().expect("invalid path"))
-            .expect("invalid path")
-            .into_boxed_c_str();
-        let path = Box::into_raw(path);
-
-        return path as *const libc::c_char;
+        return CString::new(path.to_str().expect("invalid path")).expect("invalid path");
     }
 
-    let wd = WORKING_DIR.read().unwrap().clone().unwrap();
-    let mut wd = PathBuf::from(wd);
+    let mut wd = current_working_dir().unwrap();
 
     canonicalize_path(&mut wd, &path);
 
-    let wd = CString::new(wd.to_str().expect("invalid path"))
-        .expect("invalid path")
-        .into_boxed_c_str();
-    let wd = Box::into_raw(wd);
-
-    wd as *const libc::c_char
+    CString::new(wd.to_str().expect("invalid path")).expect("invalid path")
 }
 
 pub fn current_dir_hash() -> u64 {
     let mut hasher = DefaultHasher::new();
-    WORKING_DIR
-        .read()
-        .unwrap()
-        .as_ref()
-        .unwrap()
-        .hash(&mut hasher);
+    current_working_dir().unwrap().as_os_str().hash(&mut hasher);
     hasher.finish()
 }
 
@@ -89,29 +191,146 @@ pub unsafe fn is_under_kompo_tmp_dir(other_path: *const libc::c_char) -> bool {
         .starts_with(tmpdir.as_os_str().as_bytes())
 }
 
-pub fn is_fd_exists_in_kompo(fd: i32) -> bool {
-    if TRIE.get().is_none() {
-        return false;
+/// Fails a lookup of `path`, a path in the VFS that is not embedded, with `ENOENT`
+/// instead of passing it to the real syscall, as strict mode requires. The attempt
+/// is passed to the callback set by [`kompo_fs_set_strict_mode_log`], if any.
+///
+/// [`kompo_fs_set_strict_mode_log`]: crate::kompo_fs_set_strict_mode_log
+pub fn refuse_host_fallback(path: &CStr) -> libc::c_int {
+    if let Some(log) = crate::strict_mode_log() {
+        unsafe { log(path.as_ptr()) };
     }
+    errno::set_errno(errno::Errno(libc::ENOENT));
+    -1
+}
 
-    let trie = std::sync::Arc::clone(TRIE.get().unwrap());
-    trie.is_fd_exists(fd)
+pub fn is_fd_exists_in_kompo(fd: i32) -> bool {
+    crate::initialized_trie().is_some_and(|trie| trie.is_fd_exists(fd))
 }
 
-/// # Safety
-/// `dir` must be a valid pointer to a `FsDir` that was previously allocated by this crate.
-pub unsafe fn is_dir_exists_in_kompo(dir: *mut libc::DIR) -> bool {
-    if TRIE.get().is_none() {
-        return false;
+// VFS directory streams, which a `DIR *` names by a handle rather than an address:
+// the slot holding the stream and the generation of the stream in it, with the low
+// bit set, which a real `DIR *` (an aligned heap pointer) never has. A slot is
+// freed at `closedir` and reused by a later stream under a new generation, so a
+// stale `DIR *` fails with `EBADF` instead of naming that stream.
+#[derive(Default)]
+struct DirStreams {
+    slots: Vec<DirSlot>,
+    free: Vec<u32>,
+}
+
+#[derive(Default)]
+struct DirSlot {
+    generation: u32,
+    dir: Option<Arc<Mutex<kompo_storage::FsDir>>>,
+}
+
+const DIR_HANDLE_TAG: usize = 1;
+// Generations take the 31 bits between the tag and the slot index
+const DIR_GENERATION_MASK: u32 = u32::MAX >> 1;
+
+static DIR_STREAMS: std::sync::LazyLock<Mutex<DirStreams>> =
+    std::sync::LazyLock::new(Default::default);
+
+fn with_dir_streams<T>(f: impl FnOnce(&mut DirStreams) -> T) -> T {
+    let mut streams = DIR_STREAMS
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    f(&mut streams)
+}
+
+fn dir_handle(index: u32, generation: u32) -> *mut libc::DIR {
+    (((index as usize) << 32) | ((generation as usize) << 1) | DIR_HANDLE_TAG) as *mut libc::DIR
+}
+
+// The slot index and generation `dir` was handed out with
+fn split_dir_handle(dir: *mut libc::DIR) -> (usize, u32) {
+    let handle = dir as usize;
+    (handle >> 32, (handle as u32) >> 1)
+}
+
+impl DirStreams {
+    fn get(&self, dir: *mut libc::DIR) -> Option<&Arc<Mutex<kompo_storage::FsDir>>> {
+        let (index, generation) = split_dir_handle(dir);
+        let slot = self.slots.get(index)?;
+        if slot.generation != generation {
+            return None;
+        }
+        slot.dir.as_ref()
     }
+}
 
-    let dir = unsafe { Box::from_raw(dir as *mut kompo_storage::FsDir) };
+/// Hands out `dir` as a `DIR *` that no stream open before it had.
+pub fn into_dir_stream(dir: kompo_storage::FsDir) -> *mut libc::DIR {
+    let dir = Some(Arc::new(Mutex::new(dir)));
+    with_dir_streams(|streams| {
+        let index = match streams.free.pop() {
+            Some(index) => index,
+            None => {
+                streams.slots.push(DirSlot::default());
+                (streams.slots.len() - 1) as u32
+            }
+        };
+        let slot = &mut streams.slots[index as usize];
+        slot.dir = dir;
+        dir_handle(index, slot.generation)
+    })
+}
 
-    let trie = std::sync::Arc::clone(TRIE.get().unwrap());
-    let bool = trie.is_dir_exists(&dir);
+/// Runs `f` on the VFS stream `dir`, or fails with [`FsError::BadFd`] if it has
+/// been closed.
+///
+/// [`FsError::BadFd`]: kompo_storage::FsError::BadFd
+pub fn with_dir_stream<T>(
+    dir: *mut libc::DIR,
+    f: impl FnOnce(&mut kompo_storage::FsDir) -> T,
+) -> Result<T, kompo_storage::FsError> {
+    // Other streams aren't held up while this one is read
+    let fs_dir = with_dir_streams(|streams| streams.get(dir).cloned())
+        .ok_or(kompo_storage::FsError::BadFd)?;
+    let mut fs_dir = fs_dir
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    Ok(f(&mut fs_dir))
+}
+
+/// Closes the VFS stream `dir`, removing its fd from the VFS and freeing the
+/// stream, and returns the fd for the caller to close on the host. Once closed,
+/// `readdir` and `closedir` on `dir` fail with `EBADF`, so this fails with
+/// [`FsError::BadFd`] the second time and the fd is never closed twice.
+///
+/// [`FsError::BadFd`]: kompo_storage::FsError::BadFd
+pub fn close_dir_stream(dir: *mut libc::DIR) -> Result<i32, kompo_storage::FsError> {
+    let trie = crate::initialized_trie().ok_or(kompo_storage::FsError::BadFd)?;
+
+    // The slot is emptied first, so no `readdir` finds the stream once its fd has
+    // left the VFS
+    let fs_dir = with_dir_streams(|streams| {
+        streams.get(dir)?;
+        let (index, _) = split_dir_handle(dir);
+        let slot = &mut streams.slots[index];
+        slot.generation = slot.generation.wrapping_add(1) & DIR_GENERATION_MASK;
+        streams.free.push(index as u32);
+        slot.dir.take()
+    })
+    .ok_or(kompo_storage::FsError::BadFd)?;
+
+    let fs_dir = fs_dir
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    trie.closedir(&fs_dir)?;
+    Ok(fs_dir.fd)
+}
+
+#[cfg(test)]
+pub(crate) fn dir_stream_slots() -> usize {
+    with_dir_streams(|streams| streams.slots.len())
+}
 
-    let _ = Box::into_raw(dir);
-    bool
+/// Returns whether `dir` was handed out by this crate rather than the host's libc,
+/// open or closed, without dereferencing it or taking a lock.
+pub fn is_dir_exists_in_kompo(dir: *mut libc::DIR) -> bool {
+    dir as usize & DIR_HANDLE_TAG != 0
 }
 
 #[cfg(test)]
@@ -180,6 +399,35 @@ mod tests {
         assert_eq!(base, PathBuf::from("/home/user/etc/config"));
     }
 
+    #[test]
+    fn test_canonicalize_join_path_absolute() {
+        let mut base = PathBuf::from("/home/user");
+        let join_path = PathBuf::from("/etc/config");
+
+        canonicalize_join_path(&mut base, &join_path);
+
+        assert_eq!(base, PathBuf::from("/etc/config"));
+    }
+
+    #[test]
+    fn test_canonicalize_join_path_relative() {
+        let mut base = PathBuf::from("/home/user");
+        let join_path = PathBuf::from("../other/./file");
+
+        canonicalize_join_path(&mut base, &join_path);
+
+        assert_eq!(base, PathBuf::from("/home/other/file"));
+    }
+
+    #[test]
+    fn test_escapes_beneath() {
+        assert!(!escapes_beneath(Path::new("data/small.bin")));
+        assert!(!escapes_beneath(Path::new("data/../hello.txt")));
+        assert!(escapes_beneath(Path::new("../hello.txt")));
+        assert!(escapes_beneath(Path::new("data/../../hello.txt")));
+        assert!(escapes_beneath(Path::new("/test/hello.txt")));
+    }
+
     #[test]
     fn test_canonicalize_path_parent_beyond_root() {
         let mut base = PathBuf::from("/home");
@@ -211,6 +459,44 @@ mod tests {
         assert_eq!(base, PathBuf::from("/home/user"));
     }
 
+    #[test]
+    #[serial_test::serial]
+    fn test_expanded_kompo_path_current_dir() {
+        crate::set_working_dir(Some(Path::new("/test")));
+
+        let dot = CString::new(".").unwrap();
+        let expanded = unsafe { expanded_kompo_path(dot.as_ptr()) };
+        assert_eq!(expanded.to_str().unwrap(), "/test");
+
+        let dot_file = CString::new("./hello.txt").unwrap();
+        let expanded = unsafe { expanded_kompo_path(dot_file.as_ptr()) };
+        assert_eq!(expanded.to_str().unwrap(), "/test/hello.txt");
+
+        crate::reset_state();
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_host_path() {
+        let host_path = |path: &str| {
+            let path = CString::new(path).unwrap();
+            unsafe { host_path(path.as_ptr()) }.map(|p| p.into_string().unwrap())
+        };
+
+        crate::set_working_dir(Some(Path::new("/test")));
+        let in_vfs = host_path("hello.txt");
+        let outside = host_path("../etc/hosts");
+        let device = host_path("/dev/null");
+        let absolute = host_path("/etc/hosts");
+        crate::reset_state();
+
+        assert_eq!(in_vfs, None);
+        assert_eq!(outside.as_deref(), Some("/etc/hosts"));
+        assert_eq!(device.as_deref(), Some("/dev/null"));
+        // Absolute paths are already routed by their prefix
+        assert_eq!(absolute, None);
+    }
+
     #[test]
     fn test_canonicalize_path_only_parent_dir() {
         let mut base = PathBuf::from("/home/user/documents");
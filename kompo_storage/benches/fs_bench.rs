@@ -1,6 +1,7 @@
-use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
-use kompo_storage::Fs;
-use std::ffi::OsStr;
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use kompo_storage::{CachedStat, Fs, StatCache};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
 use std::hint::black_box;
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
@@ -217,10 +218,10 @@ fn bench_require_simulation(c: &mut Criterion) {
             fs.stat(black_box(&path), &mut stat_buf);
 
             // open, read, close
-            let fd = fs.open(&path).unwrap();
+            let fd = fs.open(&path, libc::O_RDONLY).unwrap();
             let mut buf = [0u8; 8192];
             while fs.read(fd, &mut buf).unwrap_or(0) > 0 {}
-            fs.close(fd);
+            fs.close(fd).unwrap();
             unsafe { libc::close(fd) };
         })
     });
@@ -241,10 +242,10 @@ fn bench_require_simulation(c: &mut Criterion) {
             let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
             fs.stat(black_box(&path), &mut stat_buf);
 
-            let fd = fs.open(&path).unwrap();
+            let fd = fs.open(&path, libc::O_RDONLY).unwrap();
             let mut buf = [0u8; 8192];
             while fs.read(fd, &mut buf).unwrap_or(0) > 0 {}
-            fs.close(fd);
+            fs.close(fd).unwrap();
             unsafe { libc::close(fd) };
         })
     });
@@ -266,10 +267,10 @@ fn bench_require_simulation(c: &mut Criterion) {
             let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
             fs.stat(black_box(&path), &mut stat_buf);
 
-            let fd = fs.open(&path).unwrap();
+            let fd = fs.open(&path, libc::O_RDONLY).unwrap();
             let mut buf = [0u8; 8192];
             while fs.read(fd, &mut buf).unwrap_or(0) > 0 {}
-            fs.close(fd);
+            fs.close(fd).unwrap();
             unsafe { libc::close(fd) };
         })
     });
@@ -277,6 +278,374 @@ fn bench_require_simulation(c: &mut Criterion) {
     group.finish();
 }
 
+/// `require` stats a file and then opens it. Compare opening the path that was just
+/// stat'd against opening a different one, which is served from the open templates.
+fn bench_stat_then_open(c: &mut Criterion) {
+    let mut group = c.benchmark_group("stat_then_open");
+
+    let fs = create_rails_app_fs();
+    let path: Vec<&OsStr> = vec![
+        OsStr::new("vendor"),
+        OsStr::new("bundle"),
+        OsStr::new("ruby"),
+        OsStr::new("3.2.0"),
+        OsStr::new("gems"),
+        OsStr::new("rails"),
+        OsStr::new("lib"),
+        OsStr::new("rails0.rb"),
+    ];
+    let other_path: Vec<&OsStr> = vec![
+        OsStr::new("vendor"),
+        OsStr::new("bundle"),
+        OsStr::new("ruby"),
+        OsStr::new("3.2.0"),
+        OsStr::new("gems"),
+        OsStr::new("rails"),
+        OsStr::new("lib"),
+        OsStr::new("rails1.rb"),
+    ];
+
+    group.bench_function("same_path", |b| {
+        b.iter(|| {
+            let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+            fs.stat(black_box(&path), &mut stat_buf);
+
+            let fd = fs.open(black_box(&path), libc::O_RDONLY).unwrap();
+            fs.close(fd).unwrap();
+            unsafe { libc::close(fd) };
+        })
+    });
+
+    group.bench_function("different_path", |b| {
+        b.iter(|| {
+            let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+            fs.stat(black_box(&path), &mut stat_buf);
+
+            let fd = fs.open(black_box(&other_path), libc::O_RDONLY).unwrap();
+            fs.close(fd).unwrap();
+            unsafe { libc::close(fd) };
+        })
+    });
+
+    group.finish();
+}
+
+/// Create a filesystem with `count` files directly under a single directory
+fn create_flat_dir_fs(count: usize) -> Fs<'static> {
+    let mut builder: TrieBuilder<&OsStr, &[u8]> = TrieBuilder::new();
+
+    for i in 0..count {
+        let file = format!("file{}.rb", i);
+        let file_leaked: &'static str = Box::leak(file.into_boxed_str());
+        let path: Vec<&OsStr> = vec![
+            OsStr::new("vendor"),
+            OsStr::new("large"),
+            OsStr::new(file_leaked),
+        ];
+        builder.push(&path, SMALL_CONTENT);
+    }
+
+    Fs::new(builder)
+}
+
+/// `opendir` collects every child of the directory up front, so its cost grows with
+/// the number of entries.
+fn bench_opendir_large_dir(c: &mut Criterion) {
+    let mut group = c.benchmark_group("opendir_large_dir");
+
+    for file_count in [1000, 5000, 20000] {
+        group.bench_with_input(
+            BenchmarkId::new("entries", file_count),
+            &file_count,
+            |b, &count| {
+                let fs = create_flat_dir_fs(count);
+                let path: Vec<&OsStr> = vec![OsStr::new("vendor"), OsStr::new("large")];
+                b.iter(|| {
+                    let dir = fs.opendir(black_box(&path)).unwrap();
+                    let fd = dir.fd;
+                    fs.closedir(&dir).unwrap();
+                    unsafe { libc::close(fd) };
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+// Iterating a directory that is already open, so only `readdir` is timed and not
+// the snapshot `opendir` takes
+fn bench_readdir_throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("readdir_throughput");
+
+    for file_count in [100, 1000, 10000] {
+        let fs = create_flat_dir_fs(file_count);
+        let path: Vec<&OsStr> = vec![OsStr::new("vendor"), OsStr::new("large")];
+        let mut dir = fs.opendir(&path).unwrap();
+
+        // Every file, plus `.` and `..`
+        group.throughput(Throughput::Elements(file_count as u64 + 2));
+        group.bench_function(BenchmarkId::new("entries", file_count), |b| {
+            b.iter(|| {
+                fs.rewinddir(&mut dir);
+                let mut count = 0;
+                while let Ok(entry) = fs.readdir(black_box(&mut dir)) {
+                    if entry.is_null() {
+                        break;
+                    }
+                    count += 1;
+                    unsafe { drop(Box::from_raw(entry)) };
+                }
+                count
+            })
+        });
+
+        let fd = dir.fd;
+        fs.closedir(&dir).unwrap();
+        unsafe { libc::close(fd) };
+    }
+
+    group.finish();
+}
+
+/// Many threads requiring the same file at startup open one path over and over.
+fn bench_repeated_open(c: &mut Criterion) {
+    let mut group = c.benchmark_group("repeated_open");
+
+    let fs = create_rails_app_fs();
+    let path: Vec<&OsStr> = vec![
+        OsStr::new("vendor"),
+        OsStr::new("bundle"),
+        OsStr::new("ruby"),
+        OsStr::new("3.2.0"),
+        OsStr::new("gems"),
+        OsStr::new("rails"),
+        OsStr::new("lib"),
+        OsStr::new("rails0.rb"),
+    ];
+
+    group.bench_function("same_path", |b| {
+        b.iter(|| {
+            let fd = fs.open(black_box(&path), libc::O_RDONLY).unwrap();
+            fs.close(fd).unwrap();
+            unsafe { libc::close(fd) };
+        })
+    });
+
+    group.finish();
+}
+
+/// `open(O_DIRECTORY)` used to `stat` the path to check its type before opening it,
+/// taking the locks twice. `open` now checks the flag against the one lookup it does.
+fn bench_open_directory(c: &mut Criterion) {
+    let mut group = c.benchmark_group("open_directory");
+
+    let fs = create_rails_app_fs();
+    let path: Vec<&OsStr> = vec![OsStr::new("app"), OsStr::new("models")];
+    let flags = libc::O_RDONLY | libc::O_DIRECTORY;
+
+    group.bench_function("stat_then_open", |b| {
+        b.iter(|| {
+            let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+            fs.stat(black_box(&path), &mut stat_buf).unwrap();
+            assert_eq!(stat_buf.st_mode & libc::S_IFMT, libc::S_IFDIR);
+
+            let fd = fs.open(black_box(&path), libc::O_RDONLY).unwrap();
+            fs.close(fd).unwrap();
+            unsafe { libc::close(fd) };
+        })
+    });
+
+    group.bench_function("open_with_flags", |b| {
+        b.iter(|| {
+            let fd = fs.open(black_box(&path), flags).unwrap();
+            fs.close(fd).unwrap();
+            unsafe { libc::close(fd) };
+        })
+    });
+
+    group.finish();
+}
+
+/// Ruby re-fstats open files a lot. `fstat` copies the stat computed at open, so
+/// it costs a shard lookup, measured here against a `stat` of the same path, which
+/// resolves the path and builds the stat again.
+fn bench_fstat(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fstat");
+
+    let fs = create_rails_app_fs();
+    let path: Vec<&OsStr> = vec![
+        OsStr::new("app"),
+        OsStr::new("models"),
+        OsStr::new("model0.rb"),
+    ];
+    let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+
+    group.bench_function("path_stat", |b| {
+        b.iter(|| {
+            let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+            fs.stat(black_box(&path), &mut stat_buf).unwrap();
+            black_box(stat_buf)
+        })
+    });
+
+    group.bench_function("cached_fstat", |b| {
+        b.iter(|| {
+            let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+            fs.fstat(black_box(fd), &mut stat_buf).unwrap();
+            black_box(stat_buf)
+        })
+    });
+
+    fs.close(fd).unwrap();
+    unsafe { libc::close(fd) };
+    group.finish();
+}
+
+/// `list_dir` resolves the directory and copies the names out, while `children`
+/// borrows them straight from the directory index. Both used to walk every file
+/// below the directory in the trie before the index existed.
+fn bench_child_enumeration(c: &mut Criterion) {
+    let mut group = c.benchmark_group("child_enumeration");
+
+    let fs = create_rails_app_fs();
+    let path: Vec<&OsStr> = vec![
+        OsStr::new("vendor"),
+        OsStr::new("bundle"),
+        OsStr::new("ruby"),
+        OsStr::new("3.2.0"),
+        OsStr::new("gems"),
+    ];
+
+    group.bench_function("list_dir", |b| {
+        b.iter(|| fs.list_dir(black_box(&path)).unwrap().len())
+    });
+
+    group.bench_function("children", |b| {
+        b.iter(|| fs.children(black_box(&path)).len())
+    });
+
+    group.finish();
+}
+
+/// A file mapped into memory, unmapped on drop.
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(file: &std::fs::File, len: usize) -> Self {
+        use std::os::fd::AsRawFd;
+
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        assert_ne!(ptr, libc::MAP_FAILED);
+
+        Self { ptr, len }
+    }
+
+    // The slice must not outlive the mapping.
+    fn bytes<'a>(&self) -> &'a [u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// The first read of embedded data faults it in one page at a time. Each iteration
+/// maps the data afresh, so nothing of it is mapped yet, then reads every file once
+/// with and without prefetching it first. On Linux the data is also dropped from
+/// the page cache, as it is for a binary that hasn't run in a while.
+fn bench_prefetch(c: &mut Criterion) {
+    const FILE_COUNT: usize = 1000;
+    const FILE_SIZE: usize = 16384;
+
+    let mut group = c.benchmark_group("first_read");
+
+    let image = std::env::temp_dir().join(format!("kompo_prefetch_bench_{}", std::process::id()));
+    std::fs::write(&image, vec![b'#'; FILE_COUNT * FILE_SIZE]).unwrap();
+    let file = std::fs::File::open(&image).unwrap();
+
+    // Spread over directories of 20 files, like the lib directories of gems
+    let leak = |name: String| -> &'static OsStr { OsStr::new(Box::leak(name.into_boxed_str())) };
+    let paths: Vec<Vec<&OsStr>> = (0..FILE_COUNT)
+        .map(|i| {
+            vec![
+                OsStr::new("lib"),
+                leak(format!("dir{}", i / 20)),
+                leak(format!("file{i}.rb")),
+            ]
+        })
+        .collect();
+
+    let setup = || {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+            unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+        }
+
+        let mapping = Mapping::new(&file, FILE_COUNT * FILE_SIZE);
+        let bytes = mapping.bytes();
+        let mut builder: TrieBuilder<&OsStr, &[u8]> = TrieBuilder::new();
+        for (i, path) in paths.iter().enumerate() {
+            builder.push(path, &bytes[i * FILE_SIZE..(i + 1) * FILE_SIZE]);
+        }
+
+        // Dropped in this order, the Fs goes before the data it borrows
+        (Fs::new(builder), mapping)
+    };
+    let read_all = |fs: &Fs| {
+        let mut buf = vec![0u8; FILE_SIZE];
+        for path in &paths {
+            let fd = fs.open(path, libc::O_RDONLY).unwrap();
+            fs.read(fd, &mut buf).unwrap();
+            fs.close(fd).unwrap();
+            unsafe { libc::close(fd) };
+        }
+    };
+
+    group.bench_function("cold", |b| {
+        b.iter_batched(
+            setup,
+            |state| {
+                read_all(&state.0);
+                state
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.bench_function("prefetched", |b| {
+        let slices: Vec<&[&OsStr]> = paths.iter().map(Vec::as_slice).collect();
+        b.iter_batched(
+            setup,
+            |state| {
+                state.0.prefetch(&slices);
+                read_all(&state.0);
+                state
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.finish();
+    std::fs::remove_file(&image).unwrap();
+}
+
 fn bench_dir_glob_simulation(c: &mut Criterion) {
     let mut group = c.benchmark_group("dir_glob_simulation");
 
@@ -287,7 +656,7 @@ fn bench_dir_glob_simulation(c: &mut Criterion) {
         b.iter(|| {
             let mut dir = fs.opendir(black_box(&path)).unwrap();
             let mut count = 0;
-            while let Some(entry) = fs.readdir(&mut dir) {
+            while let Ok(entry) = fs.readdir(&mut dir) {
                 if entry.is_null() {
                     break;
                 }
@@ -295,7 +664,7 @@ fn bench_dir_glob_simulation(c: &mut Criterion) {
                 unsafe { drop(Box::from_raw(entry)) };
             }
             let fd = dir.fd;
-            fs.closedir(&dir);
+            fs.closedir(&dir).unwrap();
             unsafe { libc::close(fd) };
             count
         })
@@ -315,7 +684,7 @@ fn bench_dir_glob_simulation(c: &mut Criterion) {
         b.iter(|| {
             let mut dir = fs.opendir(black_box(&path)).unwrap();
             let mut count = 0;
-            while let Some(entry) = fs.readdir(&mut dir) {
+            while let Ok(entry) = fs.readdir(&mut dir) {
                 if entry.is_null() {
                     break;
                 }
@@ -323,7 +692,7 @@ fn bench_dir_glob_simulation(c: &mut Criterion) {
                 unsafe { drop(Box::from_raw(entry)) };
             }
             let fd = dir.fd;
-            fs.closedir(&dir);
+            fs.closedir(&dir).unwrap();
             unsafe { libc::close(fd) };
             count
         })
@@ -341,7 +710,7 @@ fn bench_dir_glob_simulation(c: &mut Criterion) {
         b.iter(|| {
             let mut dir = fs.opendir(black_box(&path)).unwrap();
             let mut count = 0;
-            while let Some(entry) = fs.readdir(&mut dir) {
+            while let Ok(entry) = fs.readdir(&mut dir) {
                 if entry.is_null() {
                     break;
                 }
@@ -349,7 +718,7 @@ fn bench_dir_glob_simulation(c: &mut Criterion) {
                 unsafe { drop(Box::from_raw(entry)) };
             }
             let fd = dir.fd;
-            fs.closedir(&dir);
+            fs.closedir(&dir).unwrap();
             unsafe { libc::close(fd) };
             count
         })
@@ -381,7 +750,7 @@ fn bench_read_by_size(c: &mut Criterion) {
             let fs = Fs::new(builder);
 
             b.iter(|| {
-                let fd = fs.open(&path).unwrap();
+                let fd = fs.open(&path, libc::O_RDONLY).unwrap();
                 let mut buf = [0u8; 8192];
                 let mut total = 0;
                 while let Some(n) = fs.read(fd, &mut buf) {
@@ -390,7 +759,7 @@ fn bench_read_by_size(c: &mut Criterion) {
                     }
                     total += n;
                 }
-                fs.close(fd);
+                fs.close(fd).unwrap();
                 unsafe { libc::close(fd) };
                 total
             })
@@ -490,37 +859,47 @@ fn bench_stat_by_depth(c: &mut Criterion) {
 // Scalability benchmarks (file count)
 // ============================================================================
 
-fn create_scaled_fs(file_count: usize) -> Fs<'static> {
-    let mut builder: TrieBuilder<&OsStr, &[u8]> = TrieBuilder::new();
-
-    // Distribute files across realistic directory structure
+// Paths of `file_count` files distributed across a realistic directory structure
+fn scaled_paths(file_count: usize) -> Vec<Vec<&'static OsStr>> {
     let files_per_gem = 50;
 
-    for i in 0..file_count {
-        let gem_idx = i / files_per_gem;
-        let file_idx = i % files_per_gem;
+    (0..file_count)
+        .map(|i| {
+            let gem_idx = i / files_per_gem;
+            let file_idx = i % files_per_gem;
 
-        let gem = format!("gem{}", gem_idx);
-        let file = format!("file{}.rb", file_idx);
-        let gem_leaked: &'static str = Box::leak(gem.into_boxed_str());
-        let file_leaked: &'static str = Box::leak(file.into_boxed_str());
+            let gem = format!("gem{}", gem_idx);
+            let file = format!("file{}.rb", file_idx);
+            let gem_leaked: &'static str = Box::leak(gem.into_boxed_str());
+            let file_leaked: &'static str = Box::leak(file.into_boxed_str());
 
-        let path: Vec<&OsStr> = vec![
-            OsStr::new("vendor"),
-            OsStr::new("bundle"),
-            OsStr::new("ruby"),
-            OsStr::new("3.2.0"),
-            OsStr::new("gems"),
-            OsStr::new(gem_leaked),
-            OsStr::new("lib"),
-            OsStr::new(file_leaked),
-        ];
-        builder.push(&path, MEDIUM_CONTENT);
+            vec![
+                OsStr::new("vendor"),
+                OsStr::new("bundle"),
+                OsStr::new("ruby"),
+                OsStr::new("3.2.0"),
+                OsStr::new("gems"),
+                OsStr::new(gem_leaked),
+                OsStr::new("lib"),
+                OsStr::new(file_leaked),
+            ]
+        })
+        .collect()
+}
+
+fn build_fs(paths: &[Vec<&'static OsStr>]) -> Fs<'static> {
+    let mut builder: TrieBuilder<&OsStr, &[u8]> = TrieBuilder::new();
+    for path in paths {
+        builder.push(path, MEDIUM_CONTENT);
     }
 
     Fs::new(builder)
 }
 
+fn create_scaled_fs(file_count: usize) -> Fs<'static> {
+    build_fs(&scaled_paths(file_count))
+}
+
 fn bench_scalability(c: &mut Criterion) {
     let mut group = c.benchmark_group("scalability");
 
@@ -557,6 +936,23 @@ fn bench_scalability(c: &mut Criterion) {
     group.finish();
 }
 
+// What `initialize_fs` adds to Ruby's startup: pushing every embedded path and
+// building the trie and the indexes over it, before the first lookup
+fn bench_trie_build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("trie_build");
+    group.sample_size(10);
+
+    for file_count in [1000, 10000, 50000] {
+        let paths = scaled_paths(file_count);
+        group.throughput(Throughput::Elements(file_count as u64));
+        group.bench_with_input(BenchmarkId::new("files", file_count), &paths, |b, paths| {
+            b.iter(|| build_fs(black_box(paths)))
+        });
+    }
+
+    group.finish();
+}
+
 // ============================================================================
 // Basic operation benchmarks (isolated)
 // ============================================================================
@@ -586,7 +982,7 @@ fn bench_basic_operations(c: &mut Criterion) {
             OsStr::new("models"),
             OsStr::new("model0.rb"),
         ];
-        let fd = fs.open(&path).unwrap();
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
         b.iter(|| {
             let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
             fs.fstat(black_box(fd), &mut stat_buf)
@@ -607,6 +1003,25 @@ fn bench_basic_operations(c: &mut Criterion) {
         })
     });
 
+    // A missing require candidate next to real files, as Ruby tries each load path
+    group.bench_function("stat_nonexistent_in_gem", |b| {
+        let fs = create_rails_app_fs();
+        let path: Vec<&OsStr> = vec![
+            OsStr::new("vendor"),
+            OsStr::new("bundle"),
+            OsStr::new("ruby"),
+            OsStr::new("3.2.0"),
+            OsStr::new("gems"),
+            OsStr::new("rails"),
+            OsStr::new("lib"),
+            OsStr::new("active_record.rb"),
+        ];
+        b.iter(|| {
+            let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+            fs.stat(black_box(&path), &mut stat_buf)
+        })
+    });
+
     // Pure open/close cycle
     group.bench_function("open_close_only", |b| {
         let fs = create_rails_app_fs();
@@ -616,8 +1031,8 @@ fn bench_basic_operations(c: &mut Criterion) {
             OsStr::new("model0.rb"),
         ];
         b.iter(|| {
-            let fd = fs.open(black_box(&path)).unwrap();
-            fs.close(fd);
+            let fd = fs.open(black_box(&path), libc::O_RDONLY).unwrap();
+            fs.close(fd).unwrap();
             unsafe { libc::close(fd) };
         })
     });
@@ -690,6 +1105,86 @@ fn bench_concurrent_stat(c: &mut Criterion) {
     group.finish();
 }
 
+/// Compare a single `RwLock<HashMap>` stat cache against the sharded `StatCache`
+/// with 16 threads hitting the cache concurrently.
+fn bench_concurrent_stat_cache(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_stat_cache");
+    let num_threads = 16;
+    let lookups_per_thread = 1000;
+
+    let fs = create_rails_app_fs();
+    let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+    let paths: Vec<Vec<OsString>> = (0..30)
+        .map(|i| {
+            vec![
+                OsString::from("app"),
+                OsString::from("models"),
+                OsString::from(format!("model{}.rb", i)),
+            ]
+        })
+        .collect();
+    let search_path: Vec<&OsStr> = paths[0].iter().map(|s| s.as_os_str()).collect();
+    fs.stat(&search_path, &mut stat_buf).unwrap();
+    let stat_buf = CachedStat::from_stat(&stat_buf);
+    let paths = Arc::new(paths);
+
+    group.throughput(Throughput::Elements(
+        (num_threads * lookups_per_thread) as u64,
+    ));
+
+    let single = Arc::new(RwLock::new(HashMap::new()));
+    for path in paths.iter() {
+        single.write().unwrap().insert(path.clone(), stat_buf);
+    }
+    group.bench_function("single_rwlock", |b| {
+        b.iter(|| {
+            let handles: Vec<_> = (0..num_threads)
+                .map(|i| {
+                    let cache = Arc::clone(&single);
+                    let paths = Arc::clone(&paths);
+                    thread::spawn(move || {
+                        for j in 0..lookups_per_thread {
+                            let path = &paths[(i + j) % paths.len()];
+                            black_box(cache.read().unwrap().get(path).copied());
+                        }
+                    })
+                })
+                .collect();
+
+            for handle in handles {
+                handle.join().unwrap();
+            }
+        })
+    });
+
+    let sharded = Arc::new(StatCache::new());
+    for path in paths.iter() {
+        sharded.insert(path.clone(), stat_buf);
+    }
+    group.bench_function("sharded", |b| {
+        b.iter(|| {
+            let handles: Vec<_> = (0..num_threads)
+                .map(|i| {
+                    let cache = Arc::clone(&sharded);
+                    let paths = Arc::clone(&paths);
+                    thread::spawn(move || {
+                        for j in 0..lookups_per_thread {
+                            let path = &paths[(i + j) % paths.len()];
+                            black_box(cache.get(path));
+                        }
+                    })
+                })
+                .collect();
+
+            for handle in handles {
+                handle.join().unwrap();
+            }
+        })
+    });
+
+    group.finish();
+}
+
 fn bench_concurrent_require(c: &mut Criterion) {
     let mut group = c.benchmark_group("concurrent_require");
 
@@ -754,7 +1249,7 @@ fn bench_concurrent_require(c: &mut Criterion) {
                                 // open
                                 let fd = {
                                     let fs = fs.lock().unwrap();
-                                    fs.open(path).unwrap()
+                                    fs.open(path, libc::O_RDONLY).unwrap()
                                 };
 
                                 // read
@@ -767,7 +1262,7 @@ fn bench_concurrent_require(c: &mut Criterion) {
                                 // close
                                 {
                                     let fs = fs.lock().unwrap();
-                                    fs.close(fd);
+                                    fs.close(fd).unwrap();
                                 }
                                 unsafe { libc::close(fd) };
                             })
@@ -837,7 +1332,7 @@ fn bench_concurrent_mixed_workload(c: &mut Criterion) {
 
                                     let fd = {
                                         let fs = fs.lock().unwrap();
-                                        fs.open(path).unwrap()
+                                        fs.open(path, libc::O_RDONLY).unwrap()
                                     };
 
                                     {
@@ -848,7 +1343,7 @@ fn bench_concurrent_mixed_workload(c: &mut Criterion) {
 
                                     {
                                         let fs = fs.lock().unwrap();
-                                        fs.close(fd);
+                                        fs.close(fd).unwrap();
                                     }
                                     unsafe { libc::close(fd) };
                                 }
@@ -1089,7 +1584,7 @@ fn bench_rwlock_require(c: &mut Criterion) {
                                 // open
                                 let fd = {
                                     let fs = fs.lock().unwrap();
-                                    fs.open(path).unwrap()
+                                    fs.open(path, libc::O_RDONLY).unwrap()
                                 };
 
                                 // read
@@ -1102,7 +1597,7 @@ fn bench_rwlock_require(c: &mut Criterion) {
                                 // close
                                 {
                                     let fs = fs.lock().unwrap();
-                                    fs.close(fd);
+                                    fs.close(fd).unwrap();
                                 }
                                 unsafe { libc::close(fd) };
                             })
@@ -1164,7 +1659,7 @@ fn bench_rwlock_require(c: &mut Criterion) {
                                 // open (write lock)
                                 let fd = {
                                     let fs = fs.write().unwrap();
-                                    fs.open(path).unwrap()
+                                    fs.open(path, libc::O_RDONLY).unwrap()
                                 };
 
                                 // read (write lock)
@@ -1177,7 +1672,7 @@ fn bench_rwlock_require(c: &mut Criterion) {
                                 // close (write lock)
                                 {
                                     let fs = fs.write().unwrap();
-                                    fs.close(fd);
+                                    fs.close(fd).unwrap();
                                 }
                                 unsafe { libc::close(fd) };
                             })
@@ -1238,7 +1733,7 @@ fn bench_rwlock_read_heavy(c: &mut Criterion) {
                             // require thread (write)
                             let fd = {
                                 let fs = fs.lock().unwrap();
-                                fs.open(&require_path).unwrap()
+                                fs.open(&require_path, libc::O_RDONLY).unwrap()
                             };
 
                             {
@@ -1249,7 +1744,7 @@ fn bench_rwlock_read_heavy(c: &mut Criterion) {
 
                             {
                                 let fs = fs.lock().unwrap();
-                                fs.close(fd);
+                                fs.close(fd).unwrap();
                             }
                             unsafe { libc::close(fd) };
                         }
@@ -1297,7 +1792,7 @@ fn bench_rwlock_read_heavy(c: &mut Criterion) {
                             // require thread (write lock)
                             let fd = {
                                 let fs = fs.write().unwrap();
-                                fs.open(&require_path).unwrap()
+                                fs.open(&require_path, libc::O_RDONLY).unwrap()
                             };
 
                             {
@@ -1308,7 +1803,7 @@ fn bench_rwlock_read_heavy(c: &mut Criterion) {
 
                             {
                                 let fs = fs.write().unwrap();
-                                fs.close(fd);
+                                fs.close(fd).unwrap();
                             }
                             unsafe { libc::close(fd) };
                         }
@@ -1445,10 +1940,10 @@ fn bench_internal_rwlock(c: &mut Criterion) {
                                 let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
                                 fs.stat(black_box(path), &mut stat_buf);
 
-                                let fd = fs.open(path).unwrap();
+                                let fd = fs.open(path, libc::O_RDONLY).unwrap();
                                 let mut buf = [0u8; 8192];
                                 while fs.read(fd, &mut buf).unwrap_or(0) > 0 {}
-                                fs.close(fd);
+                                fs.close(fd).unwrap();
                                 unsafe { libc::close(fd) };
                             })
                         })
@@ -1503,10 +1998,10 @@ fn bench_internal_rwlock(c: &mut Criterion) {
                                 let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
                                 fs.stat(black_box(path), &mut stat_buf);
 
-                                let fd = fs.open(path).unwrap();
+                                let fd = fs.open(path, libc::O_RDONLY).unwrap();
                                 let mut buf = [0u8; 8192];
                                 while fs.read(fd, &mut buf).unwrap_or(0) > 0 {}
-                                fs.close(fd);
+                                fs.close(fd).unwrap();
                                 unsafe { libc::close(fd) };
                             })
                         })
@@ -1603,15 +2098,77 @@ fn bench_rwlock_stat_only(c: &mut Criterion) {
     group.finish();
 }
 
+/// Opens and closes a file while other threads read a large file in one buffer.
+/// `read` copies outside the fd table lock, so the opens, which take it for
+/// writing, don't wait for the copies to finish.
+fn bench_open_during_large_read(c: &mut Criterion) {
+    const HUGE_LEN: usize = 64 * 1024 * 1024;
+
+    let mut group = c.benchmark_group("open_during_large_read");
+
+    let huge: &'static [u8] = Box::leak(vec![b'#'; HUGE_LEN].into_boxed_slice());
+    let mut builder: TrieBuilder<&OsStr, &[u8]> = TrieBuilder::new();
+    builder.push([OsStr::new("huge.bin")], huge);
+    builder.push([OsStr::new("small.rb")], SMALL_CONTENT);
+    let fs = Arc::new(Fs::new(builder));
+    let small = vec![OsStr::new("small.rb")];
+
+    for readers in [0, 1, 4] {
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let handles = (0..readers)
+            .map(|_| {
+                let fs = Arc::clone(&fs);
+                let stop = Arc::clone(&stop);
+                thread::spawn(move || {
+                    let mut buf = vec![0u8; HUGE_LEN];
+                    let fd = fs
+                        .open(&vec![OsStr::new("huge.bin")], libc::O_RDONLY)
+                        .unwrap();
+                    while !stop.load(std::sync::atomic::Ordering::Relaxed) {
+                        fs.read_at(fd, &mut buf, 0);
+                    }
+                    fs.close(fd).unwrap();
+                    unsafe { libc::close(fd) };
+                })
+            })
+            .collect::<Vec<_>>();
+
+        group.bench_with_input(BenchmarkId::new("open_close", readers), &readers, |b, _| {
+            b.iter(|| {
+                let fd = fs.open(black_box(&small), libc::O_RDONLY).unwrap();
+                fs.close(fd).unwrap();
+                unsafe { libc::close(fd) };
+            })
+        });
+
+        stop.store(true, std::sync::atomic::Ordering::Relaxed);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    group.finish();
+}
+
 criterion_group!(
     benches,
     bench_require_simulation,
+    bench_stat_then_open,
+    bench_repeated_open,
+    bench_open_directory,
+    bench_fstat,
+    bench_opendir_large_dir,
+    bench_readdir_throughput,
+    bench_child_enumeration,
+    bench_prefetch,
     bench_dir_glob_simulation,
     bench_read_by_size,
     bench_stat_by_depth,
     bench_scalability,
+    bench_trie_build,
     bench_basic_operations,
     bench_concurrent_stat,
+    bench_concurrent_stat_cache,
     bench_concurrent_require,
     bench_concurrent_mixed_workload,
     bench_lock_contention,
@@ -1622,5 +2179,6 @@ criterion_group!(
     bench_rwlock_stat_only,
     // Internal RwLock benchmarks (Arc<Fs> without external lock)
     bench_internal_rwlock,
+    bench_open_during_large_read,
 );
 criterion_main!(benches);
@@ -0,0 +1,204 @@
+//! Assembling an [`Fs`] from several sources (the app, its gems, the standard
+//! library), each collected into its own builder and merged.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use trie_rs::map::TrieBuilder;
+
+use crate::{Fs, FsConfig};
+
+/// What [`FsBuilder`] does with a path that is already present.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// The contents pushed or merged last replace the earlier ones.
+    #[default]
+    LastWins,
+    /// Adding the path again fails with [`DuplicatePath`].
+    Error,
+}
+
+/// A path added twice to an [`FsBuilder`] with [`ConflictPolicy::Error`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicatePath(pub PathBuf);
+
+impl std::fmt::Display for DuplicatePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is embedded more than once", self.0.display())
+    }
+}
+
+impl std::error::Error for DuplicatePath {}
+
+/// Collects embedded files, unlike `TrieBuilder` allowing other builders to be
+/// merged in and duplicate paths to be detected.
+#[derive(Debug, Clone, Default)]
+pub struct FsBuilder<'a> {
+    policy: ConflictPolicy,
+    files: BTreeMap<Vec<&'a OsStr>, &'a [u8]>,
+    empty_dirs: BTreeSet<Vec<&'a OsStr>>,
+}
+
+impl<'a> FsBuilder<'a> {
+    pub fn new(policy: ConflictPolicy) -> Self {
+        Self {
+            policy,
+            files: BTreeMap::new(),
+            empty_dirs: BTreeSet::new(),
+        }
+    }
+
+    /// Adds the file at `path`, resolving a path already present by the policy.
+    pub fn push(&mut self, path: Vec<&'a OsStr>, file: &'a [u8]) -> Result<(), DuplicatePath> {
+        if self.policy == ConflictPolicy::Error && self.files.contains_key(&path) {
+            return Err(DuplicatePath(path.iter().collect()));
+        }
+
+        self.files.insert(path, file);
+        Ok(())
+    }
+
+    /// Adds the directory at `path`, which exists even if no file is pushed below it.
+    /// A directory that is also pushed as a file is a file.
+    pub fn push_dir(&mut self, path: Vec<&'a OsStr>) {
+        self.empty_dirs.insert(path);
+    }
+
+    /// Adds every file and directory of `other`, in order, as by [`FsBuilder::push`]. With
+    /// [`ConflictPolicy::Error`], nothing is added if a path of `other` is already
+    /// present.
+    pub fn merge(&mut self, other: FsBuilder<'a>) -> Result<(), DuplicatePath> {
+        if self.policy == ConflictPolicy::Error
+            && let Some(path) = other.files.keys().find(|p| self.files.contains_key(*p))
+        {
+            return Err(DuplicatePath(path.iter().collect()));
+        }
+
+        self.files.extend(other.files);
+        self.empty_dirs.extend(other.empty_dirs);
+        Ok(())
+    }
+
+    /// Returns the number of files added.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Returns the `TrieBuilder` holding every file, for [`Fs::with_config`].
+    /// Directories added with [`FsBuilder::push_dir`] are not part of it.
+    pub fn into_trie_builder(self) -> TrieBuilder<&'a OsStr, &'a [u8]> {
+        let mut builder = TrieBuilder::new();
+        for (path, file) in self.files {
+            builder.push(path, file);
+        }
+
+        builder
+    }
+
+    pub fn build(self) -> Fs<'a> {
+        self.build_with_config(FsConfig::default())
+    }
+
+    pub fn build_with_config(self, config: FsConfig) -> Fs<'a> {
+        let empty_dirs = self.empty_dirs.iter().cloned().collect::<Vec<_>>();
+        Fs::with_empty_dirs(self.into_trie_builder(), &empty_dirs, config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(s: &str) -> Vec<&OsStr> {
+        Fs::path_components(std::path::Path::new(s))
+    }
+
+    fn read_all(fs: &Fs, p: &str) -> Vec<u8> {
+        let fd = fs.open(&path(p), libc::O_RDONLY).unwrap();
+        let mut buf = [0u8; 64];
+        let n = fs.read(fd, &mut buf).unwrap() as usize;
+        fs.close(fd).unwrap();
+        buf[..n].to_vec()
+    }
+
+    fn sources(policy: ConflictPolicy) -> (FsBuilder<'static>, FsBuilder<'static>) {
+        let mut app = FsBuilder::new(policy);
+        app.push(path("/app/main.rb"), b"app main").unwrap();
+        app.push(path("/lib/json.rb"), b"app json").unwrap();
+
+        let mut gems = FsBuilder::new(policy);
+        gems.push(path("/lib/json.rb"), b"gem json").unwrap();
+        gems.push(path("/lib/yaml.rb"), b"gem yaml").unwrap();
+
+        (app, gems)
+    }
+
+    #[test]
+    fn test_merge_last_wins() {
+        let (mut app, gems) = sources(ConflictPolicy::LastWins);
+        app.merge(gems).unwrap();
+        assert_eq!(app.len(), 3);
+
+        let fs = app.build();
+        assert_eq!(read_all(&fs, "/app/main.rb"), b"app main");
+        assert_eq!(read_all(&fs, "/lib/json.rb"), b"gem json");
+        assert_eq!(read_all(&fs, "/lib/yaml.rb"), b"gem yaml");
+    }
+
+    #[test]
+    fn test_merge_error_on_duplicate() {
+        let (mut app, gems) = sources(ConflictPolicy::Error);
+        assert_eq!(
+            app.merge(gems),
+            Err(DuplicatePath(PathBuf::from("/lib/json.rb")))
+        );
+        // A failed merge leaves the builder as it was
+        assert_eq!(app.len(), 2);
+        assert_eq!(read_all(&app.build(), "/lib/json.rb"), b"app json");
+
+        let mut app = FsBuilder::new(ConflictPolicy::Error);
+        app.push(path("/app/main.rb"), b"a").unwrap();
+        assert!(app.push(path("/app/main.rb"), b"b").is_err());
+    }
+
+    #[test]
+    fn test_empty_dir() {
+        let (mut app, _) = sources(ConflictPolicy::LastWins);
+        app.push_dir(path("/app/tmp/cache"));
+        // Already a file
+        app.push_dir(path("/app/main.rb"));
+        let fs = app.build();
+
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        assert!(fs.stat(&path("/app/tmp/cache"), &mut stat).is_some());
+        assert_eq!(stat.st_mode & libc::S_IFMT, libc::S_IFDIR);
+        assert!(fs.stat(&path("/app/tmp"), &mut stat).is_some());
+        assert_eq!(stat.st_mode & libc::S_IFMT, libc::S_IFDIR);
+        assert_eq!(read_all(&fs, "/app/main.rb"), b"app main");
+
+        // Empty, yet a directory that can be read
+        let mut dir = fs.opendir(&path("/app/tmp/cache")).unwrap();
+        let mut names = Vec::new();
+        loop {
+            let dirent = fs.readdir(&mut dir).unwrap();
+            if dirent.is_null() {
+                break;
+            }
+            let dirent = unsafe { Box::from_raw(dirent) };
+            let name = unsafe { std::ffi::CStr::from_ptr(dirent.d_name.as_ptr()) };
+            names.push(name.to_str().unwrap().to_string());
+        }
+        assert_eq!(names, [".", ".."]);
+        fs.closedir(&dir).unwrap();
+        assert_eq!(fs.list_dir(&path("/app/tmp/cache")), Ok(Vec::new()));
+        assert_eq!(fs.list_dir(&path("/app/tmp")), Ok(vec!["cache".into()]));
+        assert_eq!(fs.empty_dirs(), vec![PathBuf::from("/app/tmp/cache")]);
+
+        // Missing directories still don't exist
+        assert!(fs.opendir(&path("/app/tmp/missing")).is_none());
+    }
+}
@@ -0,0 +1,165 @@
+//! Decompressed contents of compressed entries, kept so that a hot file is not
+//! decompressed again on every `open` or `read`.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// Turns the stored bytes of an entry into its contents, or `None` if they are
+/// corrupt.
+pub type Decompress = fn(&[u8]) -> Option<Vec<u8>>;
+
+#[derive(Debug, Default)]
+struct Lru {
+    // Path to contents and the tick of its last use.
+    entries: HashMap<PathBuf, (Arc<[u8]>, u64)>,
+    // Tick of last use to path, oldest first.
+    order: BTreeMap<u64, PathBuf>,
+    bytes: usize,
+    tick: u64,
+}
+
+/// Least recently used cache of decompressed contents, keyed by path and bounded
+/// by the total size of the contents it holds rather than by entry count.
+#[derive(Debug)]
+pub struct DecompressCache {
+    max_bytes: usize,
+    decompress: Decompress,
+    lru: Mutex<Lru>,
+}
+
+impl DecompressCache {
+    /// Creates a cache holding at most `max_bytes` of contents produced by
+    /// `decompress`.
+    pub fn new(max_bytes: usize, decompress: Decompress) -> Self {
+        Self {
+            max_bytes,
+            decompress,
+            lru: Mutex::new(Lru::default()),
+        }
+    }
+
+    /// Returns the contents of the entry at `path` stored as `compressed`,
+    /// decompressing it only if it isn't cached. Contents larger than the whole
+    /// cache are returned without being cached.
+    pub fn get(&self, path: &Path, compressed: &[u8]) -> Option<Arc<[u8]>> {
+        {
+            let mut lru = self.lru.lock().unwrap();
+            if let Some(contents) = lru.touch(path) {
+                return Some(contents);
+            }
+        }
+
+        // Decompress without holding the lock, so that other paths aren't blocked
+        let contents: Arc<[u8]> = (self.decompress)(compressed)?.into();
+        if contents.len() <= self.max_bytes {
+            let mut lru = self.lru.lock().unwrap();
+            lru.insert(path, Arc::clone(&contents), self.max_bytes);
+        }
+
+        Some(contents)
+    }
+
+    /// Total size of the cached contents.
+    pub fn cached_bytes(&self) -> usize {
+        self.lru.lock().unwrap().bytes
+    }
+}
+
+impl Lru {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn touch(&mut self, path: &Path) -> Option<Arc<[u8]>> {
+        let tick = self.next_tick();
+        let (contents, last_used) = self.entries.get_mut(path)?;
+        let path = self.order.remove(last_used).unwrap();
+        *last_used = tick;
+        self.order.insert(tick, path);
+
+        Some(Arc::clone(contents))
+    }
+
+    fn insert(&mut self, path: &Path, contents: Arc<[u8]>, max_bytes: usize) {
+        // Another thread may have decompressed the same path meanwhile
+        if self.touch(path).is_some() {
+            return;
+        }
+
+        while self.bytes + contents.len() > max_bytes {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            let (evicted, _) = self.entries.remove(&oldest).unwrap();
+            self.bytes -= evicted.len();
+        }
+
+        let tick = self.next_tick();
+        self.bytes += contents.len();
+        self.order.insert(tick, path.to_path_buf());
+        self.entries.insert(path.to_path_buf(), (contents, tick));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    // Each test counts its own decompressor runs
+    static REPEAT_RUNS: AtomicUsize = AtomicUsize::new(0);
+    static EVICT_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    // Stands in for a real codec: every stored byte expands to four
+    fn expand(compressed: &[u8]) -> Vec<u8> {
+        compressed.iter().flat_map(|b| [*b; 4]).collect()
+    }
+
+    #[test]
+    fn test_repeated_reads_decompress_once() {
+        let cache = DecompressCache::new(1024, |compressed| {
+            REPEAT_RUNS.fetch_add(1, Ordering::Relaxed);
+            Some(expand(compressed))
+        });
+        let path = Path::new("/app/main.rb");
+
+        for _ in 0..10 {
+            let contents = cache.get(path, b"ab").unwrap();
+            assert_eq!(&*contents, b"aaaabbbb");
+        }
+        assert_eq!(REPEAT_RUNS.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.cached_bytes(), 8);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used_by_bytes() {
+        let cache = DecompressCache::new(16, |compressed| {
+            EVICT_RUNS.fetch_add(1, Ordering::Relaxed);
+            Some(expand(compressed))
+        });
+        let (a, b, c) = (Path::new("/a"), Path::new("/b"), Path::new("/c"));
+
+        cache.get(a, b"aa").unwrap();
+        cache.get(b, b"bb").unwrap();
+        // Reading `a` again makes `b` the least recently used
+        cache.get(a, b"aa").unwrap();
+        cache.get(c, b"cc").unwrap();
+        assert_eq!(EVICT_RUNS.load(Ordering::Relaxed), 3);
+        assert_eq!(cache.cached_bytes(), 16);
+
+        cache.get(a, b"aa").unwrap();
+        assert_eq!(EVICT_RUNS.load(Ordering::Relaxed), 3);
+        cache.get(b, b"bb").unwrap();
+        assert_eq!(EVICT_RUNS.load(Ordering::Relaxed), 4);
+
+        // Contents larger than the cache are served but not kept
+        assert_eq!(cache.get(Path::new("/big"), b"12345").unwrap().len(), 20);
+        assert_eq!(cache.cached_bytes(), 16);
+    }
+}
@@ -0,0 +1,189 @@
+//! Files embedded gzip-compressed, as web assets often are, served decompressed at
+//! the path without `.gz` while still reading as stored at their own path.
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::{Decompress, DecompressCache, Fs};
+
+// A gzip member ends with the CRC-32 and the size of its contents, each 4 bytes.
+const GZIP_TRAILER_LEN: usize = 8;
+
+impl Fs<'static> {
+    /// Serves every embedded `NAME.gz` also at `NAME`, as the contents `decompress`
+    /// turns it into. `NAME.gz` still reads as the compressed bytes. A `NAME` that is
+    /// embedded itself is left as is. Decompressed contents are kept in a cache of at
+    /// most `max_cached_bytes`.
+    ///
+    /// Each `NAME` is a virtual file (see [`Fs::add_virtual`]), decompressed only when
+    /// it is opened. `stat` and listings report the size recorded in the gzip
+    /// trailer, so they never decompress. Opening `NAME` fails with
+    /// [`FsError::Io`](crate::FsError::Io) if its contents fail to decompress or
+    /// don't match that size, as do assets of 4 GiB or more, whose size the trailer
+    /// can't hold. Returns the number of paths added.
+    pub fn add_gzip_assets(&self, decompress: Decompress, max_cached_bytes: usize) -> usize {
+        let cache = Arc::new(DecompressCache::new(max_cached_bytes, decompress));
+
+        let mut added = 0;
+        for (path, compressed) in self.entries() {
+            let Some(stripped) = path.as_os_str().as_bytes().strip_suffix(b".gz") else {
+                continue;
+            };
+            // `.gz` alone names no asset
+            if path.file_name() == Some(OsStr::new(".gz")) {
+                continue;
+            }
+            let stripped = PathBuf::from(OsStr::from_bytes(stripped));
+            let stripped = Fs::path_components(&stripped);
+            if self.contains(&stripped) {
+                continue;
+            }
+
+            // Too short to be gzip, so it fails to decompress at open
+            let size = compressed
+                .len()
+                .checked_sub(GZIP_TRAILER_LEN)
+                .map_or(0, |trailer| {
+                    u32::from_le_bytes(compressed[trailer + 4..].try_into().unwrap())
+                });
+
+            let cache = Arc::clone(&cache);
+            self.add_virtual_sized(
+                &stripped,
+                size.into(),
+                Box::new(move || {
+                    cache
+                        .get(&path, compressed)
+                        .filter(|contents| contents.len() as u64 == u64::from(size))
+                }),
+            );
+            added += 1;
+        }
+
+        added
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FsError;
+    use std::path::Path;
+
+    // Stands in for gzip: every stored byte before the trailer expands to four
+    fn expand(compressed: &[u8]) -> Option<Vec<u8>> {
+        let data = &compressed[..compressed.len().checked_sub(GZIP_TRAILER_LEN)?];
+        if data == b"corrupt" {
+            return None;
+        }
+        Some(data.iter().flat_map(|b| [*b; 4]).collect())
+    }
+
+    // `data` followed by a trailer recording `size` decompressed bytes
+    fn member(data: &[u8], size: u32) -> Vec<u8> {
+        let mut member = data.to_vec();
+        member.extend_from_slice(&[0; 4]);
+        member.extend_from_slice(&size.to_le_bytes());
+        member
+    }
+
+    fn path(s: &str) -> Vec<&OsStr> {
+        Fs::path_components(Path::new(s))
+    }
+
+    fn read_all(fs: &Fs, p: &str) -> Vec<u8> {
+        let fd = fs.open(&path(p), libc::O_RDONLY).unwrap();
+        let mut buf = [0u8; 64];
+        let n = fs.read(fd, &mut buf).unwrap() as usize;
+        fs.close(fd).unwrap();
+        buf[..n].to_vec()
+    }
+
+    fn test_fs() -> Fs<'static> {
+        let files = vec![
+            (b"/app/public/app.js.gz".to_vec(), member(b"ab", 8)),
+            (b"/app/public/style.css.gz".to_vec(), member(b"xyz", 12)),
+            // Embedded uncompressed as well
+            (b"/app/public/index.html".to_vec(), b"<html>".to_vec()),
+            (b"/app/public/index.html.gz".to_vec(), member(b"h", 4)),
+            (
+                b"/app/public/broken.txt.gz".to_vec(),
+                member(b"corrupt", 28),
+            ),
+            // The trailer disagrees with the contents
+            (b"/app/public/short.txt.gz".to_vec(), member(b"s", 5)),
+            (b"/app/public/tiny.txt.gz".to_vec(), b"t".to_vec()),
+            (b"/app/public/.gz".to_vec(), b"dot".to_vec()),
+        ];
+        Fs::from_bytes(Box::leak(files.into_boxed_slice()))
+    }
+
+    #[test]
+    fn test_gzip_assets() {
+        let fs = test_fs();
+        assert_eq!(fs.add_gzip_assets(expand, 1024), 5);
+
+        // Raw at the `.gz` path, decompressed at the stripped one
+        assert_eq!(read_all(&fs, "/app/public/app.js.gz"), member(b"ab", 8));
+        assert_eq!(read_all(&fs, "/app/public/app.js"), b"aaaabbbb");
+        assert_eq!(read_all(&fs, "/app/public/style.css"), b"xxxxyyyyzzzz");
+
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        assert!(fs.stat(&path("/app/public/app.js"), &mut stat).is_some());
+        assert_eq!(stat.st_size, 8);
+        assert!(fs.stat(&path("/app/public/app.js.gz"), &mut stat).is_some());
+        assert_eq!(stat.st_size, 10);
+
+        // The embedded file wins over its compressed copy
+        assert_eq!(read_all(&fs, "/app/public/index.html"), b"<html>");
+        for broken in ["broken.txt", "short.txt", "tiny.txt"] {
+            let broken = format!("/app/public/{broken}");
+            assert_eq!(fs.open(&path(&broken), libc::O_RDONLY), Err(FsError::Io));
+        }
+        assert_eq!(fs.open_fd_count(), 0);
+
+        let names = fs.list_dir(&path("/app/public")).unwrap();
+        for name in [
+            "app.js",
+            "app.js.gz",
+            "style.css",
+            "index.html",
+            "broken.txt",
+        ] {
+            assert!(names.contains(&name.into()), "{name}");
+        }
+        assert!(!names.contains(&"".into()));
+    }
+
+    #[test]
+    fn test_gzip_assets_decompress_on_open() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        fn counting(compressed: &[u8]) -> Option<Vec<u8>> {
+            RUNS.fetch_add(1, Ordering::Relaxed);
+            expand(compressed)
+        }
+
+        let fs = test_fs();
+        fs.add_gzip_assets(counting, 1024);
+
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        assert!(fs.stat(&path("/app/public/style.css"), &mut stat).is_some());
+        let mut dir = fs.opendir(&path("/app/public")).unwrap();
+        loop {
+            let entry = fs.readdir(&mut dir).unwrap();
+            if entry.is_null() {
+                break;
+            }
+            unsafe { drop(Box::from_raw(entry)) };
+        }
+        fs.closedir(&dir).unwrap();
+        assert_eq!(RUNS.load(Ordering::Relaxed), 0);
+
+        assert_eq!(read_all(&fs, "/app/public/style.css"), b"xxxxyyyyzzzz");
+        assert_eq!(RUNS.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,351 @@
+//! Images of embedded files stored in a separate file and mapped at startup, rather
+//! than linked into the binary as the `PATHS`/`FILES` symbols.
+//!
+//! An image holds the same tables as the symbols, after a header:
+//!
+//! | bytes | contents                                              |
+//! |-------|-------------------------------------------------------|
+//! | 8     | [`IMAGE_MAGIC`]                                       |
+//! | 4     | [`KOMPO_STORAGE_VERSION`], little endian              |
+//! | 8     | number of files, little endian                        |
+//! | 8     | length of the paths table, little endian              |
+//! |       | NUL-terminated paths, one per file                    |
+//! |       | offset of each file's contents, plus the end, as u64s |
+//! |       | contents of every file, concatenated in path order    |
+//!
+//! An empty directory is stored as its path followed by a `/`, with no contents.
+
+use std::ffi::OsStr;
+use std::io::{self, Write};
+use std::ops::Deref;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+use trie_rs::map::TrieBuilder;
+
+use crate::FsConfig;
+
+use crate::{Fs, KOMPO_STORAGE_VERSION};
+
+/// Marks the start of an image.
+pub const IMAGE_MAGIC: [u8; 8] = *b"KOMPOIMG";
+
+const HEADER_SIZE: usize = 8 + 4 + 8 + 8;
+
+/// An [`Fs`] over an image mapped by [`Fs::open_image`]. The mapping is leaked:
+/// dropping a `MappedFs` frees the `Fs` but never unmaps the image, since the
+/// contents the `Fs` hands out borrow it for `'static` and may outlive it. Each
+/// call to `open_image` therefore keeps its image mapped until the process exits.
+#[derive(Debug)]
+pub struct MappedFs {
+    fs: Fs<'static>,
+    image: &'static [u8],
+}
+
+impl MappedFs {
+    /// The mapped image.
+    pub fn image(&self) -> &'static [u8] {
+        self.image
+    }
+}
+
+impl Deref for MappedFs {
+    type Target = Fs<'static>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.fs
+    }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "truncated kompo image")
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Splits `len` bytes off the front of `image`.
+fn take<'a>(image: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if image.len() < len {
+        return Err(truncated());
+    }
+
+    let (head, rest) = image.split_at(len);
+    *image = rest;
+    Ok(head)
+}
+
+fn take_u64(image: &mut &[u8]) -> io::Result<u64> {
+    Ok(u64::from_le_bytes(take(image, 8)?.try_into().unwrap()))
+}
+
+fn take_len(image: &mut &[u8]) -> io::Result<usize> {
+    usize::try_from(take_u64(image)?).map_err(|_| truncated())
+}
+
+// The files of an image and its empty directories.
+type ImageContents<'a> = (TrieBuilder<&'a OsStr, &'a [u8]>, Vec<Vec<&'a OsStr>>);
+
+// Builds the trie and the empty directories over `image`, checking the tables
+// against each other.
+fn parse_image(mut image: &[u8]) -> io::Result<ImageContents<'_>> {
+    if take(&mut image, IMAGE_MAGIC.len())? != IMAGE_MAGIC {
+        return Err(invalid("not a kompo image".to_string()));
+    }
+
+    let version = u32::from_le_bytes(take(&mut image, 4)?.try_into().unwrap());
+    if version != KOMPO_STORAGE_VERSION {
+        return Err(invalid(format!(
+            "kompo image version {version} is not the supported version {KOMPO_STORAGE_VERSION}"
+        )));
+    }
+
+    let count = take_len(&mut image)?;
+    let paths_len = take_len(&mut image)?;
+    let paths = take(&mut image, paths_len)?;
+    let offsets_len = count
+        .checked_add(1)
+        .and_then(|n| n.checked_mul(8))
+        .ok_or_else(truncated)?;
+    let mut offsets = take(&mut image, offsets_len)?;
+    let files = image;
+
+    if paths.last().is_some_and(|b| *b != b'\0') {
+        return Err(invalid(
+            "paths of the kompo image are not NUL-terminated".to_string(),
+        ));
+    }
+    let paths = paths.split(|b| *b == b'\0').take(count).collect::<Vec<_>>();
+    if paths.len() != count || paths_len != paths.iter().map(|p| p.len() + 1).sum::<usize>() {
+        return Err(invalid(format!("kompo image does not hold {count} paths")));
+    }
+
+    let offsets = (0..=count)
+        .map(|_| take_len(&mut offsets))
+        .collect::<io::Result<Vec<_>>>()?;
+    let is_consecutive = offsets[0] == 0
+        && offsets.windows(2).all(|w| w[0] <= w[1])
+        && offsets[count] == files.len();
+    if !is_consecutive {
+        return Err(truncated());
+    }
+
+    let mut builder = TrieBuilder::new();
+    let mut empty_dirs = Vec::new();
+    for (i, path) in paths.into_iter().enumerate() {
+        let is_dir = path.ends_with(b"/");
+        let path = Fs::path_components(Path::new(OsStr::from_bytes(path)));
+        if is_dir {
+            empty_dirs.push(path);
+        } else {
+            builder.push(path, &files[offsets[i]..offsets[i + 1]]);
+        }
+    }
+
+    Ok((builder, empty_dirs))
+}
+
+impl<'a> Fs<'a> {
+    /// Writes every embedded file as an image that [`Fs::open_image`] loads. Fails
+    /// with `InvalidInput` if a path holds a NUL byte.
+    pub fn write_image(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut entries = self
+            .entries()
+            .into_iter()
+            .map(|(path, file)| (path.into_os_string().into_vec(), file))
+            .collect::<Vec<_>>();
+        for dir in self.empty_dirs() {
+            let mut path = dir.into_os_string().into_vec();
+            path.push(b'/');
+            entries.push((path, &[]));
+        }
+
+        let mut paths = Vec::new();
+        for (path, _) in &entries {
+            if path.contains(&b'\0') {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "path holds a NUL byte",
+                ));
+            }
+            paths.extend_from_slice(path);
+            paths.push(b'\0');
+        }
+
+        out.write_all(&IMAGE_MAGIC)?;
+        out.write_all(&KOMPO_STORAGE_VERSION.to_le_bytes())?;
+        out.write_all(&(entries.len() as u64).to_le_bytes())?;
+        out.write_all(&(paths.len() as u64).to_le_bytes())?;
+        out.write_all(&paths)?;
+
+        let mut offset = 0u64;
+        out.write_all(&offset.to_le_bytes())?;
+        for (_, file) in &entries {
+            offset += file.len() as u64;
+            out.write_all(&offset.to_le_bytes())?;
+        }
+        for (_, file) in &entries {
+            out.write_all(file)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Fs<'static> {
+    /// Maps the image at `path`, as written by [`Fs::write_image`], and builds an
+    /// `Fs` borrowing its contents from the mapping. Fails with `InvalidData` for a
+    /// file that is not an image or has another [`KOMPO_STORAGE_VERSION`], and with
+    /// `UnexpectedEof` for a truncated one.
+    ///
+    /// The mapping is never unmapped (see [`MappedFs`]), so this suits loading an
+    /// image once at startup rather than opening images repeatedly.
+    pub fn open_image(path: &Path) -> io::Result<MappedFs> {
+        use std::os::fd::AsRawFd;
+
+        let file = std::fs::File::open(path)?;
+        let len = usize::try_from(file.metadata()?.len()).map_err(|_| truncated())?;
+        if len < HEADER_SIZE {
+            return Err(truncated());
+        }
+
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: the mapping is never unmapped, except below when it goes unused.
+        let image: &'static [u8] = unsafe { std::slice::from_raw_parts(ptr as *const u8, len) };
+        match parse_image(image) {
+            Ok((builder, empty_dirs)) => Ok(MappedFs {
+                fs: Fs::with_empty_dirs(builder, &empty_dirs, FsConfig::default()),
+                image,
+            }),
+            Err(err) => {
+                unsafe { libc::munmap(ptr, len) };
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("kompo_image_{}_{name}", std::process::id()))
+    }
+
+    fn test_files() -> Vec<(Vec<u8>, Vec<u8>)> {
+        vec![
+            (b"/app/main.rb".to_vec(), b"puts 'hello'".to_vec()),
+            (b"/app/lib/util.rb".to_vec(), b"module Util; end".to_vec()),
+            (b"/app/empty.rb".to_vec(), Vec::new()),
+        ]
+    }
+
+    fn write_test_image(name: &str) -> (std::path::PathBuf, Vec<u8>) {
+        let files = test_files();
+        let mut image = Vec::new();
+        Fs::from_bytes(&files).write_image(&mut image).unwrap();
+
+        let path = image_path(name);
+        std::fs::write(&path, &image).unwrap();
+        (path, image)
+    }
+
+    #[test]
+    fn test_open_image() {
+        let (path, _) = write_test_image("roundtrip");
+        let fs = Fs::open_image(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut entries = fs
+            .entries()
+            .into_iter()
+            .map(|(path, file)| (path.into_os_string().into_vec(), file.to_vec()))
+            .collect::<Vec<_>>();
+        let mut expected = test_files();
+        entries.sort();
+        expected.sort();
+        assert_eq!(entries, expected);
+
+        let main = vec![OsStr::new("/"), OsStr::new("app"), OsStr::new("main.rb")];
+        let fd = fs.open(&main, libc::O_RDONLY).unwrap();
+        let mut buf = [0u8; 32];
+        assert_eq!(fs.read(fd, &mut buf), Some(12));
+        assert_eq!(&buf[..12], b"puts 'hello'");
+        fs.close(fd).unwrap();
+
+        assert_eq!(
+            fs.list_dir(&vec![OsStr::new("/"), OsStr::new("app")]),
+            Ok(["empty.rb", "lib", "main.rb"].map(Into::into).to_vec())
+        );
+    }
+
+    #[test]
+    fn test_open_image_empty_dir() {
+        let files = test_files();
+        let mut builder = crate::FsBuilder::default();
+        for (path, file) in &files {
+            builder
+                .push(
+                    Fs::path_components(Path::new(OsStr::from_bytes(path))),
+                    file,
+                )
+                .unwrap();
+        }
+        builder.push_dir(Fs::path_components(Path::new("/app/tmp")));
+        let mut image = Vec::new();
+        builder.build().write_image(&mut image).unwrap();
+
+        let path = image_path("empty_dir");
+        std::fs::write(&path, &image).unwrap();
+        let fs = Fs::open_image(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(fs.empty_dirs(), vec![std::path::PathBuf::from("/app/tmp")]);
+        assert_eq!(fs.entries().len(), files.len());
+        let tmp = vec![OsStr::new("/"), OsStr::new("app"), OsStr::new("tmp")];
+        assert_eq!(fs.list_dir(&tmp), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_open_image_truncated() {
+        let (path, image) = write_test_image("truncated");
+
+        for len in [0, HEADER_SIZE - 1, HEADER_SIZE + 4, image.len() - 1] {
+            std::fs::write(&path, &image[..len]).unwrap();
+            let err = Fs::open_image(&path).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{len}");
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_image_version_mismatch() {
+        let (path, mut image) = write_test_image("version");
+
+        image[8..12].copy_from_slice(&(KOMPO_STORAGE_VERSION + 1).to_le_bytes());
+        std::fs::write(&path, &image).unwrap();
+        let err = Fs::open_image(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        image[0] = b'X';
+        std::fs::write(&path, &image).unwrap();
+        let err = Fs::open_image(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
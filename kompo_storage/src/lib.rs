@@ -1,1070 +1,4156 @@
 use rustc_hash::FxHasher;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::ffi::OsStr;
 use std::ffi::OsString;
 use std::hash::Hash;
 use std::hash::Hasher;
+use std::ops::Deref;
 use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::sync::RwLock;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use trie_rs::map::Trie;
 use trie_rs::map::TrieBuilder;
 
-#[derive(Debug, PartialEq)]
+mod builder;
+mod decompress_cache;
+mod gzip_assets;
+mod image;
+
+pub use builder::{ConflictPolicy, DuplicatePath, FsBuilder};
+pub use decompress_cache::{Decompress, DecompressCache};
+pub use image::{IMAGE_MAGIC, MappedFs};
+
+/// Version of this crate's storage format and API.
+pub const KOMPO_STORAGE_VERSION: u32 = 1;
+/// Bumped whenever a change breaks objects built against an older `kompo_storage`,
+/// such as changing the `FileType` variants.
+pub const KOMPO_STORAGE_ABI_VERSION: u32 = 1;
+
+/// Returns the [`KOMPO_STORAGE_ABI_VERSION`] this object was built with, so that
+/// callers linked against it can detect version skew.
+#[unsafe(no_mangle)]
+pub extern "C" fn kompo_storage_abi_version() -> u32 {
+    KOMPO_STORAGE_ABI_VERSION
+}
+
+/// `f_type` that `statfs` reports for the VFS on Linux, "KOMP" in ASCII.
+#[cfg(target_os = "linux")]
+pub const KOMPO_FS_MAGIC: u32 = 0x4b4f_4d50;
+/// `f_fstypename` that `statfs` reports for the VFS on macOS and FreeBSD.
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+pub const KOMPO_FS_TYPENAME: &str = "kompo";
+
+/// Hash of embedded data (XXH3, 64 bit), which the packer stores alongside the
+/// blob so that it can be checked for modification at startup.
+pub fn content_hash(data: &[u8]) -> u64 {
+    xxhash_rust::xxh3::xxh3_64(data)
+}
+
+/// Returns whether `data` still matches the hash the packer recorded for it.
+pub fn verify_content_hash(data: &[u8], expected: u64) -> bool {
+    content_hash(data) == expected
+}
+
+// A single read never copies more than `isize::MAX` bytes, so the count returned as
+// `isize` stays positive even on 32-bit targets.
+fn capped_read_size(remaining: usize, buf_len: usize) -> usize {
+    remaining.min(buf_len).min(isize::MAX as usize)
+}
+
+// `off_t` is 32 bits on some 32-bit targets, where larger sizes saturate.
+fn file_size_as_off_t(len: usize) -> libc::off_t {
+    libc::off_t::try_from(len).unwrap_or(libc::off_t::MAX)
+}
+
+/// Read position of an open file. It is atomic so that `read` only needs shared
+/// access to the fd table; a clone starts from a snapshot of the position.
+#[derive(Debug, Default)]
+struct FileOffset(AtomicU64);
+
+impl FileOffset {
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Acquire)
+    }
+
+    fn set(&self, pos: u64) {
+        self.0.store(pos, Ordering::Release);
+    }
+
+    // Moves the position past the next `len` bytes of a file of `file_len` bytes and
+    // returns where they start and how many there are. Like the kernel's, the range
+    // is claimed atomically, so concurrent reads of one fd neither overlap nor skip.
+    fn claim(&self, len: usize, file_len: usize) -> (usize, usize) {
+        let mut current = self.0.load(Ordering::Acquire);
+        loop {
+            let start = (current as usize).min(file_len);
+            let size = capped_read_size(file_len - start, len);
+            match self.0.compare_exchange_weak(
+                current,
+                (start + size) as u64,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return (start, size),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+impl Clone for FileOffset {
+    fn clone(&self) -> Self {
+        Self(AtomicU64::new(self.get()))
+    }
+}
+
+impl PartialEq for FileOffset {
+    fn eq(&self, other: &Self) -> bool {
+        self.get() == other.get()
+    }
+}
+
+/// Contents of an open file: borrowed from the embedded image, or produced by the
+/// generator of a virtual file when it was opened.
+#[derive(Debug, Clone, PartialEq)]
+enum FileData<'a> {
+    Embedded(&'a [u8]),
+    Generated(Arc<[u8]>),
+}
+
+impl Deref for FileData<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            FileData::Embedded(data) => data,
+            FileData::Generated(data) => data,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 enum FileType<'a> {
     File {
-        file: &'a [u8],
-        offset: u64,
+        file: FileData<'a>,
+        offset: FileOffset,
         inode: u64,
     },
     Directory {
         inode: u64,
-        entries: Vec<Vec<OsString>>,
+        entries: Arc<[Vec<&'a OsStr>]>,
+    },
+    // A file registered with `add_virtual`, whose contents are only generated when
+    // it is opened.
+    Virtual {
+        file: Arc<VirtualFile>,
+        inode: u64,
     },
 }
 
+// A file or directory open in the VFS, with the path it was opened at and its stat.
+// Both are built once per open rather than by every lookup.
+#[derive(Debug)]
+struct OpenFile<'a> {
+    file_type: FileType<'a>,
+    path: Arc<[OsString]>,
+    stat: libc::stat,
+}
+
+/// The platform-independent parts of a directory entry, from which `create_dirent`
+/// fills in the platform's `libc::dirent`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DirentRecord<'a> {
+    inode: u64,
+    name: &'a [u8],
+    d_type: u8,
+    reclen: u16,
+}
+
+impl<'a> DirentRecord<'a> {
+    /// `name_offset` and `name_capacity` are where the name starts in the platform's
+    /// `dirent` and how many bytes it can hold, including the terminating NUL.
+    /// Like the kernel's, `reclen` covers the name and its NUL, rounded up to 8 bytes.
+    fn new(
+        inode: u64,
+        d_type: u8,
+        name: &'a [u8],
+        name_offset: usize,
+        name_capacity: usize,
+    ) -> Result<Self, FsError> {
+        if name.len() >= name_capacity {
+            return Err(FsError::NameTooLong);
+        }
+
+        Ok(Self {
+            inode,
+            name,
+            d_type,
+            reclen: (name_offset + name.len() + 1).next_multiple_of(8) as u16,
+        })
+    }
+}
+
+// A child of a directory as it was when the directory was opened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DirSnapshotEntry {
+    name: OsString,
+    inode: u64,
+    d_type: u8,
+}
+
 #[derive(Debug)]
 pub struct FsDir {
     pub fd: i32,
     offset: u64,
+    // Children sorted by name, taken at `opendir`/`fdopendir` so that `readdir`
+    // neither looks them up again nor observes later changes mid-iteration.
+    entries: Arc<[DirSnapshotEntry]>,
 }
 
-#[derive(Debug)]
-pub struct Fs<'a> {
-    trie: Trie<&'a OsStr, &'a [u8]>,
-    fd_map: RwLock<HashMap<i32, FileType<'a>>>,
+impl FsDir {
+    /// Returns the names of the entries `readdir` yields, in order, as they were
+    /// when the directory was opened.
+    pub fn entry_names(&self) -> impl Iterator<Item = &OsStr> {
+        self.entries.iter().map(|entry| entry.name.as_os_str())
+    }
+
+    /// Returns the full paths of the entries in this directory, other than `.` and
+    /// `..`, without going through `readdir`. The entries are shared with `fs`, so
+    /// this does not copy them, but they are handed out as an `Arc` since the
+    /// `fd_map` lock cannot be held by the caller. Returns `None` if the directory
+    /// has been closed.
+    pub fn entry_paths<'a>(&self, fs: &Fs<'a>) -> Option<Arc<[Vec<&'a OsStr>]>> {
+        match fs
+            .fd_map
+            .read()
+            .unwrap()
+            .get(&self.fd)
+            .map(|open| &open.file_type)
+        {
+            Some(FileType::Directory { entries, .. }) => Some(Arc::clone(entries)),
+            _ => None,
+        }
+    }
 }
 
-#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
-type DirEntryName = [u8; 256];
-#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
-fn convert_byte(b: &u8) -> u8 {
-    *b
+/// Errors returned by the [`Fs`] operations that report why they failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    /// The path does not exist in the VFS.
+    NotFound,
+    /// A directory was required but the path is not one.
+    NotDirectory,
+    /// A name does not fit in the platform's `dirent`.
+    NameTooLong,
+    /// The fd is not open in the VFS.
+    BadFd,
+    /// The contents of a virtual file could not be produced.
+    Io,
 }
 
-#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
-type DirEntryName = [i8; 256];
-#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
-fn convert_byte(b: &u8) -> i8 {
-    *b as i8
+impl FsError {
+    /// The `errno` value matching this error.
+    pub fn errno(&self) -> i32 {
+        match self {
+            FsError::NotFound => libc::ENOENT,
+            FsError::NotDirectory => libc::ENOTDIR,
+            FsError::NameTooLong => libc::ENAMETOOLONG,
+            FsError::BadFd => libc::EBADF,
+            FsError::Io => libc::EIO,
+        }
+    }
 }
 
-#[cfg(target_os = "macos")]
-type DirEntryName = [i8; 1024];
-#[cfg(target_os = "macos")]
-fn convert_byte(b: &u8) -> i8 {
-    *b as i8
+impl std::fmt::Display for FsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FsError::NotFound => write!(f, "no such file or directory"),
+            FsError::NotDirectory => write!(f, "not a directory"),
+            FsError::NameTooLong => write!(f, "file name too long"),
+            FsError::BadFd => write!(f, "bad file descriptor"),
+            FsError::Io => write!(f, "input/output error"),
+        }
+    }
 }
 
-impl<'a> Fs<'a> {
-    const DEV: libc::dev_t = libc::makedev(2222, 0); // create fake device number. TODO: get unused device number dynamically.
+impl std::error::Error for FsError {}
+
+/// Options applied when constructing an [`Fs`].
+#[derive(Debug, Clone, Copy)]
+pub struct FsConfig {
+    /// Value reported as `st_blksize` for every file and directory.
+    pub blksize: libc::blksize_t,
+    /// Whether to hash every file up front so [`Fs::checksum`] can report it.
+    pub checksums: bool,
+    /// Owner reported as `st_uid` for every file and directory.
+    pub uid: libc::uid_t,
+    /// Group reported as `st_gid` for every file and directory.
+    pub gid: libc::gid_t,
+}
 
-    pub fn new(builder: TrieBuilder<&'static OsStr, &'static [u8]>) -> Self {
-        let trie = builder.build();
+impl Default for FsConfig {
+    /// Files are owned by the user running the process, looked up once here
+    /// rather than on every `stat`.
+    fn default() -> Self {
+        Self {
+            blksize: 4096,
+            checksums: false,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        }
+    }
+}
 
+/// Number of shards in a [`StatCache`].
+pub const STAT_CACHE_SHARDS: usize = 64;
+
+/// The fields of a VFS `stat` that differ between paths, a fraction of the size of
+/// a whole `libc::stat`. [`Fs::stat_from_cached`] fills in the rest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CachedStat {
+    pub ino: u64,
+    pub size: libc::off_t,
+    pub mode: libc::mode_t,
+    pub nlink: libc::nlink_t,
+}
+
+impl CachedStat {
+    pub fn from_stat(stat: &libc::stat) -> Self {
         Self {
-            trie,
-            fd_map: RwLock::new(HashMap::new()),
+            ino: stat.st_ino,
+            size: stat.st_size,
+            mode: stat.st_mode,
+            nlink: stat.st_nlink,
         }
     }
+}
+
+/// A stat cache split into independently locked shards, so lookups of
+/// different paths from many threads rarely wait on the same lock.
+#[derive(Debug)]
+pub struct StatCache {
+    shards: [RwLock<HashMap<Vec<OsString>, CachedStat>>; STAT_CACHE_SHARDS],
+}
 
-    pub fn entries(&self) {
-        let hoge: Vec<(OsString, &&[u8])> = self.trie.iter().collect();
-        dbg!(hoge);
+impl Default for StatCache {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    fn get_inode_from_path(&self, path: &Vec<&OsStr>) -> u64 {
+impl StatCache {
+    pub fn new() -> Self {
+        Self {
+            shards: std::array::from_fn(|_| RwLock::new(HashMap::new())),
+        }
+    }
+
+    fn shard(&self, path: &[OsString]) -> &RwLock<HashMap<Vec<OsString>, CachedStat>> {
         let mut hasher = FxHasher::default();
         path.hash(&mut hasher);
 
-        hasher.finish()
+        &self.shards[hasher.finish() as usize % STAT_CACHE_SHARDS]
     }
 
-    fn get_file_type_from_path(&self, search_path: &Vec<&OsStr>) -> Option<FileType<'a>> {
-        if let Some(file) = self.trie.exact_match(search_path) {
-            let inode = self.get_inode_from_path(search_path);
+    pub fn get(&self, path: &[OsString]) -> Option<CachedStat> {
+        self.shard(path).read().unwrap().get(path).copied()
+    }
 
-            return Some(FileType::File {
-                file,
-                offset: 0,
-                inode,
-            });
+    pub fn insert(&self, path: Vec<OsString>, stat: CachedStat) {
+        self.shard(&path).write().unwrap().insert(path, stat);
+    }
+
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            shard.write().unwrap().clear();
         }
+    }
+}
 
-        let depth = search_path.len() + 1;
-        let mut seen_entries = HashSet::new();
+// A child in the directory index.
+#[derive(Debug, Clone)]
+struct DirIndexEntry<'a> {
+    name: &'a OsStr,
+    is_dir: bool,
+    inode: u64,
+}
 
-        let entries: Vec<_> = self
-            .trie
-            .predictive_search(search_path)
-            .filter_map(|(path, _): (Vec<&OsStr>, _)| {
-                if path.len() >= depth {
-                    let next_depth_path: Vec<OsString> =
-                        path.iter().take(depth).map(|&s| s.to_os_string()).collect();
-
-                    if seen_entries.contains(&next_depth_path) {
-                        None
-                    } else {
-                        seen_entries.insert(next_depth_path.clone());
-                        Some(next_depth_path)
-                    }
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<Vec<OsString>>>();
+// Most entries of `open_templates` a process keeps before it starts over, so the
+// map doesn't grow with every distinct path ever opened.
+const OPEN_TEMPLATES_MAX: usize = 4096;
 
-        if !entries.is_empty() {
-            // dbg!(&search_path);
-            let inode = self.get_inode_from_path(search_path);
+// A path resolved by `open`, kept in `open_templates` under the hash of `path`.
+#[derive(Debug)]
+struct OpenTemplate<'a> {
+    path: Box<[OsString]>,
+    file_type: FileType<'a>,
+    stat: CachedStat,
+}
 
-            return Some(FileType::Directory { inode, entries });
-        }
+/// Produces the contents of a virtual file each time it is opened.
+pub type FsGenerator = Box<dyn Fn() -> Vec<u8> + Send + Sync>;
 
-        None
-    }
+// Produces the contents of a virtual file, or `None` if they can't be, which fails
+// the open with EIO.
+pub(crate) type VirtualGenerator = Box<dyn Fn() -> Option<Arc<[u8]>> + Send + Sync>;
 
-    pub fn is_fd_exists(&self, fd: i32) -> bool {
-        self.fd_map.read().unwrap().contains_key(&fd)
-    }
+struct VirtualFile {
+    generator: VirtualGenerator,
+    // What `stat` on its path reports before it is generated
+    size: libc::off_t,
+}
 
-    pub fn is_dir_exists(&self, dir: &FsDir) -> bool {
-        self.is_fd_exists(dir.fd)
+impl std::fmt::Debug for VirtualFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VirtualFile")
+            .field("size", &self.size)
+            .finish_non_exhaustive()
     }
+}
 
-    pub fn is_dir_exists_from_path(&self, path: &Vec<&OsStr>) -> bool {
-        matches!(
-            self.get_file_type_from_path(path),
-            Some(FileType::Directory { .. })
-        )
+// Generators can't be compared, so a virtual file only equals itself.
+impl PartialEq for VirtualFile {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
     }
+}
 
-    fn get_stat_from_file_type(&self, file_type: &FileType) -> libc::stat {
-        let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
-        let stat_ptr = stat.as_mut_ptr();
+// Files registered with `add_virtual`, by path and by parent directory, so neither
+// lookups nor listings scan them all.
+#[derive(Debug, Default)]
+struct VirtualFiles {
+    files: HashMap<Vec<&'static OsStr>, Arc<VirtualFile>>,
+    // Every registered path and all of its prefixes, for `exists_any_prefix`
+    prefixes: HashSet<Vec<&'static OsStr>>,
+    // Paths directly under every proper prefix of a registered path
+    children: HashMap<Vec<&'static OsStr>, Vec<Vec<&'static OsStr>>>,
+    // Every component of a registered path, so registering a path again leaks nothing.
+    names: HashSet<&'static OsStr>,
+}
 
-        unsafe {
-            match file_type {
-                FileType::File { file, inode, .. } => {
-                    (*stat_ptr).st_dev = Self::DEV;
-                    (*stat_ptr).st_ino = *inode;
-                    (*stat_ptr).st_mode = libc::S_IFREG // 444
-                                    | libc::S_IRUSR
-                                    | libc::S_IRGRP
-                                    | libc::S_IROTH;
-                    (*stat_ptr).st_nlink = 1;
-                    (*stat_ptr).st_uid = libc::getuid();
-                    (*stat_ptr).st_gid = libc::getgid();
-                    (*stat_ptr).st_rdev = 0;
-                    (*stat_ptr).st_size = file.len() as _;
-                    (*stat_ptr).st_blksize = 4096;
-                    (*stat_ptr).st_blocks = (file.len().div_ceil(512).div_ceil(8) * 8) as i64;
-                    (*stat_ptr).st_atime = 0;
-                    (*stat_ptr).st_atime_nsec = 0;
-                    (*stat_ptr).st_mtime = 0;
-                    (*stat_ptr).st_mtime_nsec = 0;
-                    (*stat_ptr).st_ctime = 0;
-                    (*stat_ptr).st_ctime_nsec = 0;
-
-                    stat.assume_init()
-                }
-                FileType::Directory { inode, .. } => {
-                    (*stat_ptr).st_dev = Self::DEV;
-                    (*stat_ptr).st_ino = *inode;
-                    (*stat_ptr).st_mode = libc::S_IFDIR // 555
-                                    | libc::S_IXUSR
-                                    | libc::S_IRUSR
-                                    | libc::S_IXGRP
-                                    | libc::S_IRGRP
-                                    | libc::S_IXOTH
-                                    | libc::S_IROTH;
-                    (*stat_ptr).st_nlink = 1;
-                    (*stat_ptr).st_uid = libc::getuid();
-                    (*stat_ptr).st_gid = libc::getgid();
-                    (*stat_ptr).st_rdev = 0;
-                    (*stat_ptr).st_size = 1;
-                    (*stat_ptr).st_blksize = 4096;
-                    (*stat_ptr).st_blocks = 0;
-                    (*stat_ptr).st_atime = 0;
-                    (*stat_ptr).st_atime_nsec = 0;
-                    (*stat_ptr).st_mtime = 0;
-                    (*stat_ptr).st_mtime_nsec = 0;
-                    (*stat_ptr).st_ctime = 0;
-                    (*stat_ptr).st_ctime_nsec = 0;
-
-                    stat.assume_init()
-                }
+impl VirtualFiles {
+    fn insert(&mut self, path: &[&OsStr], file: VirtualFile) {
+        let path = path.iter().map(|s| self.intern(s)).collect::<Vec<_>>();
+        self.prefixes.insert(Vec::new());
+        for depth in 1..=path.len() {
+            if self.prefixes.insert(path[..depth].to_vec()) {
+                self.children
+                    .entry(path[..depth - 1].to_vec())
+                    .or_default()
+                    .push(path[..depth].to_vec());
             }
         }
+        self.files.insert(path, Arc::new(file));
     }
 
-    pub fn open(&self, path: &Vec<&OsStr>) -> Option<i32> {
-        let file_type = self.get_file_type_from_path(path)?;
-        let fd = unsafe { libc::dup(0) };
-        self.fd_map.write().unwrap().insert(fd, file_type);
-
-        Some(fd)
+    // Registrations live as long as the process, like the embedded paths, so each
+    // distinct name is leaked once.
+    fn intern(&mut self, name: &OsStr) -> &'static OsStr {
+        if let Some(name) = self.names.get(name) {
+            return name;
+        }
+        let name: &'static OsStr = Box::leak(name.to_os_string().into_boxed_os_str());
+        self.names.insert(name);
+        name
     }
+}
 
-    pub fn open_at(&self, path: &Vec<&OsStr>) -> Option<i32> {
-        let file_type = self.get_file_type_from_path(path)?;
-        let fd = unsafe { libc::dup(0) };
-        self.fd_map.write().unwrap().insert(fd, file_type);
+/// Callback invoked with the fd and path of every successful [`Fs::open`].
+pub type FsOpenHook = Box<dyn Fn(i32, &[OsString]) + Send + Sync>;
 
-        Some(fd)
+type SharedOpenHook = Arc<dyn Fn(i32, &[OsString]) + Send + Sync>;
+
+// Held as an `Arc` so a hook runs without the lock, free to set or clear itself.
+#[derive(Default)]
+struct OpenHookSlot(RwLock<Option<SharedOpenHook>>);
+
+impl std::fmt::Debug for OpenHookSlot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let is_set = self.0.read().map(|hook| hook.is_some()).unwrap_or(false);
+        f.debug_tuple("OpenHookSlot").field(&is_set).finish()
     }
+}
 
-    pub fn read(&self, fd: i32, buf: &mut [u8]) -> Option<isize> {
-        let mut fd_map = self.fd_map.write().unwrap();
-        match fd_map.get_mut(&fd) {
-            Some(file_type) => match file_type {
-                FileType::File { file, offset, .. } => {
-                    if *offset == file.len() as u64 {
-                        return Some(0);
-                    }
+#[derive(Debug)]
+pub struct Fs<'a> {
+    trie: Trie<&'a OsStr, &'a [u8]>,
+    fd_map: RwLock<HashMap<i32, OpenFile<'a>>>,
+    config: FsConfig,
+    // The last path resolved by `stat` and its stat, so the `open` that usually
+    // follows it (as in `require`) can skip walking the trie and building the stat
+    // again.
+    last_stat: Mutex<Option<(Vec<OsString>, FileType<'a>, CachedStat)>>,
+    // Paths resolved by `open` and their stat by path hash, cloned into `fd_map` on
+    // later opens of the same path. The trie never changes, so entries
+    // never go stale. Holds at most `OPEN_TEMPLATES_MAX` paths.
+    open_templates: RwLock<HashMap<u64, OpenTemplate<'a>>>,
+    total_size: std::sync::OnceLock<u64>,
+    // Immediate children of every embedded directory. The trie itself can only
+    // list whole subtrees, which made directory lookups cost as much as the number
+    // of files below them.
+    dir_index: HashMap<Vec<&'a OsStr>, Vec<DirIndexEntry<'a>>>,
+    // Hashes of every embedded file and directory path, so most missing paths are
+    // turned away without walking the trie.
+    path_hashes: HashSet<u64>,
+    // Embedded paths holding non-ASCII components, keyed by their NFC form, so a
+    // lookup composed differently than the packer's file system still finds them.
+    #[cfg(feature = "unicode-normalization")]
+    normalized_paths: HashMap<Vec<String>, Vec<&'a OsStr>>,
+    // Path of every embedded file and directory keyed by inode, built on the first
+    // `path_for_inode` since few programs ever ask.
+    inode_paths: std::sync::OnceLock<HashMap<u64, Vec<&'a OsStr>>>,
+    // Number of paths linked to every inode shared by more than one. Paths embedding
+    // the very same bytes (same address and length) are hard links and share one
+    // inode.
+    hardlinks: HashMap<u64, u32>,
+    // Inodes of paths that are hard links keyed by the path's hash, which their
+    // inode differs from.
+    link_inodes: HashMap<u64, u64>,
+    // `content_hash` of every file keyed by inode, if `FsConfig::checksums` is set.
+    checksums: HashMap<u64, u64>,
+    open_hook: OpenHookSlot,
+    // Files registered with `add_virtual`.
+    virtual_files: RwLock<VirtualFiles>,
+    #[cfg(test)]
+    lookup_count: std::sync::atomic::AtomicUsize,
+}
 
-                    let read_size = (file.len() - *offset as usize).min(buf.len());
-                    buf[..read_size]
-                        .copy_from_slice(&file[*offset as usize..*offset as usize + read_size]);
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+compile_error!("kompo_storage supports Linux, macOS and FreeBSD only");
+
+// glibc and musl share the `dirent` layout (d_ino, d_off, d_reclen, d_type and a
+// 256 byte d_name), and FreeBSD's d_name is 256 bytes as well. Only the signedness
+// of `c_char` differs between architectures.
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+type DirEntryName = [libc::c_char; 256];
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+fn convert_byte(b: &u8) -> libc::c_char {
+    *b as libc::c_char
+}
 
-                    *offset += read_size as u64;
+#[cfg(target_os = "macos")]
+type DirEntryName = [i8; 1024];
+#[cfg(target_os = "macos")]
+fn convert_byte(b: &u8) -> i8 {
+    *b as i8
+}
 
-                    Some(read_size as isize)
-                }
-                FileType::Directory { .. } => todo!(),
-            },
-            None => None,
-        }
+impl<'a> Fs<'a> {
+    const DEV: libc::dev_t = libc::makedev(2222, 0); // create fake device number. TODO: get unused device number dynamically.
+
+    pub fn new(builder: TrieBuilder<&'a OsStr, &'a [u8]>) -> Self {
+        Self::with_config(builder, FsConfig::default())
+    }
+
+    pub fn with_config(builder: TrieBuilder<&'a OsStr, &'a [u8]>, config: FsConfig) -> Self {
+        Self::with_empty_dirs(builder, &[], config)
     }
 
-    pub fn close(&self, fd: i32) -> i32 {
-        self.fd_map.write().unwrap().remove(&fd);
+    /// Like [`Fs::with_config`], also embedding the directories in `empty_dirs`.
+    /// The trie only holds files, so a directory with nothing below it has no path
+    /// there. Paths in `empty_dirs` that are embedded files are ignored.
+    pub fn with_empty_dirs(
+        builder: TrieBuilder<&'a OsStr, &'a [u8]>,
+        empty_dirs: &[Vec<&'a OsStr>],
+        config: FsConfig,
+    ) -> Self {
+        let trie = builder.build();
+        let (hardlinks, link_inodes) = Self::build_hardlinks(&trie);
+        let checksums = if config.checksums {
+            Self::build_checksums(&trie, &link_inodes)
+        } else {
+            HashMap::new()
+        };
+        let dir_index = Self::build_dir_index(&trie, &link_inodes, empty_dirs);
+        let path_hashes = Self::build_path_hashes(&dir_index);
+        #[cfg(feature = "unicode-normalization")]
+        let normalized_paths = Self::build_normalized_paths(&dir_index);
 
-        0
+        Self {
+            trie,
+            fd_map: RwLock::new(HashMap::new()),
+            config,
+            last_stat: Mutex::new(None),
+            open_templates: RwLock::new(HashMap::new()),
+            total_size: std::sync::OnceLock::new(),
+            dir_index,
+            path_hashes,
+            #[cfg(feature = "unicode-normalization")]
+            normalized_paths,
+            inode_paths: std::sync::OnceLock::new(),
+            hardlinks,
+            link_inodes,
+            checksums,
+            open_hook: OpenHookSlot::default(),
+            virtual_files: RwLock::default(),
+            #[cfg(test)]
+            lookup_count: std::sync::atomic::AtomicUsize::new(0),
+        }
     }
 
-    pub fn stat(&self, path: &Vec<&OsStr>, stat_buf: &mut libc::stat) -> Option<i32> {
-        match self.get_file_type_from_path(path) {
-            Some(file_type) => {
-                *stat_buf = self.get_stat_from_file_type(&file_type);
-                Some(0)
+    /// Builds an `Fs` from `(path, contents)` pairs, splitting each path into
+    /// components the way lookups do. Paths may hold any bytes, so property tests and
+    /// fuzzers can feed ones the packer never produces. Paths without a component
+    /// are skipped.
+    pub fn from_bytes(files: &'a [(Vec<u8>, Vec<u8>)]) -> Self {
+        let mut builder = TrieBuilder::new();
+        for (path, contents) in files {
+            let path = Self::path_components(Path::new(OsStr::from_bytes(path)));
+            if !path.is_empty() {
+                builder.push(path, contents.as_slice());
             }
-            None => None,
         }
+
+        Self::new(builder)
     }
 
-    pub fn lstat(&self, path: &Vec<&OsStr>, stat_buf: &mut libc::stat) -> Option<i32> {
-        self.stat(path, stat_buf)
+    /// Returns the path and contents of every embedded file. The components are
+    /// joined as a path, since concatenating them would lose the separators.
+    pub fn entries(&self) -> Vec<(PathBuf, &'a [u8])> {
+        self.trie
+            .iter()
+            .map(|(path, file): (Vec<&OsStr>, &&'a [u8])| (path.iter().collect(), *file))
+            .collect()
     }
 
-    #[cfg(target_os = "macos")]
-    pub fn getattrlist(
+    /// Registers a file at `path` whose contents are produced by `generator` every
+    /// time it is opened, rather than embedded. Its parent directories appear in the
+    /// VFS as well. A virtual file shadows an embedded one at `path`.
+    ///
+    /// The generator runs without any lock held, so it may use this `Fs`. Its size is
+    /// only known once it has run, so like a procfs file, `stat` on `path` reports it
+    /// empty while `fstat` on an open fd reports the generated length.
+    pub fn add_virtual(&self, path: &Vec<&OsStr>, generator: FsGenerator) {
+        self.add_virtual_sized(path, 0, Box::new(move || Some(generator().into())));
+    }
+
+    // Like `add_virtual`, for contents whose size is known up front, which `stat` on
+    // `path` then reports. Opening it fails with EIO if `generator` returns `None`.
+    pub(crate) fn add_virtual_sized(
         &self,
-        path: &Vec<&OsStr>,
-        attr_list: &libc::attrlist,
-        attr_buf: *mut libc::c_void,
-        attr_buf_size: usize,
-    ) -> Option<i32> {
-        #[repr(C)]
-        struct AttrBufNameAndObjType {
-            length: u32,
-            name_ref: libc::attrreference_t,
-            obj_type: u32,
-        }
+        path: &[&OsStr],
+        size: libc::off_t,
+        generator: VirtualGenerator,
+    ) {
+        self.virtual_files
+            .write()
+            .unwrap()
+            .insert(path, VirtualFile { generator, size });
+
+        // Directories resolved before the registration may now have a new child.
+        self.open_templates.write().unwrap().clear();
+        self.last_stat.lock().unwrap().take();
+    }
 
-        let file_type = self.get_file_type_from_path(path)?;
+    fn get_virtual_file(&self, search_path: &[&OsStr]) -> Option<Arc<VirtualFile>> {
+        let virtual_files = self.virtual_files.read().unwrap();
+        let files: &HashMap<Vec<&OsStr>, Arc<VirtualFile>> = &virtual_files.files;
+        files.get(search_path).cloned()
+    }
 
-        let obj_type: u32 = match file_type {
-            FileType::File { .. } => 1,      // VREG
-            FileType::Directory { .. } => 2, // VDIR
-        };
+    // Turns a virtual file about to be opened into its generated contents, outside
+    // every lock. Anything else is returned as is.
+    fn generate(
+        &self,
+        file_type: FileType<'a>,
+        stat: CachedStat,
+    ) -> Result<(FileType<'a>, CachedStat), FsError> {
+        match file_type {
+            FileType::Virtual { file, inode } => {
+                let file_type = FileType::File {
+                    file: FileData::Generated((file.generator)().ok_or(FsError::Io)?),
+                    offset: FileOffset::default(),
+                    inode,
+                };
+                let stat = self.get_cached_stat(&file_type);
+                Ok((file_type, stat))
+            }
+            file_type => Ok((file_type, stat)),
+        }
+    }
 
-        let basename = path.last().map(|s| s.as_bytes()).unwrap_or(b"");
-        let basename_len = basename.len() + 1;
+    /// Returns the sum of the lengths of all embedded files.
+    pub fn total_size(&self) -> u64 {
+        *self.total_size.get_or_init(|| {
+            self.trie
+                .iter()
+                .map(|(_, file): (Vec<&OsStr>, &&[u8])| file.len() as u64)
+                .sum()
+        })
+    }
 
-        if attr_list.commonattr != (libc::ATTR_CMN_NAME | libc::ATTR_CMN_OBJTYPE) {
-            return None;
+    /// Asks the kernel to read in the embedded contents of `paths` ahead of use, so
+    /// a burst of `require`s doesn't wait on them one page at a time. Directories,
+    /// virtual files and missing paths are skipped, and the advice is best effort.
+    /// Returns the number of bytes advised.
+    pub fn prefetch(&self, paths: &[&[&OsStr]]) -> usize {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mut advised = 0;
+
+        // Page-aligned ranges, since madvise needs a page-aligned start
+        let mut ranges = paths
+            .iter()
+            .filter_map(|path| self.trie.exact_match(path))
+            .filter(|file| !file.is_empty())
+            .map(|file| {
+                advised += file.len();
+                let start = file.as_ptr() as usize & !(page_size - 1);
+                (start, file.as_ptr() as usize + file.len())
+            })
+            .collect::<Vec<_>>();
+        ranges.sort_unstable();
+
+        // Files are laid out back to back, so a burst of them is usually advised
+        // in a single call.
+        let mut merged: Vec<(usize, usize)> = Vec::new();
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        for (start, end) in merged {
+            unsafe { libc::madvise(start as *mut libc::c_void, end - start, libc::MADV_WILLNEED) };
         }
 
-        let header_size = std::mem::size_of::<AttrBufNameAndObjType>();
-        let total_size = header_size + basename_len;
+        advised
+    }
 
-        if total_size > attr_buf_size {
-            return None;
-        }
+    fn build_hardlinks(trie: &Trie<&'a OsStr, &'a [u8]>) -> (HashMap<u64, u32>, HashMap<u64, u64>) {
+        // The hash of the first path to embed each contents, and how many do
+        let mut by_content: HashMap<(usize, usize), (u64, u32)> = HashMap::new();
+        let mut files = Vec::new();
 
-        let result = AttrBufNameAndObjType {
-            length: total_size as u32,
-            name_ref: libc::attrreference_t {
-                attr_dataoffset: (header_size
-                    - std::mem::offset_of!(AttrBufNameAndObjType, name_ref))
-                    as i32,
-                attr_length: basename_len as u32,
-            },
-            obj_type,
-        };
+        for (path, file) in trie.iter() {
+            let path: Vec<&OsStr> = path;
+            let file: &&[u8] = file;
 
-        unsafe {
-            let buf = attr_buf as *mut AttrBufNameAndObjType;
-            std::ptr::write(buf, result);
+            // Empty slices may all share a dangling address, so never link them.
+            if file.is_empty() {
+                continue;
+            }
+            let content = (file.as_ptr() as usize, file.len());
+            let path_hash = Self::hash_path(&path);
+            by_content.entry(content).or_insert((path_hash, 0)).1 += 1;
+            files.push((content, path_hash));
+        }
 
-            let name_ptr = (attr_buf as *mut u8).add(header_size);
-            std::ptr::copy_nonoverlapping(basename.as_ptr(), name_ptr, basename.len());
-            *name_ptr.add(basename.len()) = 0;
+        let mut hardlinks = HashMap::new();
+        let mut link_inodes = HashMap::new();
+        for (content, path_hash) in files {
+            let (inode, count) = by_content[&content];
+            if count > 1 {
+                hardlinks.insert(inode, count);
+                link_inodes.insert(path_hash, inode);
+            }
         }
 
-        Some(0)
+        (hardlinks, link_inodes)
     }
 
-    pub fn fstat(&self, fd: i32, stat_buf: &mut libc::stat) -> Option<i32> {
-        let fd_map = self.fd_map.read().unwrap();
-        match fd_map.get(&fd) {
-            Some(file_type) => {
-                *stat_buf = self.get_stat_from_file_type(file_type);
-                Some(0)
-            }
-            None => None,
+    fn build_checksums(
+        trie: &Trie<&'a OsStr, &'a [u8]>,
+        link_inodes: &HashMap<u64, u64>,
+    ) -> HashMap<u64, u64> {
+        let mut checksums = HashMap::new();
+
+        for (path, file) in trie.iter() {
+            let path: Vec<&OsStr> = path;
+            let file: &&[u8] = file;
+            let path_hash = Self::hash_path(&path);
+            let inode = link_inodes.get(&path_hash).copied().unwrap_or(path_hash);
+
+            // Hard links share their contents, so each inode is hashed once.
+            checksums.entry(inode).or_insert_with(|| content_hash(file));
         }
+
+        checksums
     }
 
-    pub fn file_read(&self, path: &Vec<&OsStr>) -> Option<*const u8> {
-        let file_type = self
-            .get_file_type_from_path(path)
-            .unwrap_or_else(|| panic!("not found path: {:?}", path));
+    fn build_dir_index(
+        trie: &Trie<&'a OsStr, &'a [u8]>,
+        link_inodes: &HashMap<u64, u64>,
+        empty_dirs: &[Vec<&'a OsStr>],
+    ) -> HashMap<Vec<&'a OsStr>, Vec<DirIndexEntry<'a>>> {
+        let mut index: HashMap<Vec<&'a OsStr>, Vec<DirIndexEntry<'a>>> = HashMap::new();
+        let mut seen = HashSet::new();
+
+        for (path, _) in trie.iter() {
+            let path: Vec<&'a OsStr> = path;
+            for depth in 0..path.len() {
+                let entry_path = &path[..=depth];
+                // Shared prefixes are visited once per file below them
+                if !seen.insert(entry_path.to_vec()) {
+                    continue;
+                }
+
+                let is_dir = depth + 1 < path.len();
+                let path_hash = Self::hash_path(entry_path);
+                let inode = if is_dir {
+                    path_hash
+                } else {
+                    link_inodes.get(&path_hash).copied().unwrap_or(path_hash)
+                };
+
+                index
+                    .entry(path[..depth].to_vec())
+                    .or_default()
+                    .push(DirIndexEntry {
+                        name: path[depth],
+                        is_dir,
+                        inode,
+                    });
+            }
+        }
+
+        for dir in empty_dirs {
+            if dir.is_empty() || trie.exact_match(dir).is_some() {
+                continue;
+            }
+
+            for depth in 0..dir.len() {
+                let entry_path = &dir[..=depth];
+                if !seen.insert(entry_path.to_vec()) {
+                    continue;
+                }
+
+                index
+                    .entry(dir[..depth].to_vec())
+                    .or_default()
+                    .push(DirIndexEntry {
+                        name: dir[depth],
+                        is_dir: true,
+                        inode: Self::hash_path(entry_path),
+                    });
+            }
+            // An empty directory is a key without children
+            index.entry(dir.clone()).or_default();
+        }
+
+        index
+    }
+
+    /// Returns every embedded directory with nothing in it, as passed to
+    /// [`Fs::with_empty_dirs`].
+    pub fn empty_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = self
+            .dir_index
+            .iter()
+            .filter(|(dir, children)| children.is_empty() && !Self::is_root_path(dir))
+            .map(|(dir, _)| dir.iter().collect::<PathBuf>())
+            .collect::<Vec<_>>();
+        dirs.sort();
+
+        dirs
+    }
+
+    fn build_path_hashes(
+        dir_index: &HashMap<Vec<&'a OsStr>, Vec<DirIndexEntry<'a>>>,
+    ) -> HashSet<u64> {
+        let mut path_hashes = HashSet::new();
+
+        for (dir, children) in dir_index {
+            path_hashes.insert(Self::hash_path(dir));
+            for child in children {
+                let mut path = dir.clone();
+                path.push(child.name);
+                path_hashes.insert(Self::hash_path(&path));
+            }
+        }
+
+        path_hashes
+    }
+
+    #[cfg(feature = "unicode-normalization")]
+    fn build_normalized_paths(
+        dir_index: &HashMap<Vec<&'a OsStr>, Vec<DirIndexEntry<'a>>>,
+    ) -> HashMap<Vec<String>, Vec<&'a OsStr>> {
+        let mut normalized_paths = HashMap::new();
+
+        for (dir, children) in dir_index {
+            for child in children {
+                let mut path = dir.clone();
+                path.push(child.name);
+                if path.iter().all(|component| component.is_ascii()) {
+                    continue;
+                }
+                if let Some(normalized) = Self::normalize_path(&path) {
+                    normalized_paths.insert(normalized, path);
+                }
+            }
+        }
+
+        normalized_paths
+    }
+
+    // NFC form of every component, or `None` if one is not UTF-8.
+    #[cfg(feature = "unicode-normalization")]
+    fn normalize_path(path: &[&OsStr]) -> Option<Vec<String>> {
+        use unicode_normalization::UnicodeNormalization;
+
+        path.iter()
+            .map(|component| Some(component.to_str()?.nfc().collect()))
+            .collect()
+    }
+
+    /// Returns the embedded path that `path` names once both are normalized to NFC,
+    /// if `path` itself is not embedded.
+    #[cfg(feature = "unicode-normalization")]
+    fn normalized_match(&self, path: &[&OsStr]) -> Option<&Vec<&'a OsStr>> {
+        if self.normalized_paths.is_empty() || path.iter().all(|c| c.is_ascii()) {
+            return None;
+        }
+
+        self.normalized_paths.get(&Self::normalize_path(path)?)
+    }
+
+    fn build_inode_paths(&self) -> HashMap<u64, Vec<&'a OsStr>> {
+        let mut inode_paths: HashMap<u64, Vec<&'a OsStr>> = HashMap::new();
+
+        for (dir, children) in &self.dir_index {
+            for child in children {
+                let mut path = dir.clone();
+                path.push(child.name);
+                // Hard links share an inode; report the same one of them every time
+                match inode_paths.entry(child.inode) {
+                    std::collections::hash_map::Entry::Occupied(mut entry) => {
+                        if path < *entry.get() {
+                            entry.insert(path);
+                        }
+                    }
+                    std::collections::hash_map::Entry::Vacant(entry) => {
+                        entry.insert(path);
+                    }
+                }
+            }
+        }
+
+        inode_paths
+    }
+
+    /// Returns the path of the embedded file or directory whose `st_ino` is `ino`,
+    /// so that a file known only by `(st_dev, st_ino)` can be opened again. For hard
+    /// links, always the same one of their paths. Virtual files are not included.
+    pub fn path_for_inode(&self, ino: u64) -> Option<PathBuf> {
+        self.inode_paths
+            .get_or_init(|| self.build_inode_paths())
+            .get(&ino)
+            .map(|path| path.iter().collect())
+    }
+
+    fn hash_path<T: Hash>(path: &[T]) -> u64 {
+        let mut hasher = FxHasher::default();
+        path.hash(&mut hasher);
+
+        hasher.finish()
+    }
+
+    fn get_inode_from_path(&self, path: &Vec<&OsStr>) -> u64 {
+        let path_hash = Self::hash_path(path);
+        if !self.link_inodes.is_empty()
+            && let Some(inode) = self.link_inodes.get(&path_hash)
+        {
+            return *inode;
+        }
+
+        path_hash
+    }
+
+    /// Returns the inode of the parent directory of `path`, as reported for its `..`
+    /// entry. The root is its own parent, and the parent of `/x` is `/`.
+    pub fn parent_inode(&self, path: &[&OsStr]) -> u64 {
+        let parent = if Self::is_root_path(path) {
+            path
+        } else {
+            &path[..path.len() - 1]
+        };
+
+        self.get_inode_from_path(&parent.to_vec())
+    }
+
+    /// Returns the number of paths linked to the inode of `path`: 1 for directories
+    /// and files without hard links, 0 if `path` does not exist.
+    pub fn hardlink_count(&self, path: &Vec<&OsStr>) -> u32 {
+        match self.get_file_type_from_path(path) {
+            Some(FileType::File { inode, .. }) => self.hardlink_count_from_inode(inode),
+            Some(FileType::Directory { .. } | FileType::Virtual { .. }) => 1,
+            None => 0,
+        }
+    }
+
+    /// Returns the [`content_hash`] of the embedded file at `path`, computed when the
+    /// `Fs` was built. `None` if `path` is not an embedded file or the `Fs` was built
+    /// without [`FsConfig::checksums`].
+    pub fn checksum(&self, path: &Vec<&OsStr>) -> Option<u64> {
+        match self.get_file_type_from_path(path)? {
+            FileType::File {
+                file: FileData::Embedded(_),
+                inode,
+                ..
+            } => self.checksums.get(&inode).copied(),
+            _ => None,
+        }
+    }
+
+    fn hardlink_count_from_inode(&self, inode: u64) -> u32 {
+        self.hardlinks.get(&inode).copied().unwrap_or(1)
+    }
+
+    fn is_root_path(path: &[&OsStr]) -> bool {
+        path.is_empty() || (path.len() == 1 && path[0] == OsStr::new("/"))
+    }
+
+    // Embedded paths are stored either with a leading "/" component or without one.
+    fn root_prefix(&self) -> Vec<&'static OsStr> {
+        if self.trie.is_prefix([OsStr::new("/")]) {
+            vec![OsStr::new("/")]
+        } else {
+            vec![]
+        }
+    }
+
+    // Entries borrow their components from the directory index, so no `OsString` is
+    // allocated per child.
+    fn get_entries_from_path(&self, search_path: &Vec<&OsStr>) -> Vec<Vec<&'a OsStr>> {
+        let mut seen_entries = HashSet::new();
+
+        let mut entries = match self.dir_index.get_key_value(search_path) {
+            Some((dir, children)) => children
+                .iter()
+                .map(|child| {
+                    // SAFETY: the index hands out its own `&'a OsStr` labels. Only the
+                    // lifetime of `search_path` shortens them in the type.
+                    let entry: Vec<&'a OsStr> = dir
+                        .iter()
+                        .chain([&child.name])
+                        .map(|&s| unsafe { &*(s as *const OsStr) })
+                        .collect();
+                    seen_entries.insert(entry.clone());
+                    entry
+                })
+                .collect::<Vec<Vec<&'a OsStr>>>(),
+            None => Vec::new(),
+        };
+
+        let virtual_files = self.virtual_files.read().unwrap();
+        let children: &HashMap<Vec<&OsStr>, Vec<Vec<&'static OsStr>>> = &virtual_files.children;
+        for child in children.get(search_path).into_iter().flatten() {
+            if seen_entries.insert(child.clone()) {
+                entries.push(child.clone());
+            }
+        }
+
+        entries
+    }
+
+    /// Returns the names directly under the directory at `path`, in time proportional
+    /// to their number rather than to the size of the whole subtree as walking the
+    /// trie takes. Empty if `path` is not a directory.
+    pub fn children(&self, path: &Vec<&OsStr>) -> Vec<&'a OsStr> {
+        let root_prefix;
+        let path = if Self::is_root_path(path) {
+            root_prefix = self.root_prefix();
+            &root_prefix
+        } else {
+            path
+        };
+
+        let mut children = self
+            .dir_index
+            .get(path)
+            .map(|entries| entries.iter().map(|entry| entry.name).collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        let virtual_files = self.virtual_files.read().unwrap();
+        let virtual_children: &HashMap<Vec<&OsStr>, Vec<Vec<&'static OsStr>>> =
+            &virtual_files.children;
+        for child in virtual_children.get(path).into_iter().flatten() {
+            let name = child[child.len() - 1];
+            if !children.contains(&name) {
+                children.push(name);
+            }
+        }
+
+        children
+    }
+
+    /// Returns the distinct names directly under the VFS root.
+    pub fn root_entries(&self) -> Vec<OsString> {
+        self.get_entries_from_path(&self.root_prefix())
+            .into_iter()
+            .filter_map(|mut entry| entry.pop().map(OsStr::to_os_string))
+            .collect()
+    }
+
+    fn get_file_type_from_path(&self, search_path: &Vec<&OsStr>) -> Option<FileType<'a>> {
+        #[cfg(test)]
+        self.lookup_count
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+        if Self::is_root_path(search_path) {
+            // The VFS root always exists, even when nothing is embedded.
+            let root_prefix = self.root_prefix();
+            let entries = self.get_entries_from_path(&root_prefix);
+            let inode = self.get_inode_from_path(search_path);
+
+            return Some(FileType::Directory {
+                inode,
+                entries: entries.into(),
+            });
+        }
+
+        if !self.exists_any_prefix(search_path) {
+            #[cfg(feature = "unicode-normalization")]
+            if let Some(path) = self.normalized_match(search_path) {
+                return self.get_file_type_from_path(path);
+            }
+
+            return None;
+        }
+
+        if let Some(file) = self.get_virtual_file(search_path) {
+            return Some(FileType::Virtual {
+                file,
+                inode: Self::hash_path(search_path),
+            });
+        }
+
+        if let Some(file) = self.trie.exact_match(search_path) {
+            let inode = self.get_inode_from_path(search_path);
+
+            return Some(FileType::File {
+                file: FileData::Embedded(file),
+                offset: FileOffset::default(),
+                inode,
+            });
+        }
+
+        let entries = self.get_entries_from_path(search_path);
+
+        if !entries.is_empty() || self.dir_index.contains_key(search_path) {
+            let inode = self.get_inode_from_path(search_path);
+
+            return Some(FileType::Directory {
+                inode,
+                entries: entries.into(),
+            });
+        }
+
+        None
+    }
+
+    pub fn is_fd_exists(&self, fd: i32) -> bool {
+        self.fd_map.read().unwrap().contains_key(&fd)
+    }
+
+    /// Returns the path of the directory open as `fd`, against which paths relative
+    /// to `fd` resolve. `None` if `fd` is not an open directory.
+    pub fn dir_path(&self, fd: i32) -> Option<PathBuf> {
+        match self.fd_map.read().unwrap().get(&fd)? {
+            OpenFile {
+                file_type: FileType::Directory { .. },
+                path,
+                ..
+            } => Some(path.iter().collect()),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of files and directories currently open in the VFS.
+    pub fn open_fd_count(&self) -> usize {
+        self.fd_map.read().unwrap().len()
+    }
+
+    /// Returns the path the file or directory open as `fd` was resolved to when it
+    /// was opened. `None` if `fd` is not open in the VFS.
+    pub fn fd_path(&self, fd: i32) -> Option<PathBuf> {
+        self.fd_map
+            .read()
+            .unwrap()
+            .get(&fd)
+            .map(|open| open.path.iter().collect())
+    }
+
+    /// Returns every file and directory open in the VFS with the path it was opened
+    /// at, sorted by fd, to track down fds that are never closed.
+    pub fn open_fds(&self) -> Vec<(i32, PathBuf)> {
+        let mut fds = self
+            .fd_map
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(fd, open)| (*fd, open.path.iter().collect()))
+            .collect::<Vec<_>>();
+        fds.sort_by_key(|(fd, _)| *fd);
+
+        fds
+    }
+
+    pub fn is_dir_exists(&self, dir: &FsDir) -> bool {
+        self.is_fd_exists(dir.fd)
+    }
 
+    /// Returns false if `path` is definitely neither an embedded file nor directory,
+    /// nor a prefix of a virtual file, without walking the trie. True means it may
+    /// exist, since different paths can share a hash.
+    pub fn exists_any_prefix(&self, path: &[&OsStr]) -> bool {
+        if self.path_hashes.contains(&Self::hash_path(path)) {
+            return true;
+        }
+
+        let virtual_files = self.virtual_files.read().unwrap();
+        let prefixes: &HashSet<Vec<&OsStr>> = &virtual_files.prefixes;
+        prefixes.contains(path)
+    }
+
+    /// Returns whether `path` is an embedded file or directory.
+    pub fn contains(&self, path: &Vec<&OsStr>) -> bool {
+        self.get_file_type_from_path(path).is_some()
+    }
+
+    /// Returns the names of the children of the directory at `path`, sorted.
+    pub fn list_dir(&self, path: &Vec<&OsStr>) -> Result<Vec<OsString>, FsError> {
+        match self.get_file_type_from_path(path) {
+            Some(FileType::Directory { entries, .. }) => {
+                let mut names = entries
+                    .iter()
+                    .filter_map(|entry| entry.last().map(|name| name.to_os_string()))
+                    .collect::<Vec<_>>();
+                names.sort();
+
+                Ok(names)
+            }
+            Some(FileType::File { .. } | FileType::Virtual { .. }) => Err(FsError::NotDirectory),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    pub fn is_dir_exists_from_path(&self, path: &Vec<&OsStr>) -> bool {
+        matches!(
+            self.get_file_type_from_path(path),
+            Some(FileType::Directory { .. })
+        )
+    }
+
+    fn get_cached_stat(&self, file_type: &FileType) -> CachedStat {
         match file_type {
-            FileType::File { file, .. } => Some(file.as_ptr()),
+            FileType::File { file, inode, .. } => CachedStat {
+                ino: *inode,
+                size: file_size_as_off_t(file.len()),
+                mode: libc::S_IFREG | libc::S_IRUSR | libc::S_IRGRP | libc::S_IROTH, // 444
+                nlink: self.hardlink_count_from_inode(*inode) as _,
+            },
+            // As recorded at registration, so empty until generated if unknown
+            FileType::Virtual { file, inode } => CachedStat {
+                ino: *inode,
+                size: file.size,
+                mode: libc::S_IFREG | libc::S_IRUSR | libc::S_IRGRP | libc::S_IROTH, // 444
+                nlink: 1,
+            },
+            FileType::Directory { inode, .. } => CachedStat {
+                ino: *inode,
+                size: 1,
+                mode: libc::S_IFDIR // 555
+                    | libc::S_IXUSR
+                    | libc::S_IRUSR
+                    | libc::S_IXGRP
+                    | libc::S_IRGRP
+                    | libc::S_IXOTH
+                    | libc::S_IROTH,
+                nlink: 1,
+            },
+        }
+    }
+
+    /// Rebuilds the `stat` that `cached` was taken from with [`CachedStat::from_stat`].
+    /// The other fields are the same for every path or follow from these.
+    pub fn stat_from_cached(&self, cached: &CachedStat) -> libc::stat {
+        // Timestamps and the fields not set here stay zero
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        stat.st_dev = Self::DEV;
+        stat.st_ino = cached.ino;
+        stat.st_mode = cached.mode;
+        stat.st_nlink = cached.nlink;
+        stat.st_uid = self.config.uid;
+        stat.st_gid = self.config.gid;
+        stat.st_size = cached.size;
+        stat.st_blksize = self.config.blksize;
+        // Directories take no blocks
+        if cached.mode & libc::S_IFMT == libc::S_IFREG {
+            stat.st_blocks = ((cached.size as usize).div_ceil(512).div_ceil(8) * 8) as i64;
+        }
+
+        stat
+    }
+
+    // Resolves `path` for `open`, reusing the result of a preceding `stat` or `open`
+    // of the same path. Templates always carry a zero offset, so clones start fresh.
+    fn get_file_type_for_open(&self, path: &Vec<&OsStr>) -> Option<(FileType<'a>, CachedStat)> {
+        if let Ok(last_stat) = self.last_stat.try_lock()
+            && let Some((last_path, file_type, stat)) = last_stat.as_ref()
+            && last_path
+                .iter()
+                .map(|s| s.as_os_str())
+                .eq(path.iter().copied())
+        {
+            return Some((file_type.clone(), *stat));
+        }
+
+        // Paths with the same hash replace each other, so a hit is checked against
+        // the path it was stored for.
+        let hash = Self::hash_path(path);
+        if let Some(template) = self.open_templates.read().unwrap().get(&hash)
+            && template
+                .path
+                .iter()
+                .map(|s| s.as_os_str())
+                .eq(path.iter().copied())
+        {
+            return Some((template.file_type.clone(), template.stat));
+        }
+
+        let file_type = self.get_file_type_from_path(path)?;
+        let stat = self.get_cached_stat(&file_type);
+        let mut open_templates = self.open_templates.write().unwrap();
+        if open_templates.len() >= OPEN_TEMPLATES_MAX {
+            open_templates.clear();
+        }
+        open_templates.insert(
+            hash,
+            OpenTemplate {
+                path: path.iter().map(|s| s.to_os_string()).collect(),
+                file_type: file_type.clone(),
+                stat,
+            },
+        );
+
+        Some((file_type, stat))
+    }
+
+    /// Sets the callback run after every successful `open`, replacing any previous one.
+    pub fn set_open_hook(&self, hook: FsOpenHook) {
+        *self.open_hook.0.write().unwrap() = Some(Arc::from(hook));
+    }
+
+    pub fn clear_open_hook(&self) {
+        self.open_hook.0.write().unwrap().take();
+    }
+
+    // Must be called after the fd_map lock is released, so hooks may use this Fs.
+    fn call_open_hook(&self, fd: i32, path: &[&OsStr]) {
+        let hook = self.open_hook.0.read().unwrap().clone();
+        if let Some(hook) = hook {
+            let path = path.iter().map(|s| s.to_os_string()).collect::<Vec<_>>();
+            hook(fd, &path);
+        }
+    }
+
+    /// Opens `path` and returns its new fd. With `O_DIRECTORY` in `flags`, fails with
+    /// [`FsError::NotDirectory`] unless `path` is a directory. Other flags are ignored,
+    /// since the VFS is read-only.
+    pub fn open(&self, path: &Vec<&OsStr>, flags: libc::c_int) -> Result<i32, FsError> {
+        let (file_type, stat) = self.get_file_type_for_open(path).ok_or(FsError::NotFound)?;
+        if flags & libc::O_DIRECTORY != 0 && !matches!(file_type, FileType::Directory { .. }) {
+            return Err(FsError::NotDirectory);
+        }
+        let (file_type, stat) = self.generate(file_type, stat)?;
+
+        let fd = unsafe { libc::dup(0) };
+        self.insert_fd(fd, file_type, stat, self.resolved_path(path));
+        self.call_open_hook(fd, path);
+
+        Ok(fd)
+    }
+
+    // `stat` must be the one `get_cached_stat` builds for `file_type`, and `path`
+    // where it was found (see `resolved_path`).
+    fn insert_fd(&self, fd: i32, file_type: FileType<'a>, stat: CachedStat, path: Arc<[OsString]>) {
+        let stat = self.stat_from_cached(&stat);
+        self.fd_map.write().unwrap().insert(
+            fd,
+            OpenFile {
+                file_type,
+                path,
+                stat,
+            },
+        );
+    }
+
+    // The path the fds open on `path` report: the root as it is stored, and with
+    // `unicode-normalization`, the composition the packer stored.
+    fn resolved_path(&self, path: &[&OsStr]) -> Arc<[OsString]> {
+        let root_prefix;
+        let path = if Self::is_root_path(path) {
+            root_prefix = self.root_prefix();
+            &root_prefix
+        } else {
+            path
+        };
+
+        #[cfg(feature = "unicode-normalization")]
+        if !self.exists_any_prefix(path)
+            && let Some(path) = self.normalized_match(path)
+        {
+            return path.iter().map(|s| s.to_os_string()).collect();
+        }
+
+        path.iter().map(|s| s.to_os_string()).collect()
+    }
+
+    pub fn open_at(&self, path: &Vec<&OsStr>) -> Option<i32> {
+        let (file_type, stat) = self.get_file_type_for_open(path)?;
+        let (file_type, stat) = self.generate(file_type, stat).ok()?;
+        let fd = unsafe { libc::dup(0) };
+        self.insert_fd(fd, file_type, stat, self.resolved_path(path));
+        self.call_open_hook(fd, path);
+
+        Some(fd)
+    }
+
+    /// Copies the file contents at the fd's offset into `buf` and advances the
+    /// offset. `None` if `fd` is not open in the VFS or is a directory, which has
+    /// no contents to read.
+    pub fn read(&self, fd: i32, buf: &mut [u8]) -> Option<isize> {
+        // Only the range is claimed under the lock. Embedded contents are borrowed for
+        // 'a and generated ones are shared, so they outlive it, and a large copy
+        // doesn't hold up opens and closes on other threads.
+        let (file, start, read_size) = match &self.fd_map.read().unwrap().get(&fd)?.file_type {
+            FileType::File { file, offset, .. } => {
+                let (start, read_size) = offset.claim(buf.len(), file.len());
+                (file.clone(), start, read_size)
+            }
+            _ => return None,
+        };
+        buf[..read_size].copy_from_slice(&file[start..start + read_size]);
+
+        Some(read_size as isize)
+    }
+
+    /// Returns the file contents from the fd's offset to the end and moves the offset
+    /// there, so a caller reading a whole file needs one call rather than a loop of
+    /// reads. Embedded contents are borrowed rather than copied. `None` as for
+    /// [`Fs::read`].
+    pub fn read_to_end(&self, fd: i32) -> Option<Cow<'a, [u8]>> {
+        let (file, start, read_size) = match &self.fd_map.read().unwrap().get(&fd)?.file_type {
+            FileType::File { file, offset, .. } => {
+                let (start, read_size) = offset.claim(usize::MAX, file.len());
+                (file.clone(), start, read_size)
+            }
+            _ => return None,
+        };
+
+        Some(match file {
+            FileData::Embedded(file) => Cow::Borrowed(&file[start..start + read_size]),
+            FileData::Generated(file) => Cow::Owned(file[start..start + read_size].to_vec()),
+        })
+    }
+
+    /// Copies the file contents starting at `pos` into `buf` without moving the
+    /// fd's offset. Returns the number of bytes copied, which is 0 past the end.
+    pub fn read_at(&self, fd: i32, buf: &mut [u8], pos: u64) -> Option<isize> {
+        // Copies outside the lock, as `read` does
+        let file = match &self.fd_map.read().unwrap().get(&fd)?.file_type {
+            FileType::File { file, .. } => file.clone(),
+            _ => return None,
+        };
+        let start = (pos as usize).min(file.len());
+        let read_size = capped_read_size(file.len() - start, buf.len());
+        buf[..read_size].copy_from_slice(&file[start..start + read_size]);
+
+        Some(read_size as isize)
+    }
+
+    /// Returns the current read position of the file open as `fd`.
+    pub fn file_offset(&self, fd: i32) -> Option<u64> {
+        match self
+            .fd_map
+            .read()
+            .unwrap()
+            .get(&fd)
+            .map(|open| &open.file_type)
+        {
+            Some(FileType::File { offset, .. }) => Some(offset.get()),
             _ => None,
         }
     }
 
-    pub fn fdopendir(&self, fd: i32) -> Option<FsDir> {
-        let fd_map = self.fd_map.read().unwrap();
-        match fd_map.get(&fd) {
-            Some(FileType::Directory { .. }) => Some(FsDir { fd, offset: 0 }),
-            _ => None,
-        }
-    }
+    /// Moves the read position of the file open as `fd` to `pos`, clamped to its end.
+    pub fn set_file_offset(&self, fd: i32, pos: u64) -> Option<()> {
+        match self
+            .fd_map
+            .read()
+            .unwrap()
+            .get(&fd)
+            .map(|open| &open.file_type)
+        {
+            Some(FileType::File { file, offset, .. }) => {
+                offset.set(pos.min(file.len() as u64));
+                Some(())
+            }
+            _ => None,
+        }
+    }
+
+    /// Forgets `fd`. Fails with [`FsError::BadFd`] if it was not open in the VFS,
+    /// e.g. when it has already been closed.
+    pub fn close(&self, fd: i32) -> Result<(), FsError> {
+        match self.fd_map.write().unwrap().remove(&fd) {
+            Some(_) => Ok(()),
+            None => Err(FsError::BadFd),
+        }
+    }
+
+    pub fn stat(&self, path: &Vec<&OsStr>, stat_buf: &mut libc::stat) -> Option<i32> {
+        match self.get_file_type_from_path(path) {
+            Some(file_type) => {
+                let cached = self.get_cached_stat(&file_type);
+                *stat_buf = self.stat_from_cached(&cached);
+
+                // Skip remembering it rather than wait when another thread holds the slot.
+                if let Ok(mut last_stat) = self.last_stat.try_lock() {
+                    let path = path.iter().map(|s| s.to_os_string()).collect();
+                    *last_stat = Some((path, file_type, cached));
+                }
+
+                Some(0)
+            }
+            None => None,
+        }
+    }
+
+    pub fn lstat(&self, path: &Vec<&OsStr>, stat_buf: &mut libc::stat) -> Option<i32> {
+        self.stat(path, stat_buf)
+    }
+
+    /// Returns the target of the symlink at `path`, or `None` if `path` is a file,
+    /// a directory or does not exist. Embedded paths are always regular files or
+    /// directories for now, so this never finds a symlink yet.
+    pub fn symlink_target(&self, path: &Vec<&OsStr>) -> Option<OsString> {
+        match self.get_file_type_from_path(path)? {
+            FileType::File { .. } | FileType::Directory { .. } | FileType::Virtual { .. } => None,
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn getattrlist(
+        &self,
+        path: &Vec<&OsStr>,
+        attr_list: &libc::attrlist,
+        attr_buf: *mut libc::c_void,
+        attr_buf_size: usize,
+    ) -> Option<i32> {
+        #[repr(C)]
+        struct AttrBufNameAndObjType {
+            length: u32,
+            name_ref: libc::attrreference_t,
+            obj_type: u32,
+        }
+
+        let file_type = self.get_file_type_from_path(path)?;
+
+        let obj_type: u32 = match file_type {
+            FileType::File { .. } | FileType::Virtual { .. } => 1, // VREG
+            FileType::Directory { .. } => 2,                       // VDIR
+        };
+
+        let basename = path.last().map(|s| s.as_bytes()).unwrap_or(b"");
+        let basename_len = basename.len() + 1;
+
+        if attr_list.commonattr != (libc::ATTR_CMN_NAME | libc::ATTR_CMN_OBJTYPE) {
+            return None;
+        }
+
+        let header_size = std::mem::size_of::<AttrBufNameAndObjType>();
+        let total_size = header_size + basename_len;
+
+        if total_size > attr_buf_size {
+            return None;
+        }
+
+        let result = AttrBufNameAndObjType {
+            length: total_size as u32,
+            name_ref: libc::attrreference_t {
+                attr_dataoffset: (header_size
+                    - std::mem::offset_of!(AttrBufNameAndObjType, name_ref))
+                    as i32,
+                attr_length: basename_len as u32,
+            },
+            obj_type,
+        };
+
+        unsafe {
+            let buf = attr_buf as *mut AttrBufNameAndObjType;
+            std::ptr::write(buf, result);
+
+            let name_ptr = (attr_buf as *mut u8).add(header_size);
+            std::ptr::copy_nonoverlapping(basename.as_ptr(), name_ptr, basename.len());
+            *name_ptr.add(basename.len()) = 0;
+        }
+
+        Some(0)
+    }
+
+    /// Copies the stat computed when `fd` was opened, so it costs no more than a
+    /// lookup no matter how often Ruby re-fstats the same fd.
+    pub fn fstat(&self, fd: i32, stat_buf: &mut libc::stat) -> Option<i32> {
+        *stat_buf = self.fd_map.read().unwrap().get(&fd)?.stat;
+        Some(0)
+    }
+
+    /// Describes the VFS as a file system holding every embedded file and no free
+    /// space, if `path` is in it.
+    pub fn statfs(&self, path: &Vec<&OsStr>, statfs_buf: &mut libc::statfs) -> Option<i32> {
+        if !self.contains(path) {
+            return None;
+        }
+
+        *statfs_buf = self.get_statfs();
+        Some(0)
+    }
+
+    /// Like [`Fs::statfs`], for the file system of the file or directory open as `fd`.
+    pub fn fstatfs(&self, fd: i32, statfs_buf: &mut libc::statfs) -> Option<i32> {
+        if !self.is_fd_exists(fd) {
+            return None;
+        }
+
+        *statfs_buf = self.get_statfs();
+        Some(0)
+    }
+
+    // The field layout differs between Linux, macOS and FreeBSD, so each fills its own.
+    fn get_statfs(&self) -> libc::statfs {
+        let mut statfs: libc::statfs = unsafe { std::mem::zeroed() };
+        let blksize = self.config.blksize as u64;
+
+        statfs.f_bsize = self.config.blksize as _;
+        statfs.f_blocks = self.total_size().div_ceil(blksize) as _;
+        statfs.f_files = self.path_hashes.len() as _;
+
+        #[cfg(target_os = "linux")]
+        {
+            statfs.f_type = KOMPO_FS_MAGIC as _;
+            statfs.f_frsize = self.config.blksize as _;
+            statfs.f_namelen = 255;
+        }
+
+        #[cfg(target_os = "macos")]
+        {
+            statfs.f_iosize = self.config.blksize as _;
+            statfs.f_flags = libc::MNT_RDONLY as _;
+            for (dst, src) in statfs
+                .f_fstypename
+                .iter_mut()
+                .zip(KOMPO_FS_TYPENAME.as_bytes())
+            {
+                *dst = *src as _;
+            }
+        }
+
+        #[cfg(target_os = "freebsd")]
+        {
+            statfs.f_iosize = self.config.blksize as _;
+            statfs.f_flags = libc::MNT_RDONLY as _;
+            statfs.f_namemax = 255;
+            for (dst, src) in statfs
+                .f_fstypename
+                .iter_mut()
+                .zip(KOMPO_FS_TYPENAME.as_bytes())
+            {
+                *dst = *src as _;
+            }
+        }
+
+        statfs
+    }
+
+    pub fn file_read(&self, path: &Vec<&OsStr>) -> Option<*const u8> {
+        let file_type = self
+            .get_file_type_from_path(path)
+            .unwrap_or_else(|| panic!("not found path: {:?}", path));
+
+        match file_type {
+            // Generated contents are dropped with `file_type`, so only embedded data
+            // has a pointer that outlives this call.
+            FileType::File {
+                file: FileData::Embedded(file),
+                ..
+            } => Some(file.as_ptr()),
+            _ => None,
+        }
+    }
+
+    /// Returns the address and length of the embedded contents of the file at
+    /// `path`, which stay valid for as long as the `Fs`. `None` if `path` does not
+    /// exist, is a directory, or has generated contents, which have no fixed address.
+    pub fn file_slice(&self, path: &Vec<&OsStr>) -> Option<(*const u8, usize)> {
+        match self.get_file_type_from_path(path)? {
+            FileType::File {
+                file: FileData::Embedded(file),
+                ..
+            } => Some((file.as_ptr(), file.len())),
+            _ => None,
+        }
+    }
+
+    pub fn fdopendir(&self, fd: i32) -> Option<FsDir> {
+        let (inode, entries, path) = match self.fd_map.read().unwrap().get(&fd)? {
+            OpenFile {
+                file_type: FileType::Directory { inode, entries, .. },
+                path,
+                ..
+            } => (*inode, Arc::clone(entries), Arc::clone(path)),
+            _ => return None,
+        };
+
+        Some(self.snapshot_dir(fd, inode, &path, &entries))
+    }
+
+    // `.` and `..` come first, like on most file systems, followed by the children
+    // of the directory at `path`.
+    fn snapshot_dir(
+        &self,
+        fd: i32,
+        inode: u64,
+        path: &[OsString],
+        entries: &[Vec<&'a OsStr>],
+    ) -> FsDir {
+        // All entries share the directory, so its index entries serve every one of
+        // them. Only virtual files fall back to a lookup.
+        let indexed = entries
+            .first()
+            .and_then(|entry| self.dir_index.get(&entry[..entry.len() - 1]))
+            .map(|children| {
+                children
+                    .iter()
+                    .map(|child| (child.name, child))
+                    .collect::<HashMap<_, _>>()
+            })
+            .unwrap_or_default();
+
+        let mut snapshot = entries
+            .iter()
+            .filter_map(|full_path| {
+                let name = *full_path.last()?;
+                if let Some(child) = indexed.get(name) {
+                    return Some(DirSnapshotEntry {
+                        name: name.to_os_string(),
+                        inode: child.inode,
+                        d_type: if child.is_dir {
+                            libc::DT_DIR
+                        } else {
+                            libc::DT_REG
+                        },
+                    });
+                }
+
+                let d_type = match self.get_file_type_from_path(full_path)? {
+                    FileType::File { .. } | FileType::Virtual { .. } => libc::DT_REG,
+                    FileType::Directory { .. } => libc::DT_DIR,
+                };
+
+                Some(DirSnapshotEntry {
+                    name: full_path.last()?.to_os_string(),
+                    inode: self.get_inode_from_path(full_path),
+                    d_type,
+                })
+            })
+            .collect::<Vec<_>>();
+        snapshot.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let path = path.iter().map(OsString::as_os_str).collect::<Vec<_>>();
+        let dots = [(".", inode), ("..", self.parent_inode(&path))].map(|(name, inode)| {
+            DirSnapshotEntry {
+                name: name.into(),
+                inode,
+                d_type: libc::DT_DIR,
+            }
+        });
+        snapshot.splice(0..0, dots);
+
+        FsDir {
+            fd,
+            offset: 0,
+            entries: snapshot.into(),
+        }
+    }
+
+    /// Returns the next entry of `dir`, or null at the end. An entry whose name does
+    /// not fit in `d_name` fails with [`FsError::NameTooLong`] and is skipped, so the
+    /// next call continues with the entry after it.
+    pub fn readdir(&self, dir: &mut FsDir) -> Result<*mut libc::dirent, FsError> {
+        match self
+            .fd_map
+            .read()
+            .unwrap()
+            .get(&dir.fd)
+            .map(|open| &open.file_type)
+        {
+            Some(FileType::Directory { .. }) => {}
+            Some(_) => return Err(FsError::NotDirectory),
+            None => return Err(FsError::BadFd),
+        }
+
+        let Some(entry) = dir.entries.get(dir.offset as usize) else {
+            return Ok(std::ptr::null_mut());
+        };
+        let dirent = Self::create_dirent(entry.inode, entry.d_type, &entry.name);
+
+        dir.offset += 1;
+
+        let dirent = Box::new(dirent?);
+        Ok(Box::into_raw(dirent))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn create_dirent(inode: u64, file_type: u8, name: &OsStr) -> Result<libc::dirent, FsError> {
+        let mut buf: DirEntryName = [0; 256];
+        let record = DirentRecord::new(
+            inode,
+            file_type,
+            name.as_bytes(),
+            std::mem::offset_of!(libc::dirent, d_name),
+            buf.len(),
+        )?;
+        let convert_path: Vec<_> = record.name.iter().map(convert_byte).collect();
+        buf[..record.name.len()].copy_from_slice(&convert_path);
+
+        Ok(libc::dirent {
+            d_ino: record.inode,
+            d_off: 0, // TODO
+            d_reclen: record.reclen,
+            d_type: record.d_type,
+            d_name: buf,
+        })
+    }
+
+    #[cfg(target_os = "macos")]
+    fn create_dirent(inode: u64, file_type: u8, name: &OsStr) -> Result<libc::dirent, FsError> {
+        let mut buf: DirEntryName = [0; 1024];
+        let record = DirentRecord::new(
+            inode,
+            file_type,
+            name.as_bytes(),
+            std::mem::offset_of!(libc::dirent, d_name),
+            buf.len(),
+        )?;
+        let convert_path: Vec<i8> = record.name.iter().map(convert_byte).collect();
+        buf[..record.name.len()].copy_from_slice(&convert_path);
+
+        Ok(libc::dirent {
+            d_ino: record.inode,
+            d_reclen: record.reclen,
+            d_type: record.d_type,
+            d_name: buf,
+            d_seekoff: 0, // TODO
+            d_namlen: record.name.len() as u16,
+        })
+    }
+
+    // FreeBSD 12 added d_off and widened d_namlen, and the padding fields can't be
+    // named, so the entry is filled in field by field over a zeroed one.
+    #[cfg(target_os = "freebsd")]
+    fn create_dirent(inode: u64, file_type: u8, name: &OsStr) -> Result<libc::dirent, FsError> {
+        let mut buf: DirEntryName = [0; 256];
+        let record = DirentRecord::new(
+            inode,
+            file_type,
+            name.as_bytes(),
+            std::mem::offset_of!(libc::dirent, d_name),
+            buf.len(),
+        )?;
+        let convert_path: Vec<_> = record.name.iter().map(convert_byte).collect();
+        buf[..record.name.len()].copy_from_slice(&convert_path);
+
+        let mut dirent: libc::dirent = unsafe { std::mem::zeroed() };
+        dirent.d_fileno = record.inode as _;
+        dirent.d_reclen = record.reclen;
+        dirent.d_type = record.d_type;
+        dirent.d_namlen = record.name.len() as _;
+        dirent.d_name = buf;
+        Ok(dirent)
+    }
+
+    pub fn closedir(&self, dir: &FsDir) -> Result<(), FsError> {
+        self.close(dir.fd)
+    }
+
+    pub fn opendir(&self, path: &Vec<&OsStr>) -> Option<FsDir> {
+        match self.get_file_type_from_path(path) {
+            Some(FileType::Directory { inode, entries }) => {
+                let file_type = FileType::Directory {
+                    inode,
+                    entries: Arc::clone(&entries),
+                };
+                let stat = self.get_cached_stat(&file_type);
+                let path = self.resolved_path(path);
+                let fd = unsafe { libc::dup(0) };
+                self.insert_fd(fd, file_type, stat, Arc::clone(&path));
+
+                Some(self.snapshot_dir(fd, inode, &path, &entries))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn rewinddir(&self, dir: &mut FsDir) {
+        dir.offset = 0;
+    }
+
+    // `Path::iter` keeps a leading `.` component, which never matches a stored path.
+    fn path_components(path: &Path) -> Vec<&OsStr> {
+        path.components()
+            .filter(|comp| *comp != std::path::Component::CurDir)
+            .map(|comp| comp.as_os_str())
+            .collect()
+    }
+
+    /// Resolves `feature` the way `require` searches `$LOAD_PATH`: each load path in
+    /// order, trying `feature.rb` and then `feature.so` in it. A feature that already
+    /// ends in `.rb` or `.so` is only tried as given, and an absolute one is looked
+    /// up directly. Returns the path of the first embedded file found.
+    pub fn resolve_require(&self, feature: &str, load_paths: &[&OsStr]) -> Option<PathBuf> {
+        self.which(feature, load_paths, &["rb", "so"])
+    }
+
+    /// Returns the embedded file that `feature` resolves to, searching like
+    /// [`Fs::resolve_require`] but trying the extensions in `exts`, in order. Nothing
+    /// is opened, so this only answers which file a `require` would load.
+    pub fn which(&self, feature: &str, load_paths: &[&OsStr], exts: &[&str]) -> Option<PathBuf> {
+        let has_extension = Path::new(feature)
+            .extension()
+            .is_some_and(|ext| exts.iter().any(|e| ext == *e));
+        let candidates = if has_extension {
+            vec![feature.to_string()]
+        } else {
+            exts.iter().map(|ext| format!("{feature}.{ext}")).collect()
+        };
+
+        let is_file = |path: &Path| {
+            matches!(
+                self.get_file_type_from_path(&Self::path_components(path)),
+                Some(FileType::File { .. } | FileType::Virtual { .. })
+            )
+        };
+
+        if Path::new(feature).is_absolute() {
+            return candidates
+                .into_iter()
+                .map(PathBuf::from)
+                .find(|p| is_file(p));
+        }
+
+        load_paths.iter().find_map(|load_path| {
+            candidates
+                .iter()
+                .map(|candidate| Path::new(load_path).join(candidate))
+                .find(|p| is_file(p))
+        })
+    }
+
+    pub fn open_path(&self, path: &Path) -> Result<i32, FsError> {
+        let path = Self::path_components(path);
+        self.open(&path, libc::O_RDONLY)
+    }
+
+    pub fn stat_path(&self, path: &Path, stat_buf: &mut libc::stat) -> Result<(), FsError> {
+        let path = Self::path_components(path);
+        self.stat(&path, stat_buf)
+            .map(|_| ())
+            .ok_or(FsError::NotFound)
+    }
+
+    pub fn lstat_path(&self, path: &Path, stat_buf: &mut libc::stat) -> Result<(), FsError> {
+        let path = Self::path_components(path);
+        self.lstat(&path, stat_buf)
+            .map(|_| ())
+            .ok_or(FsError::NotFound)
+    }
+
+    pub fn opendir_path(&self, path: &Path) -> Result<FsDir, FsError> {
+        let path = Self::path_components(path);
+        if let Some(dir) = self.opendir(&path) {
+            return Ok(dir);
+        }
+
+        match self.get_file_type_from_path(&path) {
+            Some(_) => Err(FsError::NotDirectory),
+            None => Err(FsError::NotFound),
+        }
+    }
+}
+
+impl<'a> Drop for Fs<'a> {
+    fn drop(&mut self) {
+        let fd_map = self.fd_map.read().unwrap();
+        for fd in fd_map.keys() {
+            unsafe { libc::close(*fd) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Reads past the `.` and `..` every directory starts with.
+    fn skip_dots(fs: &Fs, dir: &mut FsDir) {
+        for dot in [&b"."[..], b".."] {
+            let dirent = unsafe { Box::from_raw(fs.readdir(dir).unwrap()) };
+            let name = unsafe { std::ffi::CStr::from_ptr(dirent.d_name.as_ptr()) };
+            assert_eq!(name.to_bytes(), dot);
+        }
+    }
+
+    fn create_test_fs() -> Fs<'static> {
+        let mut builder: TrieBuilder<&OsStr, &[u8]> = TrieBuilder::new();
+        let ls = vec!["usr", "bin", "ls"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+        let cat = vec!["usr", "bin", "cat"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+        let hoge = vec!["usr", "bin", "hoge", "fuga"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+        let fuga = vec!["usr", "bin", "fuga"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+        let empty = vec!["usr", "empty"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        builder.push(&ls, b"ls_content");
+        builder.push(&cat, b"cat_content_here");
+        builder.push(&hoge, b"hoge_fuga_content");
+        builder.push(&fuga, b"fuga_content");
+        builder.push(&empty, b"");
+
+        Fs::new(builder)
+    }
+
+    #[test]
+    fn test_open_existing_file() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin", "ls"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let fd = fs.open(&path, libc::O_RDONLY);
+        assert!(fd.is_ok());
+        assert!(fd.unwrap() >= 0);
+    }
+
+    #[test]
+    fn test_open_nonexistent_file() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin", "nonexistent"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let fd = fs.open(&path, libc::O_RDONLY);
+        assert_eq!(fd, Err(FsError::NotFound));
+    }
+
+    #[test]
+    fn test_open_directory_flag() {
+        let fs = create_test_fs();
+        let file = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("ls")];
+        let dir = vec![OsStr::new("usr"), OsStr::new("bin")];
+        let missing = vec![OsStr::new("usr"), OsStr::new("missing")];
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY;
+
+        assert_eq!(fs.open(&file, flags), Err(FsError::NotDirectory));
+        assert_eq!(fs.open(&missing, flags), Err(FsError::NotFound));
+        assert_eq!(fs.open_fd_count(), 0);
+
+        let fd = fs.open(&dir, flags).unwrap();
+        assert_eq!(fs.dir_path(fd), Some(PathBuf::from("usr/bin")));
+        fs.close(fd).unwrap();
+    }
+
+    #[test]
+    fn test_read_file() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin", "ls"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+        let mut buf = [0u8; 128];
+        let read_size = fs.read(fd, &mut buf);
+
+        assert!(read_size.is_some());
+        assert_eq!(read_size.unwrap(), 10);
+        assert_eq!(&buf[..10], b"ls_content");
+    }
+
+    #[test]
+    fn test_read_partial() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin", "cat"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+
+        // Read in small chunks
+        let mut buf = [0u8; 4];
+        let read_size = fs.read(fd, &mut buf).unwrap();
+        assert_eq!(read_size, 4);
+        assert_eq!(&buf, b"cat_");
+
+        let read_size = fs.read(fd, &mut buf).unwrap();
+        assert_eq!(read_size, 4);
+        assert_eq!(&buf, b"cont");
+    }
+
+    #[test]
+    fn test_read_empty_file() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "empty"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+        let mut buf = [0u8; 128];
+        let read_size = fs.read(fd, &mut buf);
+
+        assert!(read_size.is_some());
+        assert_eq!(read_size.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_read_eof() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin", "ls"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+        let mut buf = [0u8; 128];
+
+        // First read
+        fs.read(fd, &mut buf);
+
+        // Second read should return 0 (EOF)
+        let read_size = fs.read(fd, &mut buf).unwrap();
+        assert_eq!(read_size, 0);
+    }
+
+    #[test]
+    fn test_read_zero_length_buffer() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin", "cat"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+        let mut buf = [0u8; 4];
+        fs.read(fd, &mut buf).unwrap();
+
+        // Mid-file, nothing is read and the offset stays put
+        assert_eq!(fs.read(fd, &mut []), Some(0));
+        assert_eq!(fs.file_offset(fd), Some(4));
+        assert_eq!(fs.read_at(fd, &mut [], 0), Some(0));
+        assert_eq!(fs.read(fd, &mut buf), Some(4));
+        assert_eq!(&buf, b"cont");
+
+        let mut rest = [0u8; 128];
+        fs.read(fd, &mut rest).unwrap();
+        assert_eq!(fs.read(fd, &mut []), Some(0));
+        assert_eq!(fs.file_offset(fd), Some(16));
+    }
+
+    #[test]
+    fn test_read_zero_length_buffer_empty_file() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "empty"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+
+        assert_eq!(fs.read(fd, &mut []), Some(0));
+        assert_eq!(fs.file_offset(fd), Some(0));
+    }
+
+    #[test]
+    fn test_read_to_end() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin", "cat"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+        let content = fs.read_to_end(fd).unwrap();
+        assert!(matches!(content, Cow::Borrowed(_)));
+        assert_eq!(&*content, b"cat_content_here");
+        assert_eq!(fs.file_offset(fd), Some(16));
+        assert_eq!(fs.read_to_end(fd).as_deref(), Some(&b""[..]));
+
+        // From the current offset
+        fs.set_file_offset(fd, 4).unwrap();
+        assert_eq!(fs.read_to_end(fd).as_deref(), Some(&b"content_here"[..]));
+        fs.close(fd).unwrap();
+        assert_eq!(fs.read_to_end(fd), None);
+
+        let generated = vec![OsStr::new("kompo"), OsStr::new("version")];
+        fs.add_virtual(&generated, Box::new(|| b"1.0".to_vec()));
+        let fd = fs.open(&generated, libc::O_RDONLY).unwrap();
+        assert_eq!(fs.read_to_end(fd).as_deref(), Some(&b"1.0"[..]));
+        fs.close(fd).unwrap();
+
+        let dir = vec![OsStr::new("usr")];
+        let fd = fs.open(&dir, libc::O_RDONLY).unwrap();
+        assert_eq!(fs.read_to_end(fd), None);
+        fs.close(fd).unwrap();
+    }
+
+    #[test]
+    fn test_read_invalid_fd() {
+        let fs = create_test_fs();
+        let mut buf = [0u8; 128];
+
+        let result = fs.read(9999, &mut buf);
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_close() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin", "ls"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+        assert!(fs.is_fd_exists(fd));
+        assert_eq!(fs.open_fd_count(), 1);
+
+        let result = fs.close(fd);
+        assert_eq!(result, Ok(()));
+        assert!(!fs.is_fd_exists(fd));
+
+        // Closing it again fails like close(2)
+        assert_eq!(fs.close(fd), Err(FsError::BadFd));
+        assert_eq!(fs.open_fd_count(), 0);
+    }
+
+    #[test]
+    fn test_storage() {
+        let mut builder: TrieBuilder<&OsStr, &[u8]> = TrieBuilder::new();
+        let ls = vec!["usr", "bin", "ls"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+        let cat = vec!["usr", "bin", "cat"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+        let hoge = vec!["usr", "bin", "hoge", "fuga"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+        let fuga = vec!["usr", "bin", "fuga"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        builder.push(&ls, &[1, 2, 3]);
+        builder.push(&cat, &[4, 5, 6]);
+        builder.push(&hoge, &[7, 8, 9]);
+        builder.push(&fuga, &[10, 11, 12]);
+
+        let fs = Fs::new(builder);
+
+        let mut hasher = FxHasher::default();
+        ls.hash(&mut hasher);
+
+        assert_eq!(
+            fs.get_file_type_from_path(&ls),
+            Some(FileType::File {
+                file: FileData::Embedded(&[1, 2, 3]),
+                offset: FileOffset::default(),
+                inode: hasher.finish(),
+            })
+        );
+
+        let mut hasher = FxHasher::default();
+        let search_path = vec!["usr", "bin"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        search_path.clone().hash(&mut hasher);
+
+        assert_eq!(
+            fs.get_file_type_from_path(&search_path.clone()),
+            Some(FileType::Directory {
+                inode: hasher.finish(),
+                entries: vec![
+                    vec!["usr", "bin", "cat"]
+                        .into_iter()
+                        .map(OsStr::new)
+                        .collect(),
+                    vec!["usr", "bin", "fuga"]
+                        .into_iter()
+                        .map(OsStr::new)
+                        .collect(),
+                    vec!["usr", "bin", "hoge"]
+                        .into_iter()
+                        .map(OsStr::new)
+                        .collect(),
+                    vec!["usr", "bin", "ls"]
+                        .into_iter()
+                        .map(OsStr::new)
+                        .collect(),
+                ]
+                .into(),
+            })
+        );
+
+        let search_path = "usr/bin/cat"
+            .split('/')
+            .map(OsStr::new)
+            .collect::<Vec<&OsStr>>();
+        let mut hasher = FxHasher::default();
+        ["usr", "bin", "cat"]
+            .iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>()
+            .hash(&mut hasher);
+
+        assert_eq!(
+            fs.get_file_type_from_path(&search_path),
+            Some(FileType::File {
+                file: FileData::Embedded(&[4, 5, 6]),
+                offset: FileOffset::default(),
+                inode: hasher.finish(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_stat_file() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin", "ls"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+        let result = fs.stat(&path, &mut stat);
+
+        assert_eq!(result, Some(0));
+        assert_eq!(stat.st_size, 10); // "ls_content" = 10 bytes
+        assert_eq!(stat.st_mode & libc::S_IFMT, libc::S_IFREG);
+    }
+
+    #[test]
+    fn test_stat_custom_blksize() {
+        let mut builder: TrieBuilder<&OsStr, &[u8]> = TrieBuilder::new();
+        let ls = vec!["usr", "bin", "ls"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+        builder.push(&ls, b"ls_content");
+
+        let fs = Fs::with_config(
+            builder,
+            FsConfig {
+                blksize: 65536,
+                ..FsConfig::default()
+            },
+        );
+
+        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+        assert_eq!(fs.stat(&ls, &mut stat), Some(0));
+        assert_eq!(stat.st_blksize, 65536);
+
+        let dir = vec!["usr", "bin"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+        assert_eq!(fs.stat(&dir, &mut stat), Some(0));
+        assert_eq!(stat.st_blksize, 65536);
+    }
+
+    #[test]
+    fn test_stat_directory() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+        let result = fs.stat(&path, &mut stat);
+
+        assert_eq!(result, Some(0));
+        assert_eq!(stat.st_mode & libc::S_IFMT, libc::S_IFDIR);
+    }
+
+    #[test]
+    fn test_stat_nonexistent() {
+        let fs = create_test_fs();
+        let path = vec!["nonexistent"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+        let result = fs.stat(&path, &mut stat);
+
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_fstat() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin", "cat"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+        let result = fs.fstat(fd, &mut stat);
+
+        assert_eq!(result, Some(0));
+        assert_eq!(stat.st_size, 16); // "cat_content_here" = 16 bytes
+    }
+
+    #[test]
+    fn test_fstat_invalid_fd() {
+        let fs = create_test_fs();
+        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+        let result = fs.fstat(9999, &mut stat);
+
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_lstat() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin", "ls"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+        let result = fs.lstat(&path, &mut stat);
+
+        assert_eq!(result, Some(0));
+    }
+
+    #[test]
+    fn test_opendir() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let dir = fs.opendir(&path);
+        assert!(dir.is_some());
+
+        let dir = dir.unwrap();
+        assert!(dir.fd >= 0);
+    }
+
+    #[test]
+    fn test_opendir_file_fails() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin", "ls"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let dir = fs.opendir(&path);
+        assert!(dir.is_none());
+    }
+
+    #[test]
+    fn test_opendir_nonexistent() {
+        let fs = create_test_fs();
+        let path = vec!["nonexistent"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let dir = fs.opendir(&path);
+        assert!(dir.is_none());
+    }
+
+    #[test]
+    fn test_readdir() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let mut dir = fs.opendir(&path).unwrap();
+
+        // Read directory entries
+        let mut entries = Vec::new();
+        loop {
+            let dirent = fs.readdir(&mut dir);
+            assert!(dirent.is_ok());
+
+            let dirent_ptr = dirent.unwrap();
+            if dirent_ptr.is_null() {
+                break;
+            }
+
+            let dirent = unsafe { &*dirent_ptr };
+            let name_bytes: Vec<u8> = dirent
+                .d_name
+                .iter()
+                .take_while(|&&c| c != 0)
+                .map(|&c| c as u8)
+                .collect();
+            let name = String::from_utf8_lossy(&name_bytes).to_string();
+            entries.push(name);
+
+            // Free the dirent
+            unsafe { drop(Box::from_raw(dirent_ptr)) };
+        }
+
+        assert!(entries.contains(&"cat".to_string()));
+        assert!(entries.contains(&"ls".to_string()));
+        assert!(entries.contains(&"fuga".to_string()));
+    }
+
+    #[test]
+    fn test_closedir() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let mut dir = fs.opendir(&path).unwrap();
+        let fd = dir.fd;
+
+        assert!(fs.is_fd_exists(fd));
+
+        let result = fs.closedir(&dir);
+        assert_eq!(result, Ok(()));
+        assert!(!fs.is_fd_exists(fd));
+        assert_eq!(fs.readdir(&mut dir), Err(FsError::BadFd));
+    }
+
+    #[test]
+    fn test_rewinddir() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let mut dir = fs.opendir(&path).unwrap();
+
+        // Read first entry
+        let first_entry = fs.readdir(&mut dir).unwrap();
+        assert!(!first_entry.is_null());
+        let first_name: Vec<u8> = unsafe { &*first_entry }
+            .d_name
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as u8)
+            .collect();
+        unsafe { drop(Box::from_raw(first_entry)) };
+
+        // Read second entry
+        let _ = fs.readdir(&mut dir);
+
+        // Rewind
+        fs.rewinddir(&mut dir);
+
+        // Read first entry again
+        let first_again = fs.readdir(&mut dir).unwrap();
+        assert!(!first_again.is_null());
+        let first_again_name: Vec<u8> = unsafe { &*first_again }
+            .d_name
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as u8)
+            .collect();
+        unsafe { drop(Box::from_raw(first_again)) };
+
+        assert_eq!(first_name, first_again_name);
+    }
+
+    #[test]
+    fn test_fdopendir() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+        let dir = fs.fdopendir(fd);
 
-    pub fn readdir(&self, dir: &mut FsDir) -> Option<*mut libc::dirent> {
-        let fd_map = self.fd_map.read().unwrap();
-        match fd_map.get(&dir.fd) {
-            Some(FileType::Directory { entries, .. }) => {
-                if dir.offset >= entries.len() as u64 {
-                    return Some(std::ptr::null_mut());
-                }
-                let full_path = &entries[dir.offset as usize];
-                let full_path = full_path
-                    .iter()
-                    .map(|s| s.as_os_str())
-                    .collect::<Vec<&OsStr>>();
-
-                let file_type = match self.get_file_type_from_path(&full_path) {
-                    Some(t) => match t {
-                        FileType::File { .. } => libc::DT_REG,
-                        FileType::Directory { .. } => libc::DT_DIR,
-                    },
-                    None => unreachable!(),
-                };
-                let inode = self.get_inode_from_path(&full_path);
-                let dirent = Self::create_dirent(inode, file_type, full_path);
+        assert!(dir.is_some());
+        let dir = dir.unwrap();
+        assert_eq!(dir.fd, fd);
+    }
 
-                dir.offset += 1;
+    #[test]
+    fn test_fdopendir_file_fails() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin", "ls"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
 
-                let dirent = Box::new(dirent);
-                Some(Box::into_raw(dirent))
-            }
-            _ => None,
-        }
-    }
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+        let dir = fs.fdopendir(fd);
 
-    #[cfg(target_os = "linux")]
-    fn create_dirent(inode: u64, file_type: u8, full_path: Vec<&OsStr>) -> libc::dirent {
-        let mut buf: DirEntryName = [0; 256];
-        let last_path = full_path.last().unwrap();
-        let convert_path: Vec<_> = last_path.as_bytes().iter().map(convert_byte).collect();
-        buf[..last_path.len()].copy_from_slice(&convert_path);
-
-        libc::dirent {
-            d_ino: inode,
-            d_off: 0,    // TODO
-            d_reclen: 0, // TODO
-            d_type: file_type,
-            d_name: buf,
-        }
+        assert!(dir.is_none());
     }
 
-    #[cfg(target_os = "macos")]
-    fn create_dirent(inode: u64, file_type: u8, full_path: Vec<&OsStr>) -> libc::dirent {
-        let mut buf: DirEntryName = [0; 1024];
-        let last_path = full_path.last().unwrap();
-        let convert_path: Vec<i8> = last_path.as_bytes().iter().map(convert_byte).collect();
-        buf[..last_path.len()].copy_from_slice(&convert_path);
-
-        libc::dirent {
-            d_ino: inode,
-            d_reclen: 0, // TODO
-            d_type: file_type,
-            d_name: buf,
-            d_seekoff: 0, // TODO
-            d_namlen: last_path.len() as u16,
-        }
-    }
+    #[test]
+    fn test_is_fd_exists() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin", "ls"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
 
-    pub fn closedir(&self, dir: &FsDir) -> i32 {
-        self.close(dir.fd)
-    }
+        assert!(!fs.is_fd_exists(9999));
 
-    pub fn opendir(&self, path: &Vec<&OsStr>) -> Option<FsDir> {
-        match self.get_file_type_from_path(path) {
-            Some(file_type @ FileType::Directory { .. }) => {
-                let fd = unsafe { libc::dup(0) };
-                self.fd_map.write().unwrap().insert(fd, file_type);
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+        assert!(fs.is_fd_exists(fd));
 
-                Some(FsDir { fd, offset: 0 })
-            }
-            _ => None,
-        }
+        fs.close(fd).unwrap();
+        assert!(!fs.is_fd_exists(fd));
     }
 
-    pub fn rewinddir(&self, dir: &mut FsDir) {
-        dir.offset = 0;
-    }
-}
+    #[test]
+    fn test_is_dir_exists() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
 
-impl<'a> Drop for Fs<'a> {
-    fn drop(&mut self) {
-        let fd_map = self.fd_map.read().unwrap();
-        for fd in fd_map.keys() {
-            unsafe { libc::close(*fd) };
-        }
+        let dir = fs.opendir(&path).unwrap();
+
+        assert!(fs.is_dir_exists(&dir));
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    #[test]
+    fn test_is_dir_exists_from_path() {
+        let fs = create_test_fs();
 
-    fn create_test_fs() -> Fs<'static> {
-        let mut builder: TrieBuilder<&OsStr, &[u8]> = TrieBuilder::new();
-        let ls = vec!["usr", "bin", "ls"]
+        let dir_path = vec!["usr", "bin"]
             .into_iter()
             .map(OsStr::new)
             .collect::<Vec<_>>();
-        let cat = vec!["usr", "bin", "cat"]
+        assert!(fs.is_dir_exists_from_path(&dir_path));
+
+        let file_path = vec!["usr", "bin", "ls"]
             .into_iter()
             .map(OsStr::new)
             .collect::<Vec<_>>();
-        let hoge = vec!["usr", "bin", "hoge", "fuga"]
+        assert!(!fs.is_dir_exists_from_path(&file_path));
+
+        let nonexistent = vec!["nonexistent"]
             .into_iter()
             .map(OsStr::new)
             .collect::<Vec<_>>();
-        let fuga = vec!["usr", "bin", "fuga"]
+        assert!(!fs.is_dir_exists_from_path(&nonexistent));
+    }
+
+    #[test]
+    fn test_file_read() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin", "ls"]
             .into_iter()
             .map(OsStr::new)
             .collect::<Vec<_>>();
-        let empty = vec!["usr", "empty"]
+
+        let ptr = fs.file_read(&path);
+        assert!(ptr.is_some());
+
+        let ptr = ptr.unwrap();
+        let content = unsafe { std::slice::from_raw_parts(ptr, 10) };
+        assert_eq!(content, b"ls_content");
+    }
+
+    #[test]
+    fn test_file_slice() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin", "cat"]
             .into_iter()
             .map(OsStr::new)
             .collect::<Vec<_>>();
 
-        builder.push(&ls, b"ls_content");
-        builder.push(&cat, b"cat_content_here");
-        builder.push(&hoge, b"hoge_fuga_content");
-        builder.push(&fuga, b"fuga_content");
-        builder.push(&empty, b"");
+        let (ptr, len) = fs.file_slice(&path).unwrap();
+        assert_eq!(len, 16);
+        let content = unsafe { std::slice::from_raw_parts(ptr, len) };
+        assert_eq!(content, b"cat_content_here");
 
-        Fs::new(builder)
+        let empty = vec![OsStr::new("usr"), OsStr::new("empty")];
+        assert_eq!(fs.file_slice(&empty).unwrap().1, 0);
+        assert_eq!(fs.file_slice(&vec![OsStr::new("usr")]), None);
+        assert_eq!(fs.file_slice(&vec![OsStr::new("nonexistent")]), None);
     }
 
     #[test]
-    fn test_open_existing_file() {
+    fn test_open_at() {
         let fs = create_test_fs();
         let path = vec!["usr", "bin", "ls"]
             .into_iter()
             .map(OsStr::new)
             .collect::<Vec<_>>();
 
-        let fd = fs.open(&path);
+        let fd = fs.open_at(&path);
         assert!(fd.is_some());
-        assert!(fd.unwrap() >= 0);
+
+        let fd = fd.unwrap();
+        assert!(fs.is_fd_exists(fd));
     }
 
     #[test]
-    fn test_open_nonexistent_file() {
+    fn test_multiple_opens() {
         let fs = create_test_fs();
-        let path = vec!["usr", "bin", "nonexistent"]
+        let path = vec!["usr", "bin", "ls"]
             .into_iter()
             .map(OsStr::new)
             .collect::<Vec<_>>();
 
-        let fd = fs.open(&path);
-        assert!(fd.is_none());
+        let fd1 = fs.open(&path, libc::O_RDONLY).unwrap();
+        let fd2 = fs.open(&path, libc::O_RDONLY).unwrap();
+
+        assert_ne!(fd1, fd2);
+        assert!(fs.is_fd_exists(fd1));
+        assert!(fs.is_fd_exists(fd2));
+
+        fs.close(fd1).unwrap();
+        assert!(!fs.is_fd_exists(fd1));
+        assert!(fs.is_fd_exists(fd2));
     }
 
     #[test]
-    fn test_read_file() {
+    fn test_nested_directory() {
         let fs = create_test_fs();
-        let path = vec!["usr", "bin", "ls"]
+        let path = vec!["usr", "bin", "hoge"]
             .into_iter()
             .map(OsStr::new)
             .collect::<Vec<_>>();
 
-        let fd = fs.open(&path).unwrap();
+        assert!(fs.is_dir_exists_from_path(&path));
+    }
+
+    #[test]
+    fn test_open_path() {
+        let fs = create_test_fs();
+
+        let fd = fs.open_path(Path::new("usr/bin/ls")).unwrap();
         let mut buf = [0u8; 128];
-        let read_size = fs.read(fd, &mut buf);
+        assert_eq!(fs.read(fd, &mut buf), Some(10));
+        assert_eq!(&buf[..10], b"ls_content");
+
+        assert_eq!(
+            fs.open_path(Path::new("usr/bin/nonexistent")),
+            Err(FsError::NotFound)
+        );
+    }
+
+    #[test]
+    fn test_stat_path_and_lstat_path() {
+        let fs = create_test_fs();
+        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+
+        assert_eq!(fs.stat_path(Path::new("usr/bin/cat"), &mut stat), Ok(()));
+        assert_eq!(stat.st_size, 16);
+
+        assert_eq!(fs.lstat_path(Path::new("usr/bin"), &mut stat), Ok(()));
+        assert_eq!(stat.st_mode & libc::S_IFMT, libc::S_IFDIR);
+
+        assert_eq!(
+            fs.stat_path(Path::new("nonexistent"), &mut stat),
+            Err(FsError::NotFound)
+        );
+    }
+
+    #[test]
+    fn test_path_with_current_dir() {
+        let fs = create_test_fs();
+        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+
+        assert_eq!(fs.stat_path(Path::new("./usr/bin/ls"), &mut stat), Ok(()));
+        assert_eq!(stat.st_size, 10);
+
+        assert_eq!(fs.stat_path(Path::new("usr/./bin/ls"), &mut stat), Ok(()));
+        assert_eq!(stat.st_size, 10);
+
+        let dir = fs.opendir_path(Path::new("./usr/bin")).unwrap();
+        assert!(fs.is_dir_exists(&dir));
+    }
+
+    #[test]
+    fn test_opendir_path() {
+        let fs = create_test_fs();
+
+        let dir = fs.opendir_path(Path::new("usr/bin")).unwrap();
+        assert!(fs.is_dir_exists(&dir));
+
+        assert_eq!(
+            fs.opendir_path(Path::new("usr/bin/ls")).unwrap_err(),
+            FsError::NotDirectory
+        );
+        assert_eq!(
+            fs.opendir_path(Path::new("nonexistent")).unwrap_err(),
+            FsError::NotFound
+        );
+    }
+
+    #[test]
+    fn test_vfs_root_is_directory() {
+        let fs = create_test_fs();
+        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+
+        let root = vec![OsStr::new("/")];
+        assert_eq!(fs.stat(&root, &mut stat), Some(0));
+        assert_eq!(stat.st_mode & libc::S_IFMT, libc::S_IFDIR);
+
+        assert_eq!(fs.stat(&vec![], &mut stat), Some(0));
+        assert_eq!(stat.st_mode & libc::S_IFMT, libc::S_IFDIR);
+    }
+
+    #[test]
+    fn test_opendir_vfs_root() {
+        let fs = create_test_fs();
+        let root = vec![OsStr::new("/")];
+
+        let mut dir = fs.opendir(&root).unwrap();
+        let mut entries = Vec::new();
+        loop {
+            let dirent_ptr = fs.readdir(&mut dir).unwrap();
+            if dirent_ptr.is_null() {
+                break;
+            }
+
+            let name_bytes: Vec<u8> = unsafe { &*dirent_ptr }
+                .d_name
+                .iter()
+                .take_while(|&&c| c != 0)
+                .map(|&c| c as u8)
+                .collect();
+            entries.push(String::from_utf8_lossy(&name_bytes).to_string());
+
+            unsafe { drop(Box::from_raw(dirent_ptr)) };
+        }
+
+        assert_eq!(entries, [".", "..", "usr"]);
+    }
+
+    #[test]
+    fn test_root_directory() {
+        let fs = create_test_fs();
+        let path = vec!["usr"].into_iter().map(OsStr::new).collect::<Vec<_>>();
+
+        assert!(fs.is_dir_exists_from_path(&path));
+    }
+
+    #[test]
+    fn test_root_entries() {
+        let fs = create_test_fs();
+
+        assert_eq!(fs.root_entries(), vec![OsString::from("usr")]);
+    }
+
+    #[test]
+    fn test_stat_cache() {
+        let cache = StatCache::new();
+        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+        stat.st_size = 42;
+        let stat = CachedStat::from_stat(&stat);
+
+        let paths = (0..256)
+            .map(|i| vec![OsString::from("usr"), OsString::from(format!("file{i}"))])
+            .collect::<Vec<_>>();
+        for path in &paths {
+            assert!(cache.get(path).is_none());
+            cache.insert(path.clone(), stat);
+        }
+
+        for path in &paths {
+            assert_eq!(cache.get(path).unwrap().size, 42);
+        }
+        assert!(cache.get(&[OsString::from("usr")]).is_none());
+    }
+
+    #[test]
+    fn test_entry_paths() {
+        let fs = create_test_fs();
+        let path = vec!["usr", "bin"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+
+        let mut dir = fs.opendir(&path).unwrap();
+        let mut readdir_names = Vec::new();
+        loop {
+            let dirent_ptr = fs.readdir(&mut dir).unwrap();
+            if dirent_ptr.is_null() {
+                break;
+            }
+
+            let dirent = unsafe { Box::from_raw(dirent_ptr) };
+            let name = unsafe { std::ffi::CStr::from_ptr(dirent.d_name.as_ptr()) };
+            readdir_names.push(OsStr::from_bytes(name.to_bytes()).to_os_string());
+        }
+
+        let entry_names = dir
+            .entry_paths(&fs)
+            .unwrap()
+            .iter()
+            .map(|path| path.last().unwrap().to_os_string())
+            .collect::<Vec<_>>();
+        // Only children have paths, not `.` and `..`
+        assert_eq!(entry_names, readdir_names[2..]);
 
-        assert!(read_size.is_some());
-        assert_eq!(read_size.unwrap(), 10);
-        assert_eq!(&buf[..10], b"ls_content");
+        fs.closedir(&dir).unwrap();
+        assert!(dir.entry_paths(&fs).is_none());
     }
 
     #[test]
-    fn test_read_partial() {
+    fn test_read_at() {
         let fs = create_test_fs();
         let path = vec!["usr", "bin", "cat"]
             .into_iter()
             .map(OsStr::new)
             .collect::<Vec<_>>();
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
 
-        let fd = fs.open(&path).unwrap();
-
-        // Read in small chunks
         let mut buf = [0u8; 4];
-        let read_size = fs.read(fd, &mut buf).unwrap();
-        assert_eq!(read_size, 4);
-        assert_eq!(&buf, b"cat_");
-
-        let read_size = fs.read(fd, &mut buf).unwrap();
-        assert_eq!(read_size, 4);
+        assert_eq!(fs.read_at(fd, &mut buf, 4), Some(4));
         assert_eq!(&buf, b"cont");
+
+        // Clamped to the end of the file
+        let mut buf = [0u8; 8];
+        assert_eq!(fs.read_at(fd, &mut buf, 12), Some(4));
+        assert_eq!(&buf[..4], b"here");
+        assert_eq!(fs.read_at(fd, &mut buf, 100), Some(0));
+
+        // The fd offset is left untouched
+        let mut buf = [0u8; 3];
+        assert_eq!(fs.read(fd, &mut buf), Some(3));
+        assert_eq!(&buf, b"cat");
+
+        fs.close(fd).unwrap();
+        assert_eq!(fs.read_at(fd, &mut buf, 0), None);
     }
 
     #[test]
-    fn test_read_empty_file() {
+    fn test_symlink_target() {
         let fs = create_test_fs();
-        let path = vec!["usr", "empty"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
+        let file = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("ls")];
+        let dir = vec![OsStr::new("usr"), OsStr::new("bin")];
+        let missing = vec![OsStr::new("usr"), OsStr::new("nonexistent")];
 
-        let fd = fs.open(&path).unwrap();
-        let mut buf = [0u8; 128];
-        let read_size = fs.read(fd, &mut buf);
-
-        assert!(read_size.is_some());
-        assert_eq!(read_size.unwrap(), 0);
+        assert_eq!(fs.symlink_target(&file), None);
+        assert_eq!(fs.symlink_target(&dir), None);
+        assert_eq!(fs.symlink_target(&missing), None);
     }
 
     #[test]
-    fn test_read_eof() {
+    fn test_open_after_stat() {
         let fs = create_test_fs();
-        let path = vec!["usr", "bin", "ls"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
+        let cat = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("cat")];
+        let ls = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("ls")];
+        let mut stat_buf = unsafe { std::mem::zeroed::<libc::stat>() };
+
+        assert_eq!(fs.stat(&cat, &mut stat_buf), Some(0));
+
+        // Same path as the preceding stat
+        let fd = fs.open(&cat, libc::O_RDONLY).unwrap();
+        let mut buf = [0u8; 32];
+        assert_eq!(fs.read(fd, &mut buf), Some(16));
+        assert_eq!(&buf[..16], b"cat_content_here");
+        fs.close(fd).unwrap();
+
+        // Opening it again starts from the beginning
+        let fd = fs.open(&cat, libc::O_RDONLY).unwrap();
+        assert_eq!(fs.read(fd, &mut buf), Some(16));
+        fs.close(fd).unwrap();
+
+        // A different path than the preceding stat
+        let fd = fs.open(&ls, libc::O_RDONLY).unwrap();
+        assert_eq!(fs.read(fd, &mut buf), Some(10));
+        assert_eq!(&buf[..10], b"ls_content");
+        fs.close(fd).unwrap();
+    }
 
-        let fd = fs.open(&path).unwrap();
-        let mut buf = [0u8; 128];
+    #[test]
+    fn test_repeated_open_reuses_lookup() {
+        use std::sync::atomic::Ordering;
 
-        // First read
-        fs.read(fd, &mut buf);
+        let fs = create_test_fs();
+        let path = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("cat")];
+
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+        fs.close(fd).unwrap();
+        let lookups = fs.lookup_count.load(Ordering::Relaxed);
+
+        for _ in 0..10 {
+            let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+            let mut buf = [0u8; 32];
+            assert_eq!(fs.read(fd, &mut buf), Some(16));
+            assert_eq!(&buf[..16], b"cat_content_here");
+            fs.close(fd).unwrap();
+        }
 
-        // Second read should return 0 (EOF)
-        let read_size = fs.read(fd, &mut buf).unwrap();
-        assert_eq!(read_size, 0);
+        assert_eq!(fs.lookup_count.load(Ordering::Relaxed), lookups);
     }
 
     #[test]
-    fn test_read_invalid_fd() {
+    fn test_open_directory_flag_single_lookup() {
+        use std::sync::atomic::Ordering;
+
         let fs = create_test_fs();
-        let mut buf = [0u8; 128];
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY;
+
+        // The type is checked against the lookup `open` does anyway
+        let dir = vec![OsStr::new("usr"), OsStr::new("bin")];
+        let fd = fs.open(&dir, flags).unwrap();
+        assert_eq!(fs.lookup_count.load(Ordering::Relaxed), 1);
+        fs.close(fd).unwrap();
+
+        let file = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("ls")];
+        assert_eq!(fs.open(&file, flags), Err(FsError::NotDirectory));
+        assert_eq!(fs.lookup_count.load(Ordering::Relaxed), 2);
+        assert_eq!(fs.open_fd_count(), 0);
+    }
 
-        let result = fs.read(9999, &mut buf);
-        assert!(result.is_none());
+    #[test]
+    fn test_open_templates_bounded() {
+        use std::sync::atomic::Ordering;
+
+        // Spread over directories, since the trie scans siblings one by one
+        let lib_path = |i: usize| format!("/lib/{}/{}.rb", i / 64, i % 64);
+        let files = (0..OPEN_TEMPLATES_MAX + 10)
+            .map(|i| (lib_path(i).into_bytes(), Vec::new()))
+            .collect::<Vec<_>>();
+        let fs = Fs::from_bytes(Box::leak(files.into_boxed_slice()));
+
+        for i in 0..OPEN_TEMPLATES_MAX + 10 {
+            let path = PathBuf::from(lib_path(i));
+            let fd = fs
+                .open(&Fs::path_components(&path), libc::O_RDONLY)
+                .unwrap();
+            fs.close(fd).unwrap();
+        }
+        assert!(fs.open_templates.read().unwrap().len() <= OPEN_TEMPLATES_MAX);
+
+        // The latest paths are still served without a lookup
+        let path = PathBuf::from(lib_path(OPEN_TEMPLATES_MAX + 9));
+        let lookups = fs.lookup_count.load(Ordering::Relaxed);
+        let fd = fs
+            .open(&Fs::path_components(&path), libc::O_RDONLY)
+            .unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(fs.lookup_count.load(Ordering::Relaxed), lookups);
     }
 
     #[test]
-    fn test_close() {
+    fn test_total_size() {
         let fs = create_test_fs();
-        let path = vec!["usr", "bin", "ls"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
 
-        let fd = fs.open(&path).unwrap();
-        assert!(fs.is_fd_exists(fd));
+        let expected = [
+            "ls_content",
+            "cat_content_here",
+            "hoge_fuga_content",
+            "fuga_content",
+            "",
+        ]
+        .iter()
+        .map(|content| content.len() as u64)
+        .sum::<u64>();
+        assert_eq!(fs.total_size(), expected);
+        assert_eq!(fs.total_size(), expected);
+    }
 
-        let result = fs.close(fd);
-        assert_eq!(result, 0);
-        assert!(!fs.is_fd_exists(fd));
+    #[test]
+    fn test_hardlink_count() {
+        static SHARED: &[u8] = b"shared_content";
+
+        let mut builder: TrieBuilder<&OsStr, &[u8]> = TrieBuilder::new();
+        let first = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("first")];
+        let second = vec![OsStr::new("usr"), OsStr::new("lib"), OsStr::new("second")];
+        let other = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("other")];
+        builder.push(&first, SHARED);
+        builder.push(&second, SHARED);
+        builder.push(&other, b"other_content");
+        let fs = Fs::new(builder);
+
+        assert_eq!(fs.hardlink_count(&first), 2);
+        assert_eq!(fs.hardlink_count(&second), 2);
+        assert_eq!(fs.hardlink_count(&other), 1);
+        assert_eq!(fs.hardlink_count(&vec![OsStr::new("usr")]), 1);
+        assert_eq!(fs.hardlink_count(&vec![OsStr::new("missing")]), 0);
+        // Only the paths sharing contents are kept
+        assert_eq!(fs.link_inodes.len(), 2);
+        assert_eq!(fs.hardlinks.len(), 1);
+
+        let mut first_stat = unsafe { std::mem::zeroed::<libc::stat>() };
+        let mut second_stat = unsafe { std::mem::zeroed::<libc::stat>() };
+        let mut other_stat = unsafe { std::mem::zeroed::<libc::stat>() };
+        fs.stat(&first, &mut first_stat).unwrap();
+        fs.stat(&second, &mut second_stat).unwrap();
+        fs.stat(&other, &mut other_stat).unwrap();
+
+        assert_eq!(first_stat.st_nlink, 2);
+        assert_eq!(second_stat.st_nlink, 2);
+        assert_eq!(first_stat.st_ino, second_stat.st_ino);
+        assert_eq!(other_stat.st_nlink, 1);
+        assert_ne!(other_stat.st_ino, first_stat.st_ino);
+        assert_eq!(
+            fs.path_for_inode(second_stat.st_ino as u64),
+            Some(PathBuf::from("usr/bin/first"))
+        );
     }
 
     #[test]
-    fn test_storage() {
+    fn test_open_hook() {
+        let fs = Arc::new(create_test_fs());
+        let opened = Arc::new(Mutex::new(Vec::new()));
+
+        let hook_opened = Arc::clone(&opened);
+        let hook_fs = Arc::clone(&fs);
+        fs.set_open_hook(Box::new(move |fd, path| {
+            // The fd is already registered when the hook runs
+            assert!(hook_fs.is_fd_exists(fd));
+            hook_opened.lock().unwrap().push((fd, path.to_vec()));
+        }));
+
+        let path = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("ls")];
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+        let missing = vec![OsStr::new("usr"), OsStr::new("missing")];
+        assert!(fs.open(&missing, libc::O_RDONLY).is_err());
+
+        assert_eq!(
+            *opened.lock().unwrap(),
+            vec![(
+                fd,
+                vec![
+                    OsString::from("usr"),
+                    OsString::from("bin"),
+                    OsString::from("ls")
+                ]
+            )]
+        );
+        fs.close(fd).unwrap();
+
+        fs.clear_open_hook();
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+        assert_eq!(opened.lock().unwrap().len(), 1);
+        fs.close(fd).unwrap();
+    }
+
+    #[test]
+    fn test_open_hook_clears_itself() {
+        let fs = Arc::new(create_test_fs());
+        let calls = Arc::new(Mutex::new(0));
+
+        let hook_calls = Arc::clone(&calls);
+        let hook_fs = Arc::clone(&fs);
+        fs.set_open_hook(Box::new(move |_, _| {
+            *hook_calls.lock().unwrap() += 1;
+            hook_fs.clear_open_hook();
+        }));
+
+        let path = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("ls")];
+        for _ in 0..2 {
+            let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+            fs.close(fd).unwrap();
+        }
+        assert_eq!(*calls.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_readdir_name_too_long() {
+        let long_name: &'static str = "a".repeat(1100).leak();
         let mut builder: TrieBuilder<&OsStr, &[u8]> = TrieBuilder::new();
-        let ls = vec!["usr", "bin", "ls"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
-        let cat = vec!["usr", "bin", "cat"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
-        let hoge = vec!["usr", "bin", "hoge", "fuga"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
-        let fuga = vec!["usr", "bin", "fuga"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
+        let long = vec![OsStr::new("usr"), OsStr::new(long_name)];
+        let short = vec![OsStr::new("usr"), OsStr::new("short")];
+        builder.push(&long, b"long");
+        builder.push(&short, b"short");
+        let fs = Fs::new(builder);
 
-        builder.push(&ls, &[1, 2, 3]);
-        builder.push(&cat, &[4, 5, 6]);
-        builder.push(&hoge, &[7, 8, 9]);
-        builder.push(&fuga, &[10, 11, 12]);
+        let mut dir = fs.opendir(&vec![OsStr::new("usr")]).unwrap();
+        skip_dots(&fs, &mut dir);
+        assert_eq!(fs.readdir(&mut dir), Err(FsError::NameTooLong));
+
+        // The over-long entry is skipped
+        let dirent_ptr = fs.readdir(&mut dir).unwrap();
+        assert!(!dirent_ptr.is_null());
+        let dirent = unsafe { Box::from_raw(dirent_ptr) };
+        let name = unsafe { std::ffi::CStr::from_ptr(dirent.d_name.as_ptr()) };
+        assert_eq!(name.to_bytes(), b"short");
+
+        assert!(fs.readdir(&mut dir).unwrap().is_null());
+        fs.closedir(&dir).unwrap();
+    }
 
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+    fn test_readdir_name_longer_than_linux_dirent() {
+        let long_name: &'static str = "a".repeat(300).leak();
+        let mut builder: TrieBuilder<&OsStr, &[u8]> = TrieBuilder::new();
+        let long = vec![OsStr::new("usr"), OsStr::new(long_name)];
+        builder.push(&long, b"long");
         let fs = Fs::new(builder);
 
-        let mut hasher = FxHasher::default();
-        ls.hash(&mut hasher);
+        let mut dir = fs.opendir(&vec![OsStr::new("usr")]).unwrap();
+        skip_dots(&fs, &mut dir);
+        assert_eq!(fs.readdir(&mut dir), Err(FsError::NameTooLong));
+        assert!(fs.readdir(&mut dir).unwrap().is_null());
+        fs.closedir(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_readdir_dirent_layout() {
+        let fs = create_test_fs();
+        let path = vec![OsStr::new("usr"), OsStr::new("bin")];
+
+        let mut dir = fs.opendir(&path).unwrap();
+        skip_dots(&fs, &mut dir);
+        let dirent = unsafe { Box::from_raw(fs.readdir(&mut dir).unwrap()) };
 
         assert_eq!(
-            fs.get_file_type_from_path(&ls),
-            Some(FileType::File {
-                file: &[1, 2, 3],
-                offset: 0,
-                inode: hasher.finish()
-            })
+            dirent.d_reclen as usize,
+            (std::mem::offset_of!(libc::dirent, d_name) + b"cat\0".len()).next_multiple_of(8)
+        );
+        assert_eq!(dirent.d_type, libc::DT_REG);
+        assert_eq!(
+            dirent.d_ino,
+            fs.get_inode_from_path(&vec![
+                OsStr::new("usr"),
+                OsStr::new("bin"),
+                OsStr::new("cat")
+            ])
         );
+        let name = unsafe { std::ffi::CStr::from_ptr(dirent.d_name.as_ptr()) };
+        assert_eq!(name.to_bytes(), b"cat");
 
-        let mut hasher = FxHasher::default();
-        let search_path = vec!["usr", "bin"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
+        fs.closedir(&dir).unwrap();
+    }
 
-        search_path.clone().hash(&mut hasher);
+    #[test]
+    #[cfg(target_os = "freebsd")]
+    fn test_readdir_dirent_layout_freebsd() {
+        let fs = create_test_fs();
+        let path = vec![OsStr::new("usr"), OsStr::new("bin")];
+
+        let mut dir = fs.opendir(&path).unwrap();
+        skip_dots(&fs, &mut dir);
+        let dirent = unsafe { Box::from_raw(fs.readdir(&mut dir).unwrap()) };
 
         assert_eq!(
-            fs.get_file_type_from_path(&search_path.clone()),
-            Some(FileType::Directory {
-                inode: hasher.finish(),
-                entries: vec![
-                    vec!["usr", "bin", "cat"]
-                        .into_iter()
-                        .map(OsString::from)
-                        .collect(),
-                    vec!["usr", "bin", "fuga"]
-                        .into_iter()
-                        .map(OsString::from)
-                        .collect(),
-                    vec!["usr", "bin", "hoge"]
-                        .into_iter()
-                        .map(OsString::from)
-                        .collect(),
-                    vec!["usr", "bin", "ls"]
-                        .into_iter()
-                        .map(OsString::from)
-                        .collect(),
-                ]
-            })
+            dirent.d_reclen as usize,
+            (std::mem::offset_of!(libc::dirent, d_name) + b"cat\0".len()).next_multiple_of(8)
+        );
+        assert_eq!(dirent.d_type, libc::DT_REG);
+        assert_eq!(dirent.d_namlen as usize, 3);
+        assert_eq!(
+            dirent.d_fileno as u64,
+            fs.get_inode_from_path(&vec![
+                OsStr::new("usr"),
+                OsStr::new("bin"),
+                OsStr::new("cat")
+            ])
         );
+        let name = unsafe { std::ffi::CStr::from_ptr(dirent.d_name.as_ptr()) };
+        assert_eq!(name.to_bytes(), b"cat");
 
-        let search_path = "usr/bin/cat"
-            .split('/')
-            .map(OsStr::new)
-            .collect::<Vec<&OsStr>>();
-        let mut hasher = FxHasher::default();
-        vec!["usr", "bin", "cat"]
-            .iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>()
-            .hash(&mut hasher);
+        fs.closedir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_dirent_record() {
+        let record = DirentRecord::new(42, libc::DT_DIR, b"lib", 19, 256).unwrap();
+        assert_eq!(record.inode, 42);
+        assert_eq!(record.name, b"lib");
+        assert_eq!(record.d_type, libc::DT_DIR);
+        // 19 byte header + "lib" + NUL, rounded up to 8
+        assert_eq!(record.reclen, 24);
+
+        let record = DirentRecord::new(1, libc::DT_REG, b"hello.rb", 19, 256).unwrap();
+        assert_eq!(record.reclen, 32);
+        assert_eq!(record.d_type, libc::DT_REG);
+    }
 
+    #[test]
+    fn test_dirent_record_name_too_long() {
+        let name = [b'a'; 8];
+
+        // The name and its NUL must fit
         assert_eq!(
-            fs.get_file_type_from_path(&search_path),
-            Some(FileType::File {
-                file: &[4, 5, 6],
-                offset: 0,
-                inode: hasher.finish()
-            })
+            DirentRecord::new(1, libc::DT_REG, &name, 19, 8),
+            Err(FsError::NameTooLong)
+        );
+        assert_eq!(
+            DirentRecord::new(1, libc::DT_REG, &name[..7], 19, 8)
+                .unwrap()
+                .name,
+            &name[..7]
         );
     }
 
     #[test]
-    fn test_stat_file() {
+    fn test_concurrent_reads_on_separate_fds() {
         let fs = create_test_fs();
-        let path = vec!["usr", "bin", "ls"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
-
-        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
-        let result = fs.stat(&path, &mut stat);
+        let cat = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("cat")];
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let fd = fs.open(&cat, libc::O_RDONLY).unwrap();
+                    let mut contents = Vec::new();
+                    let mut buf = [0u8; 3];
+                    loop {
+                        match fs.read(fd, &mut buf).unwrap() {
+                            0 => break,
+                            n => contents.extend_from_slice(&buf[..n as usize]),
+                        }
+                    }
+                    assert_eq!(contents, b"cat_content_here");
+                    fs.close(fd).unwrap();
+                });
+            }
+        });
+    }
 
-        assert_eq!(result, Some(0));
-        assert_eq!(stat.st_size, 10); // "ls_content" = 10 bytes
-        assert_eq!(stat.st_mode & libc::S_IFMT, libc::S_IFREG);
+    #[test]
+    fn test_concurrent_reads_on_one_fd() {
+        // The file holds consecutive u32s, read one per call, so an overlap shows up
+        // as a duplicate and a gap as a missing number
+        const COUNT: u32 = 100_000;
+        let contents = (0..COUNT).flat_map(u32::to_le_bytes).collect::<Vec<u8>>();
+        let files = vec![(b"/data".to_vec(), contents)];
+        let fs = Fs::from_bytes(&files);
+        let fd = fs
+            .open(&vec![OsStr::new("/"), OsStr::new("data")], libc::O_RDONLY)
+            .unwrap();
+
+        let mut read = std::thread::scope(|scope| {
+            let readers = (0..2)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut read = Vec::new();
+                        let mut buf = [0u8; 4];
+                        while fs.read(fd, &mut buf).unwrap() == 4 {
+                            read.push(u32::from_le_bytes(buf));
+                        }
+                        read
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            readers
+                .into_iter()
+                .flat_map(|reader| reader.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        read.sort();
+        assert_eq!(read, (0..COUNT).collect::<Vec<_>>());
+        fs.close(fd).unwrap();
     }
 
     #[test]
-    fn test_stat_directory() {
+    fn test_file_offset() {
         let fs = create_test_fs();
-        let path = vec!["usr", "bin"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
+        let path = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("cat")];
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+        assert_eq!(fs.file_offset(fd), Some(0));
 
-        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
-        let result = fs.stat(&path, &mut stat);
+        let mut buf = [0u8; 4];
+        fs.read(fd, &mut buf).unwrap();
+        assert_eq!(fs.file_offset(fd), Some(4));
 
-        assert_eq!(result, Some(0));
-        assert_eq!(stat.st_mode & libc::S_IFMT, libc::S_IFDIR);
+        assert_eq!(fs.set_file_offset(fd, 12), Some(()));
+        assert_eq!(fs.read(fd, &mut buf), Some(4));
+        assert_eq!(&buf, b"here");
+
+        // Clamped to the end of the file
+        fs.set_file_offset(fd, 100).unwrap();
+        assert_eq!(fs.file_offset(fd), Some(16));
+
+        fs.close(fd).unwrap();
+        assert_eq!(fs.file_offset(fd), None);
+        assert_eq!(fs.set_file_offset(fd, 0), None);
     }
 
     #[test]
-    fn test_stat_nonexistent() {
+    fn test_add_virtual() {
         let fs = create_test_fs();
-        let path = vec!["nonexistent"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
+        let path = vec![OsStr::new("kompo"), OsStr::new("now")];
+        fs.add_virtual(
+            &path,
+            Box::new(|| {
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap();
+                now.as_nanos().to_string().into_bytes()
+            }),
+        );
 
-        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
-        let result = fs.stat(&path, &mut stat);
+        let read_all = || {
+            let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+            fs.stat(&path, &mut stat).unwrap();
+            assert_eq!(stat.st_mode & libc::S_IFMT, libc::S_IFREG);
+            assert_eq!(stat.st_size, 0);
+
+            let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+            let mut buf = [0u8; 64];
+            let n = fs.read(fd, &mut buf).unwrap() as usize;
+            fs.fstat(fd, &mut stat).unwrap();
+            assert_eq!(stat.st_size, n as i64);
+            fs.close(fd).unwrap();
+            String::from_utf8(buf[..n].to_vec()).unwrap()
+        };
 
-        assert!(result.is_none());
+        let first = read_all();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let second = read_all();
+        assert!(!first.is_empty());
+        assert_ne!(first, second);
+
+        // The parent directory exists and lists the virtual file
+        let kompo = vec![OsStr::new("kompo")];
+        assert!(fs.is_dir_exists_from_path(&kompo));
+        let mut dir = fs.opendir(&kompo).unwrap();
+        skip_dots(&fs, &mut dir);
+        let entry = fs.readdir(&mut dir).unwrap();
+        assert!(!entry.is_null());
+        let name = unsafe { std::ffi::CStr::from_ptr((*entry).d_name.as_ptr()) };
+        assert_eq!(name.to_bytes(), b"now");
+        unsafe { drop(Box::from_raw(entry)) };
+        fs.closedir(&dir).unwrap();
     }
 
     #[test]
-    fn test_fstat() {
+    fn test_add_virtual_generates_on_open_only() {
+        static RUNS: AtomicU64 = AtomicU64::new(0);
         let fs = create_test_fs();
-        let path = vec!["usr", "bin", "cat"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
+        let path = vec![OsStr::new("kompo"), OsStr::new("count")];
+        fs.add_virtual(
+            &path,
+            Box::new(|| {
+                RUNS.fetch_add(1, Ordering::Relaxed);
+                b"counted".to_vec()
+            }),
+        );
 
-        let fd = fs.open(&path).unwrap();
         let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
-        let result = fs.fstat(fd, &mut stat);
-
-        assert_eq!(result, Some(0));
-        assert_eq!(stat.st_size, 16); // "cat_content_here" = 16 bytes
+        fs.stat(&path, &mut stat).unwrap();
+        assert!(fs.contains(&path));
+        assert_eq!(fs.list_dir(&vec![OsStr::new("kompo")]).unwrap(), ["count"]);
+        assert_eq!(RUNS.load(Ordering::Relaxed), 0);
+
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+        fs.close(fd).unwrap();
+        let fd = fs.open_at(&path).unwrap();
+        fs.close(fd).unwrap();
+        assert_eq!(RUNS.load(Ordering::Relaxed), 2);
+
+        // Registering the path again replaces the generator
+        fs.add_virtual(&path, Box::new(|| b"replaced".to_vec()));
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+        assert_eq!(fs.read_to_end(fd).as_deref(), Some(&b"replaced"[..]));
+        fs.close(fd).unwrap();
     }
 
     #[test]
-    fn test_fstat_invalid_fd() {
-        let fs = create_test_fs();
-        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
-        let result = fs.fstat(9999, &mut stat);
+    fn test_verify_content_hash() {
+        let mut blob = b"ls_contentcat_content_here".to_vec();
+        let expected = content_hash(&blob);
+        assert!(verify_content_hash(&blob, expected));
 
-        assert!(result.is_none());
+        blob[3] ^= 1;
+        assert!(!verify_content_hash(&blob, expected));
     }
 
     #[test]
-    fn test_lstat() {
-        let fs = create_test_fs();
-        let path = vec!["usr", "bin", "ls"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
+    fn test_checksum() {
+        let mut builder: TrieBuilder<&OsStr, &[u8]> = TrieBuilder::new();
+        let ls = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("ls")];
+        builder.push(&ls, b"ls_content");
+        let fs = Fs::with_config(
+            builder,
+            FsConfig {
+                checksums: true,
+                ..FsConfig::default()
+            },
+        );
 
-        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
-        let result = fs.lstat(&path, &mut stat);
+        assert_eq!(fs.checksum(&ls), Some(content_hash(b"ls_content")));
+        assert_eq!(fs.checksum(&vec![OsStr::new("usr")]), None);
+        assert_eq!(fs.checksum(&vec![OsStr::new("missing")]), None);
 
-        assert_eq!(result, Some(0));
+        // Not stored unless requested
+        let fs = create_test_fs();
+        assert_eq!(fs.checksum(&ls), None);
     }
 
     #[test]
-    fn test_opendir() {
-        let fs = create_test_fs();
-        let path = vec!["usr", "bin"]
+    fn test_resolve_require() {
+        let mut builder: TrieBuilder<&OsStr, &[u8]> = TrieBuilder::new();
+        let foo = vec!["/", "app", "vendor", "foo.rb"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+        let ext = vec!["/", "app", "lib", "ext.so"]
             .into_iter()
             .map(OsStr::new)
             .collect::<Vec<_>>();
+        let dir = vec!["/", "app", "lib", "bar.rb", "baz.rb"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+        builder.push(&foo, b"foo");
+        builder.push(&ext, b"ext");
+        builder.push(&dir, b"baz");
+        let fs = Fs::new(builder);
 
-        let dir = fs.opendir(&path);
-        assert!(dir.is_some());
+        let load_paths = [OsStr::new("/app/lib"), OsStr::new("/app/vendor")];
+
+        // Found under the second load path
+        assert_eq!(
+            fs.resolve_require("foo", &load_paths),
+            Some(PathBuf::from("/app/vendor/foo.rb"))
+        );
+        assert_eq!(
+            fs.resolve_require("foo.rb", &load_paths),
+            Some(PathBuf::from("/app/vendor/foo.rb"))
+        );
+        assert_eq!(
+            fs.resolve_require("ext", &load_paths),
+            Some(PathBuf::from("/app/lib/ext.so"))
+        );
+        assert_eq!(
+            fs.resolve_require("/app/vendor/foo", &load_paths),
+            Some(PathBuf::from("/app/vendor/foo.rb"))
+        );
 
-        let dir = dir.unwrap();
-        assert!(dir.fd >= 0);
+        // Directories are not features
+        assert_eq!(fs.resolve_require("bar", &load_paths), None);
+        assert_eq!(fs.resolve_require("foo.so", &load_paths), None);
+        assert_eq!(fs.resolve_require("missing", &load_paths), None);
     }
 
     #[test]
-    fn test_opendir_file_fails() {
-        let fs = create_test_fs();
-        let path = vec!["usr", "bin", "ls"]
+    fn test_which() {
+        let mut builder: TrieBuilder<&OsStr, &[u8]> = TrieBuilder::new();
+        let bundle = vec!["/", "app", "lib", "ext.bundle"]
+            .into_iter()
+            .map(OsStr::new)
+            .collect::<Vec<_>>();
+        let rb = vec!["/", "app", "vendor", "ext.rb"]
             .into_iter()
             .map(OsStr::new)
             .collect::<Vec<_>>();
+        builder.push(&bundle, b"bundle");
+        builder.push(&rb, b"rb");
+        let fs = Fs::new(builder);
 
-        let dir = fs.opendir(&path);
-        assert!(dir.is_none());
+        let load_paths = [OsStr::new("/app/lib"), OsStr::new("/app/vendor")];
+
+        // Earlier load paths win over earlier extensions
+        assert_eq!(
+            fs.which("ext", &load_paths, &["rb", "bundle"]),
+            Some(PathBuf::from("/app/lib/ext.bundle"))
+        );
+        assert_eq!(
+            fs.which("ext", &load_paths, &["rb"]),
+            Some(PathBuf::from("/app/vendor/ext.rb"))
+        );
+        assert_eq!(fs.resolve_require("ext", &load_paths[..1]), None);
     }
 
     #[test]
-    fn test_opendir_nonexistent() {
-        let fs = create_test_fs();
-        let path = vec!["nonexistent"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
+    fn test_capped_read_size() {
+        assert_eq!(capped_read_size(10, 4), 4);
+        assert_eq!(capped_read_size(3, 4), 3);
+
+        // A length past isize::MAX, as a huge file on a 32-bit target would have
+        let read_size = capped_read_size(usize::MAX, usize::MAX);
+        assert_eq!(read_size, isize::MAX as usize);
+        assert!(read_size as isize > 0);
+    }
 
-        let dir = fs.opendir(&path);
-        assert!(dir.is_none());
+    #[test]
+    fn test_file_size_as_off_t() {
+        assert_eq!(file_size_as_off_t(16), 16);
+        assert!(file_size_as_off_t(usize::MAX) > 0);
     }
 
     #[test]
-    fn test_readdir() {
+    fn test_readdir_snapshot() {
         let fs = create_test_fs();
-        let path = vec!["usr", "bin"]
-            .into_iter()
-            .map(OsStr::new)
+        let path = vec![OsStr::new("usr"), OsStr::new("bin")];
+        let mut dir = fs.opendir(&path).unwrap();
+        let snapshot = dir
+            .entry_names()
+            .map(OsStr::to_os_string)
             .collect::<Vec<_>>();
 
-        let mut dir = fs.opendir(&path).unwrap();
+        // Added after opening, so not part of this iteration
+        let added = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("added")];
+        fs.add_virtual(&added, Box::new(Vec::new));
 
-        // Read directory entries
-        let mut entries = Vec::new();
+        let mut names = Vec::new();
         loop {
-            let dirent = fs.readdir(&mut dir);
-            assert!(dirent.is_some());
-
-            let dirent_ptr = dirent.unwrap();
+            let dirent_ptr = fs.readdir(&mut dir).unwrap();
             if dirent_ptr.is_null() {
                 break;
             }
 
-            let dirent = unsafe { &*dirent_ptr };
-            let name_bytes: Vec<u8> = dirent
-                .d_name
-                .iter()
-                .take_while(|&&c| c != 0)
-                .map(|&c| c as u8)
-                .collect();
-            let name = String::from_utf8_lossy(&name_bytes).to_string();
-            entries.push(name);
-
-            // Free the dirent
-            unsafe { drop(Box::from_raw(dirent_ptr)) };
+            let dirent = unsafe { Box::from_raw(dirent_ptr) };
+            let name = unsafe { std::ffi::CStr::from_ptr(dirent.d_name.as_ptr()) };
+            names.push(OsStr::from_bytes(name.to_bytes()).to_os_string());
         }
 
-        assert!(entries.contains(&"cat".to_string()));
-        assert!(entries.contains(&"ls".to_string()));
-        assert!(entries.contains(&"fuga".to_string()));
+        assert_eq!(names, snapshot);
+        assert_eq!(names, vec![".", "..", "cat", "fuga", "hoge", "ls"]);
+        fs.closedir(&dir).unwrap();
+
+        // A new opendir sees the added entry
+        let dir = fs.opendir(&path).unwrap();
+        assert!(dir.entry_names().any(|name| name == "added"));
+        fs.closedir(&dir).unwrap();
     }
 
     #[test]
-    fn test_closedir() {
-        let fs = create_test_fs();
-        let path = vec!["usr", "bin"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
+    fn test_configured_owner() {
+        let mut builder: TrieBuilder<&OsStr, &[u8]> = TrieBuilder::new();
+        let ls = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("ls")];
+        builder.push(&ls, b"ls_content");
 
-        let dir = fs.opendir(&path).unwrap();
-        let fd = dir.fd;
+        // Never the running user, so a live getuid() in stat would show up here
+        let uid = unsafe { libc::getuid() }.wrapping_add(1);
+        let gid = unsafe { libc::getgid() }.wrapping_add(1);
+        let fs = Fs::with_config(
+            builder,
+            FsConfig {
+                uid,
+                gid,
+                ..FsConfig::default()
+            },
+        );
 
-        assert!(fs.is_fd_exists(fd));
+        let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+        fs.stat(&ls, &mut stat).unwrap();
+        assert_eq!(stat.st_uid, uid);
+        assert_eq!(stat.st_gid, gid);
 
-        let result = fs.closedir(&dir);
-        assert_eq!(result, 0);
-        assert!(!fs.is_fd_exists(fd));
+        fs.stat(&vec![OsStr::new("usr")], &mut stat).unwrap();
+        assert_eq!(stat.st_uid, uid);
+        assert_eq!(stat.st_gid, gid);
+
+        let fs = create_test_fs();
+        fs.stat(&ls, &mut stat).unwrap();
+        assert_eq!(stat.st_uid, unsafe { libc::getuid() });
     }
 
     #[test]
-    fn test_rewinddir() {
+    fn test_dir_path() {
         let fs = create_test_fs();
-        let path = vec!["usr", "bin"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
-
-        let mut dir = fs.opendir(&path).unwrap();
+        let path = vec![OsStr::new("usr"), OsStr::new("bin")];
+        let dir = fs.opendir(&path).unwrap();
+        assert_eq!(fs.dir_path(dir.fd), Some(PathBuf::from("usr/bin")));
+        fs.closedir(&dir).unwrap();
+        assert_eq!(fs.dir_path(dir.fd), None);
+
+        let file = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("ls")];
+        let fd = fs.open(&file, libc::O_RDONLY).unwrap();
+        assert_eq!(fs.dir_path(fd), None);
+        fs.close(fd).unwrap();
+    }
 
-        // Read first entry
-        let first_entry = fs.readdir(&mut dir).unwrap();
-        assert!(!first_entry.is_null());
-        let first_name: Vec<u8> = unsafe { &*first_entry }
-            .d_name
-            .iter()
-            .take_while(|&&c| c != 0)
-            .map(|&c| c as u8)
-            .collect();
-        unsafe { drop(Box::from_raw(first_entry)) };
+    #[test]
+    fn test_statfs() {
+        let fs = create_test_fs();
+        let ls = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("ls")];
+        let mut statfs: libc::statfs = unsafe { std::mem::zeroed() };
+
+        assert!(fs.statfs(&ls, &mut statfs).is_some());
+        assert_eq!(statfs.f_bsize as u64, 4096);
+        assert_eq!(statfs.f_blocks as u64, 1);
+        assert_eq!(statfs.f_bfree as u64, 0);
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(statfs.f_type as u32, KOMPO_FS_MAGIC);
+            assert_eq!(statfs.f_namelen, 255);
+        }
+        #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+        {
+            let name = unsafe { std::ffi::CStr::from_ptr(statfs.f_fstypename.as_ptr()) };
+            assert_eq!(name.to_bytes(), KOMPO_FS_TYPENAME.as_bytes());
+        }
 
-        // Read second entry
-        let _ = fs.readdir(&mut dir);
+        let fd = fs.open(&ls, libc::O_RDONLY).unwrap();
+        let mut fstatfs: libc::statfs = unsafe { std::mem::zeroed() };
+        assert!(fs.fstatfs(fd, &mut fstatfs).is_some());
+        assert_eq!(fstatfs.f_blocks, statfs.f_blocks);
+        fs.close(fd).unwrap();
+        assert!(fs.fstatfs(fd, &mut fstatfs).is_none());
 
-        // Rewind
-        fs.rewinddir(&mut dir);
+        assert!(
+            fs.statfs(&vec![OsStr::new("missing")], &mut statfs)
+                .is_none()
+        );
+    }
 
-        // Read first entry again
-        let first_again = fs.readdir(&mut dir).unwrap();
-        assert!(!first_again.is_null());
-        let first_again_name: Vec<u8> = unsafe { &*first_again }
-            .d_name
-            .iter()
-            .take_while(|&&c| c != 0)
-            .map(|&c| c as u8)
-            .collect();
-        unsafe { drop(Box::from_raw(first_again)) };
+    #[test]
+    fn test_fd_path() {
+        let fs = create_test_fs();
+        let ls = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("ls")];
+        let fd = fs.open(&ls, libc::O_RDONLY).unwrap();
+        let at_fd = fs.open_at(&ls).unwrap();
+        let dir = fs
+            .opendir(&vec![OsStr::new("usr"), OsStr::new("bin")])
+            .unwrap();
+
+        assert_eq!(fs.fd_path(fd), Some(PathBuf::from("usr/bin/ls")));
+        assert_eq!(fs.fd_path(at_fd), Some(PathBuf::from("usr/bin/ls")));
+        assert_eq!(fs.fd_path(dir.fd), Some(PathBuf::from("usr/bin")));
+
+        // Opened again from the cached template, the path is still the one opened
+        let hoge = vec![
+            OsStr::new("usr"),
+            OsStr::new("bin"),
+            OsStr::new("hoge"),
+            OsStr::new("fuga"),
+        ];
+        let first = fs.open(&hoge, libc::O_RDONLY).unwrap();
+        let second = fs.open(&hoge, libc::O_RDONLY).unwrap();
+        assert_eq!(fs.fd_path(second), Some(PathBuf::from("usr/bin/hoge/fuga")));
+
+        for fd in [fd, at_fd, first, second] {
+            fs.close(fd).unwrap();
+            assert_eq!(fs.fd_path(fd), None);
+        }
+        fs.closedir(&dir).unwrap();
+    }
 
-        assert_eq!(first_name, first_again_name);
+    #[test]
+    fn test_open_fds() {
+        let fs = create_test_fs();
+        let ls = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("ls")];
+        let cat = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("cat")];
+        let ls_fd = fs.open(&ls, libc::O_RDONLY).unwrap();
+        let cat_fd = fs.open(&cat, libc::O_RDONLY).unwrap();
+        let dir = fs.opendir(&vec![OsStr::new("usr")]).unwrap();
+
+        let mut expected = vec![
+            (ls_fd, PathBuf::from("usr/bin/ls")),
+            (cat_fd, PathBuf::from("usr/bin/cat")),
+            (dir.fd, PathBuf::from("usr")),
+        ];
+        expected.sort();
+        assert_eq!(fs.open_fds(), expected);
+
+        fs.close(ls_fd).unwrap();
+        fs.close(cat_fd).unwrap();
+        fs.closedir(&dir).unwrap();
+        assert!(fs.open_fds().is_empty());
     }
 
     #[test]
-    fn test_fdopendir() {
+    fn test_entries() {
         let fs = create_test_fs();
-        let path = vec!["usr", "bin"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
+        let entries = fs.entries();
+
+        assert_eq!(entries.len(), 5);
+        assert!(entries.contains(&(PathBuf::from("usr/bin/ls"), b"ls_content".as_slice())));
+        assert!(entries.contains(&(
+            PathBuf::from("usr/bin/hoge/fuga"),
+            b"hoge_fuga_content".as_slice()
+        )));
+        assert!(entries.contains(&(PathBuf::from("usr/empty"), b"".as_slice())));
+    }
 
-        let fd = fs.open(&path).unwrap();
-        let dir = fs.fdopendir(fd);
+    #[test]
+    fn test_list_dir() {
+        let fs = create_test_fs();
 
-        assert!(dir.is_some());
-        let dir = dir.unwrap();
-        assert_eq!(dir.fd, fd);
+        assert_eq!(
+            fs.list_dir(&vec![OsStr::new("usr"), OsStr::new("bin")]),
+            Ok(vec![
+                OsString::from("cat"),
+                OsString::from("fuga"),
+                OsString::from("hoge"),
+                OsString::from("ls")
+            ])
+        );
+        assert_eq!(
+            fs.list_dir(&vec![OsStr::new("usr"), OsStr::new("empty")]),
+            Err(FsError::NotDirectory)
+        );
+        assert_eq!(
+            fs.list_dir(&vec![OsStr::new("missing")]),
+            Err(FsError::NotFound)
+        );
     }
 
     #[test]
-    fn test_fdopendir_file_fails() {
-        let fs = create_test_fs();
-        let path = vec!["usr", "bin", "ls"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
+    fn test_fstat_uses_stat_from_open() {
+        let fs = Arc::new(create_test_fs());
+        let path = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("cat")];
+        let mut expected = unsafe { std::mem::zeroed::<libc::stat>() };
+        fs.stat(&path, &mut expected).unwrap();
+        let fd = fs.open(&path, libc::O_RDONLY).unwrap();
+
+        // With the fd_map lock held for reading, as `read` holds it, fstat still
+        // answers from the stat taken at open
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let guard = fs.fd_map.read().unwrap();
+        let fstat_fs = Arc::clone(&fs);
+        let handle = std::thread::spawn(move || {
+            let mut stat_buf = unsafe { std::mem::zeroed::<libc::stat>() };
+            let result = fstat_fs.fstat(fd, &mut stat_buf);
+            sender.send((result, stat_buf)).unwrap();
+        });
+        let (result, stat_buf) = receiver
+            .recv_timeout(std::time::Duration::from_secs(10))
+            .expect("fstat waited on the fd_map lock");
+        drop(guard);
+        handle.join().unwrap();
 
-        let fd = fs.open(&path).unwrap();
-        let dir = fs.fdopendir(fd);
+        assert_eq!(result, Some(0));
+        assert_eq!(stat_buf.st_ino, expected.st_ino);
+        assert_eq!(stat_buf.st_size, expected.st_size);
+        assert_eq!(stat_buf.st_mode, expected.st_mode);
 
-        assert!(dir.is_none());
+        fs.close(fd).unwrap();
+        let mut stat_buf = unsafe { std::mem::zeroed::<libc::stat>() };
+        assert_eq!(fs.fstat(fd, &mut stat_buf), None);
     }
 
     #[test]
-    fn test_is_fd_exists() {
+    fn test_fstat_matches_stat_from_file_type() {
         let fs = create_test_fs();
-        let path = vec!["usr", "bin", "ls"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
-
-        assert!(!fs.is_fd_exists(9999));
-
-        let fd = fs.open(&path).unwrap();
-        assert!(fs.is_fd_exists(fd));
+        let file = vec![OsStr::new("usr"), OsStr::new("bin"), OsStr::new("ls")];
+        let dir = vec![OsStr::new("usr"), OsStr::new("bin")];
+        let mut stat_buf = unsafe { std::mem::zeroed::<libc::stat>() };
+
+        // Once through the trie, once from `last_stat` and once from `open_templates`
+        let mut fds = vec![fs.open(&file, libc::O_RDONLY).unwrap()];
+        fs.stat(&file, &mut stat_buf).unwrap();
+        fds.push(fs.open(&file, libc::O_RDONLY).unwrap());
+        fs.stat(&dir, &mut stat_buf).unwrap();
+        fds.push(fs.open(&file, libc::O_RDONLY).unwrap());
+        let dir_fd = fs.opendir(&dir).unwrap().fd;
+
+        let paths = fds.iter().map(|fd| (*fd, &file)).chain([(dir_fd, &dir)]);
+        for (fd, path) in paths {
+            let expected = fs
+                .stat_from_cached(&fs.get_cached_stat(&fs.get_file_type_from_path(path).unwrap()));
+            fs.fstat(fd, &mut stat_buf).unwrap();
+
+            assert_eq!(stat_buf.st_dev, expected.st_dev);
+            assert_eq!(stat_buf.st_ino, expected.st_ino);
+            assert_eq!(stat_buf.st_mode, expected.st_mode);
+            assert_eq!(stat_buf.st_nlink, expected.st_nlink);
+            assert_eq!(stat_buf.st_size, expected.st_size);
+            assert_eq!(stat_buf.st_blocks, expected.st_blocks);
+
+            fs.close(fd).unwrap();
+        }
+    }
 
-        fs.close(fd);
-        assert!(!fs.is_fd_exists(fd));
+    #[test]
+    #[cfg(feature = "unicode-normalization")]
+    fn test_normalized_lookup() {
+        // "café" decomposed (NFD, as macOS stores it) and precomposed (NFC)
+        let nfd = "cafe\u{301}";
+        let nfc = "caf\u{e9}";
+        let files = vec![
+            (format!("/app/{nfd}.rb").into_bytes(), b"nfd".to_vec()),
+            (format!("/app/{nfc}/lib.rb").into_bytes(), b"nfc".to_vec()),
+        ];
+        let fs = Fs::from_bytes(&files);
+        fn path(s: &str) -> Vec<&OsStr> {
+            Fs::path_components(Path::new(s))
+        }
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+
+        let nfc_query = format!("/app/{nfc}.rb");
+        assert!(fs.stat(&path(&nfc_query), &mut stat_buf).is_some());
+        assert_eq!(stat_buf.st_size, 3);
+        let fd = fs.open(&path(&nfc_query), libc::O_RDONLY).unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(fs.read(fd, &mut buf), Some(3));
+        assert_eq!(&buf[..3], b"nfd");
+        fs.close(fd).unwrap();
+
+        // The other way around, through a directory
+        let nfd_query = format!("/app/{nfd}/lib.rb");
+        assert!(fs.is_dir_exists_from_path(&path(&format!("/app/{nfd}"))));
+        assert!(fs.stat(&path(&nfd_query), &mut stat_buf).is_some());
+
+        assert!(fs.stat(&path("/app/cafe.rb"), &mut stat_buf).is_none());
     }
 
     #[test]
-    fn test_is_dir_exists() {
+    fn test_path_for_inode() {
         let fs = create_test_fs();
-        let path = vec!["usr", "bin"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
-
-        let dir = fs.opendir(&path).unwrap();
+        let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+
+        for path in ["usr/bin/ls", "usr/bin/hoge/fuga", "usr/bin", "usr"] {
+            fs.stat(&Fs::path_components(Path::new(path)), &mut stat_buf)
+                .unwrap();
+            assert_eq!(
+                fs.path_for_inode(stat_buf.st_ino as u64),
+                Some(PathBuf::from(path))
+            );
+        }
 
-        assert!(fs.is_dir_exists(&dir));
+        assert_eq!(fs.path_for_inode(0), None);
     }
 
     #[test]
-    fn test_is_dir_exists_from_path() {
+    fn test_parent_inode() {
         let fs = create_test_fs();
+        let inode = |path: &[&OsStr]| {
+            let mut stat_buf: libc::stat = unsafe { std::mem::zeroed() };
+            fs.stat(&path.to_vec(), &mut stat_buf).unwrap();
+            stat_buf.st_ino
+        };
 
-        let dir_path = vec!["usr", "bin"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
-        assert!(fs.is_dir_exists_from_path(&dir_path));
-
-        let file_path = vec!["usr", "bin", "ls"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
-        assert!(!fs.is_dir_exists_from_path(&file_path));
+        let usr = [OsStr::new("usr")];
+        let bin = [OsStr::new("usr"), OsStr::new("bin")];
+        assert_eq!(fs.parent_inode(&bin), inode(&usr));
 
-        let nonexistent = vec!["nonexistent"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
-        assert!(!fs.is_dir_exists_from_path(&nonexistent));
+        let root = [OsStr::new("/")];
+        assert_eq!(
+            fs.parent_inode(&[OsStr::new("/"), OsStr::new("usr")]),
+            inode(&root)
+        );
+        assert_eq!(fs.parent_inode(&root), inode(&root));
     }
 
     #[test]
-    fn test_file_read() {
+    fn test_children() {
         let fs = create_test_fs();
-        let path = vec!["usr", "bin", "ls"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
-
-        let ptr = fs.file_read(&path);
-        assert!(ptr.is_some());
+        let bin = vec![OsStr::new("usr"), OsStr::new("bin")];
+
+        for path in [vec![], vec![OsStr::new("usr")], bin.clone()] {
+            let mut expected = fs
+                .get_entries_from_path(&path)
+                .into_iter()
+                .map(|entry| *entry.last().unwrap())
+                .collect::<Vec<_>>();
+            expected.sort();
+            let mut children = fs.children(&path);
+            children.sort();
+
+            assert_eq!(children, expected);
+        }
 
-        let ptr = ptr.unwrap();
-        let content = unsafe { std::slice::from_raw_parts(ptr, 10) };
-        assert_eq!(content, b"ls_content");
+        assert!(
+            fs.children(&vec![OsStr::new("usr"), OsStr::new("empty")])
+                .is_empty()
+        );
+        assert!(fs.children(&vec![OsStr::new("missing")]).is_empty());
+
+        let generated = vec![
+            OsStr::new("usr"),
+            OsStr::new("bin"),
+            OsStr::new("generated"),
+        ];
+        fs.add_virtual(&generated, Box::new(Vec::new));
+        assert!(fs.children(&bin).contains(&OsStr::new("generated")));
     }
 
     #[test]
-    fn test_open_at() {
+    fn test_dir_index_matches_trie() {
         let fs = create_test_fs();
-        let path = vec!["usr", "bin", "ls"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
-
-        let fd = fs.open_at(&path);
-        assert!(fd.is_some());
 
-        let fd = fd.unwrap();
-        assert!(fs.is_fd_exists(fd));
+        // Every directory is a proper prefix of some file path
+        let mut dirs = HashSet::new();
+        for (path, _) in fs.trie.iter() {
+            let path: Vec<&OsStr> = path;
+            for depth in 0..path.len() {
+                dirs.insert(path[..depth].to_vec());
+            }
+        }
+        assert_eq!(dirs.len(), fs.dir_index.len());
+
+        for dir in dirs {
+            let depth = dir.len() + 1;
+            let mut expected = fs
+                .trie
+                .predictive_search(&dir)
+                .filter_map(|(path, _): (Vec<&OsStr>, _)| {
+                    (path.len() >= depth).then(|| path[..depth].to_vec())
+                })
+                .collect::<Vec<_>>();
+            expected.sort();
+            expected.dedup();
+
+            let mut children = fs.dir_index[&dir].iter().collect::<Vec<_>>();
+            children.sort_by_key(|child| child.name);
+            assert_eq!(children.len(), expected.len());
+
+            for (child, path) in children.into_iter().zip(expected) {
+                assert_eq!(child.name, *path.last().unwrap());
+                assert_eq!(child.inode, fs.get_inode_from_path(&path));
+                assert_eq!(child.is_dir, fs.trie.exact_match(&path).is_none());
+            }
+        }
     }
 
     #[test]
-    fn test_multiple_opens() {
+    fn test_exists_any_prefix() {
         let fs = create_test_fs();
-        let path = vec!["usr", "bin", "ls"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
 
-        let fd1 = fs.open(&path).unwrap();
-        let fd2 = fs.open(&path).unwrap();
+        for (path, _) in fs.trie.iter() {
+            let path: Vec<&OsStr> = path;
+            for depth in 0..=path.len() {
+                assert!(fs.exists_any_prefix(&path[..depth]), "{:?}", &path[..depth]);
+            }
+        }
 
-        assert_ne!(fd1, fd2);
-        assert!(fs.is_fd_exists(fd1));
-        assert!(fs.is_fd_exists(fd2));
+        assert!(!fs.exists_any_prefix(&[OsStr::new("missing")]));
+        assert!(!fs.exists_any_prefix(&[OsStr::new("usr"), OsStr::new("lib")]));
+        assert!(!fs.exists_any_prefix(&[
+            OsStr::new("usr"),
+            OsStr::new("bin"),
+            OsStr::new("ls"),
+            OsStr::new("x")
+        ]));
+
+        let generated = vec![OsStr::new("var"), OsStr::new("run"), OsStr::new("pid")];
+        fs.add_virtual(&generated, Box::new(Vec::new));
+        assert!(fs.exists_any_prefix(&generated[..1]));
+        assert!(fs.exists_any_prefix(&generated));
+    }
 
-        fs.close(fd1);
-        assert!(!fs.is_fd_exists(fd1));
-        assert!(fs.is_fd_exists(fd2));
+    fn path_byte() -> impl proptest::strategy::Strategy<Value = u8> {
+        use proptest::prelude::*;
+
+        prop_oneof![
+            Just(b'/'),
+            Just(b'.'),
+            Just(b'\0'),
+            b'a'..=b'c',
+            any::<u8>(),
+        ]
     }
 
-    #[test]
-    fn test_nested_directory() {
-        let fs = create_test_fs();
-        let path = vec!["usr", "bin", "hoge"]
-            .into_iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>();
+    // Runs every path operation on `path`, closing whatever it opens.
+    fn exercise_path(fs: &Fs, path: &[u8]) {
+        let path = Fs::path_components(Path::new(OsStr::from_bytes(path)));
+        let mut stat_buf = unsafe { std::mem::zeroed::<libc::stat>() };
+
+        fs.stat(&path, &mut stat_buf);
+        fs.lstat(&path, &mut stat_buf);
+        let _ = fs.list_dir(&path);
+        fs.children(&path);
+        fs.exists_any_prefix(&path);
+
+        if let Ok(fd) = fs.open(&path, libc::O_RDONLY) {
+            fs.fstat(fd, &mut stat_buf).unwrap();
+            let mut buf = [0u8; 7];
+            if stat_buf.st_mode & libc::S_IFMT == libc::S_IFREG {
+                while fs.read(fd, &mut buf).unwrap() > 0 {}
+                fs.read_at(fd, &mut buf, u64::MAX).unwrap();
+            } else {
+                assert_eq!(fs.read(fd, &mut buf), None);
+            }
+            fs.close(fd).unwrap();
+            unsafe { libc::close(fd) };
+        }
 
-        assert!(fs.is_dir_exists_from_path(&path));
+        if let Some(mut dir) = fs.opendir(&path) {
+            loop {
+                match fs.readdir(&mut dir) {
+                    Ok(entry) if entry.is_null() => break,
+                    Ok(entry) => unsafe { drop(Box::from_raw(entry)) },
+                    Err(err) => assert_eq!(err, FsError::NameTooLong),
+                }
+            }
+            fs.closedir(&dir).unwrap();
+            unsafe { libc::close(dir.fd) };
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_from_bytes_operations_never_panic(
+            files in proptest::collection::vec(
+                (
+                    proptest::collection::vec(path_byte(), 0..12),
+                    proptest::collection::vec(proptest::prelude::any::<u8>(), 0..32),
+                ),
+                0..8,
+            ),
+            queries in proptest::collection::vec(
+                proptest::collection::vec(path_byte(), 0..12),
+                0..8,
+            ),
+        ) {
+            let fs = Fs::from_bytes(&files);
+
+            for (path, _) in &files {
+                exercise_path(&fs, path);
+            }
+            for path in &queries {
+                exercise_path(&fs, path);
+            }
+            exercise_path(&fs, b"/");
+        }
     }
 
     #[test]
-    fn test_root_directory() {
+    fn test_prefetch() {
         let fs = create_test_fs();
-        let path = vec!["usr"].into_iter().map(OsStr::new).collect::<Vec<_>>();
-
-        assert!(fs.is_dir_exists_from_path(&path));
+        let ls = [OsStr::new("usr"), OsStr::new("bin"), OsStr::new("ls")];
+        let cat = [OsStr::new("usr"), OsStr::new("bin"), OsStr::new("cat")];
+        let empty = [OsStr::new("usr"), OsStr::new("empty")];
+        let dir = [OsStr::new("usr"), OsStr::new("bin")];
+        let missing = [OsStr::new("missing")];
+
+        assert_eq!(fs.prefetch(&[&ls, &cat]), 10 + 16);
+        assert_eq!(fs.prefetch(&[&empty, &dir, &missing]), 0);
+
+        // Contents read the same afterwards
+        let fd = fs.open(&ls.to_vec(), libc::O_RDONLY).unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(fs.read(fd, &mut buf), Some(10));
+        assert_eq!(&buf[..10], b"ls_content");
     }
 }
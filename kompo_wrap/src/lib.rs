@@ -49,6 +49,58 @@ macro_rules! syscall_hook {
     };
 }
 
+/// Macro to define a syscall hook whose body is written inline instead of in a
+/// `_from_fs` function in kompo_fs. The `HANDLE` static is generated the same way
+/// as `syscall_hook!`, so the body can fall through to the real syscall.
+/// The body is evaluated in an unsafe context.
+///
+/// Usage:
+/// - With return type: `syscall_hook_inline!(chmod, (path: *const libc::c_char, mode: libc::mode_t) -> libc::c_int, |path, mode| { CHMOD_HANDLE(path, mode) });`
+/// - Without return type: `syscall_hook_inline!(sync, (), || { SYNC_HANDLE() });`
+macro_rules! syscall_hook_inline {
+    // `||` is lexed as a single token, so closures without arguments are forwarded here
+    ($syscall:ident, () -> $ret:ty, || $body:block) => {
+        syscall_hook_inline!($syscall, () -> $ret, | | $body);
+    };
+    ($syscall:ident, (), || $body:block) => {
+        syscall_hook_inline!($syscall, (), | | $body);
+    };
+
+    // Pattern with return type
+    ($syscall:ident, ($($param:ident: $ty:ty),*) -> $ret:ty, |$($arg:ident),*| $body:block) => {
+        paste! {
+            pub static [<$syscall:upper _HANDLE>]: std::sync::LazyLock<
+                unsafe extern "C-unwind" fn($($ty),*) -> $ret,
+            > = std::sync::LazyLock::new(|| unsafe {
+                let handle = libc::dlsym(libc::RTLD_NEXT, concat!(stringify!($syscall), "\0").as_ptr() as _);
+                std::mem::transmute::<*mut libc::c_void, unsafe extern "C-unwind" fn($($ty),*) -> $ret>(handle)
+            });
+
+            #[unsafe(no_mangle)]
+            unsafe extern "C-unwind" fn $syscall($($arg: $ty),*) -> $ret {
+                unsafe { $body }
+            }
+        }
+    };
+
+    // Pattern without return type (void)
+    ($syscall:ident, ($($param:ident: $ty:ty),*), |$($arg:ident),*| $body:block) => {
+        paste! {
+            pub static [<$syscall:upper _HANDLE>]: std::sync::LazyLock<
+                unsafe extern "C-unwind" fn($($ty),*),
+            > = std::sync::LazyLock::new(|| unsafe {
+                let handle = libc::dlsym(libc::RTLD_NEXT, concat!(stringify!($syscall), "\0").as_ptr() as _);
+                std::mem::transmute::<*mut libc::c_void, unsafe extern "C-unwind" fn($($ty),*)>(handle)
+            });
+
+            #[unsafe(no_mangle)]
+            unsafe extern "C-unwind" fn $syscall($($arg: $ty),*) {
+                unsafe { $body }
+            }
+        }
+    };
+}
+
 // =============================================================================
 // Syscall hooks using the macro
 // =============================================================================
@@ -71,6 +123,26 @@ syscall_hook!(mkdir, (path: *const libc::c_char, mode: libc::mode_t) -> libc::c_
 syscall_hook!(closedir, (dirp: *mut libc::DIR) -> libc::c_int);
 syscall_hook!(chdir, (path: *const libc::c_char) -> libc::c_int);
 syscall_hook!(realpath, (path: *const libc::c_char, resolved_path: *mut libc::c_char) -> *const libc::c_char);
+syscall_hook!(statfs, (path: *const libc::c_char, buf: *mut libc::statfs) -> libc::c_int);
+syscall_hook!(fstatfs, (fd: libc::c_int, buf: *mut libc::statfs) -> libc::c_int);
+
+unsafe extern "C" {
+    fn kompo_fs_is_vfs_path(path: *const libc::c_char) -> libc::c_int;
+}
+
+// The VFS is read-only, so its files keep the mode they were embedded with.
+syscall_hook_inline!(chmod, (path: *const libc::c_char, mode: libc::mode_t) -> libc::c_int, |path, mode| {
+    if !path.is_null() && kompo_fs_is_vfs_path(path) != 0 {
+        errno::set_errno(errno::Errno(libc::EROFS));
+        -1
+    } else {
+        CHMOD_HANDLE(path, mode)
+    }
+});
+#[cfg(target_os = "linux")]
+syscall_hook!(posix_fadvise, (fd: libc::c_int, offset: libc::off_t, len: libc::off_t, advice: libc::c_int) -> libc::c_int);
+#[cfg(target_os = "linux")]
+syscall_hook!(sendfile, (out_fd: libc::c_int, in_fd: libc::c_int, offset: *mut libc::off_t, count: libc::size_t) -> libc::ssize_t);
 
 // getattrlist - macOS only
 #[cfg(target_os = "macos")]
@@ -120,3 +192,28 @@ unsafe extern "C-unwind" fn getattrlist(
 ) -> libc::c_int {
     unsafe { getattrlist_from_fs(path, attr_list, attr_buf, attr_buf_size, options) }
 }
+
+// openat2 - Linux only. Only reached by callers of an openat2 wrapper, which libc
+// may not export at all, so there is no HANDLE: the real one is the raw syscall,
+// made by `openat2_from_fs`. `how` points to a `libc::open_how`, which is
+// non-exhaustive and so not FFI-safe.
+#[cfg(target_os = "linux")]
+unsafe extern "C" {
+    fn openat2_from_fs(
+        dirfd: libc::c_int,
+        pathname: *const libc::c_char,
+        how: *mut libc::c_void,
+        size: libc::size_t,
+    ) -> libc::c_int;
+}
+
+#[cfg(target_os = "linux")]
+#[unsafe(no_mangle)]
+unsafe extern "C-unwind" fn openat2(
+    dirfd: libc::c_int,
+    pathname: *const libc::c_char,
+    how: *mut libc::c_void,
+    size: libc::size_t,
+) -> libc::c_int {
+    unsafe { openat2_from_fs(dirfd, pathname, how, size) }
+}